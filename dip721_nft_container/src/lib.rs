@@ -446,11 +446,14 @@ fn metadata_to_nft_metadata(properties: SquareProperties) -> MetadataDesc {
 }
 */
 
+// A mint with an idempotency key that was already used returns the NFT minted first instead of minting another one,
+// so callers can safely retry a mint whose reply they never got
 #[update(name = "mintDip721")]
 fn mint(
     to: Principal,
     metadata: MetadataDesc,
     blob_content: Vec<u8>,
+    idempotency_key: Option<String>,
 ) -> Result<MintResult, ConstrainedError> {
     ic_cdk::println!("DIP_721_mintDip721 called");
     ic_cdk::println!("DIP_721_Recipient principal: {:?}", to);
@@ -467,8 +470,16 @@ fn mint(
             return Err(ConstrainedError::Unauthorized);
         }
 
+        if let Some(key) = &idempotency_key {
+            if let Some(&(txid, token_id)) = state.minted_keys.get(key) {
+                ic_cdk::println!("DIP_721_Already minted NFT ID {} for key {}", token_id, key);
+                return Ok((txid, token_id));
+            }
+        }
+
         let new_id = state.nfts.len() as u64;
         ic_cdk::println!("DIP_721_New NFT ID: {}", new_id);
+        let txid = state.next_txid();
 
         let nft = Nft {
             owner: to,
@@ -481,7 +492,10 @@ fn mint(
         ic_cdk::println!("DIP_721_New NFT: {:?}", nft);
 
         state.nfts.push(nft);
-        Ok((state.next_txid(), new_id))
+        if let Some(key) = idempotency_key {
+            state.minted_keys.insert(key, (txid, new_id));
+        }
+        Ok((txid, new_id))
     })?;
 
     http::add_hash(tkid);
@@ -554,6 +568,7 @@ struct State {
     name: String,
     symbol: String,
     txid: u128,
+    minted_keys: HashMap<String, (u128, u64)>, // idempotency key of a mint to its transaction and token IDs
 }
 
 #[derive(CandidType, Deserialize, Debug)]
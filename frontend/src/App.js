@@ -4,6 +4,53 @@ import GeolocationMap from './GeolocationMap';
 import './tailwind.css';
 import { AuthClient } from "@dfinity/auth-client";
 
+// Format a GeohashError variant (and the errors nested in it) as readable text
+const formatError = (error) => {
+  if (error === null || typeof error !== 'object') {
+    return String(error);
+  }
+  const [kind, value] = Object.entries(error)[0];
+  if (value === null) {
+    return kind;
+  }
+  if (typeof value !== 'object') {
+    return `${kind}: ${value}`;
+  }
+  // Variants have a single key, records such as { geohash, token_id } several fields
+  if (Object.keys(value).length > 1) {
+    const fields = Object.entries(value).map(([field, inner]) => `${field} ${inner}`);
+    return `${kind}: ${fields.join(', ')}`;
+  }
+  return `${kind}: ${formatError(value)}`;
+};
+
+// Unwrap a Candid result of the canister endpoints, turning the GeohashError variant into a readable error
+const unwrapResult = (result) => {
+  if ('Err' in result) {
+    throw new Error(formatError(result.Err));
+  }
+  return result.Ok;
+};
+
+// Convert a typed metric value (a Candid variant such as { Nat: 42n }) into a plain value for rendering
+const toMetricValue = (value) => {
+  const [kind, inner] = Object.entries(value)[0];
+  return kind === 'Nat' || kind === 'Int' ? Number(inner) : inner;
+};
+
+// Convert a Candid AreaResponse into plain values for rendering (opt fields arrive as arrays, nat64 values as BigInt)
+const toAreaResponse = (response) => {
+  const nft = response.nft_square[0];
+  return {
+    ...response,
+    nft_square: nft ? { ...nft, token_id: Number(nft.token_id) } : null,
+    bitcoin_balance: Number(response.bitcoin_balance),
+    ethereum_balance: Number(response.ethereum_balance),
+    real_time_metrics: Object.fromEntries((response.real_time_metrics[0] ?? []).map(([name, value]) => [name, toMetricValue(value)])),
+    rating: response.rating[0] ?? null,
+  };
+};
+
 function App() {
   const [latitude, setLatitude] = useState('');
   const [longitude, setLongitude] = useState('');
@@ -73,11 +120,8 @@ function App() {
       const geolocation = { latitude: parseFloat(latitude), longitude: parseFloat(longitude) };
       console.log('Sending geolocation:', geolocation);
 
-      const resultString = await geohashActor.compute_geohash(geolocation);
-      console.log('Received result:', resultString);
-
-      // Parse the result string to JSON
-      const result = JSON.parse(resultString);
+      const result = toAreaResponse(unwrapResult(await geohashActor.compute_geohash(geolocation)));
+      console.log('Received result:', result);
 
       // Enhanced logging for the entire result
       console.log('Result (full):', JSON.stringify(result, null, 2));
@@ -135,11 +179,8 @@ function App() {
   const handleGeohashSubmit = async () => {
     try {
       
-      const resultString = await geohashActor.compute_area(geohash);
-      console.log('Received result:', resultString);
-  
-      // Parse the result string to JSON
-      const result = JSON.parse(resultString);
+      const result = toAreaResponse(unwrapResult(await geohashActor.compute_area(geohash)));
+      console.log('Received result:', result);
   
       // Enhanced logging for the entire result
       console.log('Result (full):', JSON.stringify(result, null, 2));
@@ -182,6 +223,29 @@ function App() {
   };
 
 
+  // User clicks claim to mint the NFT of a square that was not claimed yet
+  const handleClaimSquare = async () => {
+    try {
+      // Submit the location of the user, the canister may require it to lie inside the square
+      const location = { latitude: parseFloat(latitude), longitude: parseFloat(longitude) };
+      const hasLocation = !isNaN(location.latitude) && !isNaN(location.longitude);
+      const claimResult = await geohashActor.claim_square(response.geohash, hasLocation ? [location] : []);
+      if ('Err' in claimResult) {
+        throw new Error(formatError(claimResult.Err));
+      }
+
+      const result = toAreaResponse(claimResult.Ok);
+      console.log('Claimed square:', result);
+
+      setResponse(result);
+      setError(null);
+    } catch (err) {
+      console.error('Error claiming square:', err.message, err);
+      setError(err.message);
+    }
+  };
+
+
   // Update inputs rating and clicks submit to update the rating of the square
 
 
@@ -194,17 +258,17 @@ function App() {
     }
   
     try {
-      const ipnsName = response.nft_square.metadata[0].key_val_data.find(kv => kv.key === 'ipns_id').val.TextContent;
-      await geohashActor.update_rating(ipnsName, ratingValue);
+      const ratingResult = await geohashActor.update_rating(response.geohash, ratingValue);
+      if ('Err' in ratingResult) {
+        throw new Error(formatError(ratingResult.Err));
+      }
       console.log('Rating updated successfully');
   
       // Clear the error message
       setError('');
   
-      // Update the rating in the response state
-      const updatedResponse = { ...response };
-      updatedResponse.real_time_metrics["Rating"] = ratingValue;
-      setResponse(updatedResponse);
+      // Update the ratings of the square in the response state
+      setResponse({ ...response, rating: ratingResult.Ok });
   
     } catch (err) {
       console.error('Error updating rating:', err.message, err);
@@ -260,7 +324,21 @@ function App() {
               </div>
             )}
 
-            {response && (
+            {response && !response.nft_square && (
+              <div className="mt-10">
+                <p className="text-blue-500 font-bold">The square {response.geohash} has not been claimed yet.</p>
+                {isAuthenticated && (
+                  <button
+                    className="bg-blue-500 text-white px-4 py-2 mt-2 rounded hover:bg-blue-700"
+                    onClick={handleClaimSquare}
+                  >
+                    Claim Square
+                  </button>
+                )}
+              </div>
+            )}
+
+            {response && response.nft_square && (
               <div className="mt-10">
                 {/*
                 {response.created ? (
@@ -284,8 +362,9 @@ function App() {
                         <td className="px-6 py-4">{response.geohash}</td>
                       </tr>
                       <tr className="odd:bg-white even:bg-gray-50 border-b">
-                        <th scope="row" className="px-6 py-4 font-medium text-gray-900">IPNS Name</th>
-                        <td className="px-6 py-4">{response.nft_square.metadata[0].key_val_data.find(kv => kv.key === 'ipns_id').val.TextContent}</td>
+                        <th scope="row" className="px-6 py-4 font-medium text-gray-900">Document Name</th>
+                        {/* Squares minted before documents existed carry a mocked IPNS ID instead */}
+                        <td className="px-6 py-4">{response.nft_square.metadata[0].key_val_data.find(kv => kv.key === 'document_name' || kv.key === 'ipns_id')?.val.TextContent ?? "n/a"}</td>
                       </tr>
                       <tr className="odd:bg-white even:bg-gray-50 border-b">
                         <th scope="row" className="px-6 py-4 font-medium text-gray-900">Bitcoin Address</th>
@@ -317,19 +396,19 @@ function App() {
                       </tr>
                       <tr className="odd:bg-white even:bg-gray-50 border-b">
                         <th scope="row" className="px-6 py-4 font-medium text-gray-900">Air quality index</th>
-                        <td className="px-6 py-4">{response.real_time_metrics["Air quality index"]}</td>
+                        <td className="px-6 py-4">{response.real_time_metrics["Air quality index"] ?? "n/a"}</td>
                       </tr>
                       <tr className="odd:bg-white even:bg-gray-50 border-b">
                         <th scope="row" className="px-6 py-4 font-medium text-gray-900">Crime Rate</th>
-                        <td className="px-6 py-4">{response.real_time_metrics["Crime rate"]}</td>
+                        <td className="px-6 py-4">{response.real_time_metrics["Crime rate"] ?? "n/a"}</td>
                       </tr>
                       <tr className="odd:bg-white even:bg-gray-50 border-b">
                         <th scope="row" className="px-6 py-4 font-medium text-gray-900">Car Accident Rate</th>
-                        <td className="px-6 py-4">{response.real_time_metrics["Car accident rate"]}</td>
+                        <td className="px-6 py-4">{response.real_time_metrics["Car accident rate"] ?? "n/a"}</td>
                       </tr>
                       <tr className="odd:bg-white even:bg-gray-50 border-b">
                         <th scope="row" className="px-6 py-4 font-medium text-gray-900">Rating</th>
-                        <td className="px-6 py-4">{response.real_time_metrics.Rating}</td>
+                        <td className="px-6 py-4">{response.rating ? `${response.rating.mean.toFixed(1)} (${response.rating.count} ratings)` : "n/a"}</td>
                       </tr>
                     </tbody>
                   </table>
@@ -337,7 +416,7 @@ function App() {
               </div>
             )}
 
-            {isAuthenticated && showUpdateRating && (
+            {isAuthenticated && showUpdateRating && response && response.nft_square && (
               <div className="mb-6 mt-10">
                 <h2 className="text-xl mb-2">Update Rating</h2>
                 <input
@@ -54,22 +54,118 @@ export interface Geolocation {
   longitude: number;
 }
 
+export type MetricValue =
+  | { Nat: bigint }
+  | { Int: bigint }
+  | { Float: number }
+  | { Bool: boolean }
+  | { Text: string };
+
+export interface RatingSummary {
+  count: number;
+  mean: number;
+  distribution: Array<number>; // Number of users per rating, starting at 1
+}
+
 export interface AreaResponse {
   lat_start: number;
   lon_start: number;
   lat_end: number;
   lon_end: number;
   geohash: string;
-  nft_square: Nft | null; // Optional field for the NFT
+  nft_square: [] | [Nft]; // Optional field for the NFT
+  bitcoin_balance: bigint;
+  ethereum_balance: bigint;
+  real_time_metrics: [] | [Array<[string, MetricValue]>];
+  rating: [] | [RatingSummary];
   created: boolean; // Indicating if NFT was created
 }
 
 
 
+export type GridError =
+  | { InvalidLatitude: number }
+  | { InvalidLongitude: number }
+  | { InvalidGeohash: string }
+  | { NotASquare: string }
+  | { InvalidRegion: string }
+  | { InvalidCode: string }
+  | { TooManyInputs: number };
+
+export type MetricValueType =
+  | { Nat: null }
+  | { Int: null }
+  | { Float: null }
+  | { Bool: null }
+  | { Text: null };
+
+export type MetricError =
+  | { Unauthorized: null }
+  | { UnknownMetric: string }
+  | { InvalidDefinition: string }
+  | { WrongValueType: MetricValueType }
+  | { OutOfRange: number }
+  | { InvalidSquare: GridError }
+  | { NotNumeric: string }
+  | { InvalidPercentile: number }
+  | { TooManyObservations: number }
+  | { TooManyInputs: number }
+  | { RateLimited: bigint }
+  | { InvalidTimestamp: bigint }
+  | { InvalidSourceReference: string }
+  | { UnknownFeed: string }
+  | { FetchFailed: string };
+
+export type ContributionStatus =
+  | { Pending: null }
+  | { Accepted: null }
+  | { Rejected: null };
+
+export type ContributionError =
+  | { AnonymousCaller: null }
+  | { Unauthorized: null }
+  | { UnknownContribution: bigint }
+  | { InvalidObservation: MetricError }
+  | { InvalidDescription: string }
+  | { InvalidPolicy: string }
+  | { OwnContribution: null }
+  | { AlreadyVoted: null }
+  | { AlreadyDecided: ContributionStatus }
+  | { InsufficientReputation: bigint };
+
+export type GeohashError =
+  | { InvalidCoordinate: string }
+  | { InvalidGeohash: string }
+  | { NotASquare: string }
+  | { InvalidInput: string }
+  | { TooManyInputs: number }
+  | { NotFound: string }
+  | { NotMinted: string }
+  | { AlreadyClaimed: { geohash: string; token_id: bigint } }
+  | { MintInProgress: string }
+  | { MintFailed: { geohash: string; reason: string } }
+  | { TransferFailed: { token_id: bigint; reason: string } }
+  | { DependencyUnavailable: { canister: string; reason: string } }
+  | { NotConfigured: string }
+  | { LocationRequired: null }
+  | { OutsideSquare: string }
+  | { ClaimLimitReached: number }
+  | { AnonymousCaller: null }
+  | { Unauthorized: null }
+  | { RefreshRunning: null }
+  | { StorageFailed: string }
+  | { Metric: MetricError }
+  | { Contribution: ContributionError };
+
+export type ComputeResult = { Ok: AreaResponse } | { Err: GeohashError };
+
+export type ClaimResult = { Ok: AreaResponse } | { Err: GeohashError };
+
+export type RatingResult = { Ok: RatingSummary } | { Err: GeohashError };
+
 export interface _SERVICE {
-  compute_area: ActorMethod<[string], string>;
-  //compute_area: ActorMethod<[string], AreaResponse>;
-  compute_geohash: ActorMethod<[Geolocation], string>;
-  //compute_geohash: ActorMethod<[Geolocation], AreaResponse>;
-  update_rating: ActorMethod<[string, number], Result>;
+  compute_area: ActorMethod<[string], ComputeResult>;
+  compute_geohash: ActorMethod<[Geolocation], ComputeResult>;
+  claim_square: ActorMethod<[string, [] | [Geolocation]], ClaimResult>;
+  update_rating: ActorMethod<[string, number], RatingResult>;
 }
\ No newline at end of file
@@ -51,6 +51,20 @@ export const idlFactory = ({ IDL }) => {
     longitude: IDL.Float64,
   });
 
+  const MetricValue = IDL.Variant({
+    Nat: IDL.Nat64,
+    Int: IDL.Int64,
+    Float: IDL.Float64,
+    Bool: IDL.Bool,
+    Text: IDL.Text,
+  });
+
+  const RatingSummary = IDL.Record({
+    count: IDL.Nat32,
+    mean: IDL.Float64,
+    distribution: IDL.Vec(IDL.Nat32),
+  });
+
   const AreaResponse = IDL.Record({
     lat_start: IDL.Float64,
     lon_start: IDL.Float64,
@@ -58,18 +72,105 @@ export const idlFactory = ({ IDL }) => {
     lon_end: IDL.Float64,
     geohash: IDL.Text,
     nft_square: IDL.Opt(Nft),
+    bitcoin_balance: IDL.Nat64,
+    ethereum_balance: IDL.Nat64,
+    real_time_metrics: IDL.Opt(IDL.Vec(IDL.Tuple(IDL.Text, MetricValue))),
+    rating: IDL.Opt(RatingSummary),
     created: IDL.Bool,
   });
 
 
-  const Result = IDL.Variant({ 'Ok' : IDL.Null, 'Err' : IDL.Text });
+  const GridError = IDL.Variant({
+    InvalidLatitude: IDL.Float64,
+    InvalidLongitude: IDL.Float64,
+    InvalidGeohash: IDL.Text,
+    NotASquare: IDL.Text,
+    InvalidRegion: IDL.Text,
+    InvalidCode: IDL.Text,
+    TooManyInputs: IDL.Nat32,
+  });
+
+  const MetricValueType = IDL.Variant({
+    Nat: IDL.Null,
+    Int: IDL.Null,
+    Float: IDL.Null,
+    Bool: IDL.Null,
+    Text: IDL.Null,
+  });
+
+  const MetricError = IDL.Variant({
+    Unauthorized: IDL.Null,
+    UnknownMetric: IDL.Text,
+    InvalidDefinition: IDL.Text,
+    WrongValueType: MetricValueType,
+    OutOfRange: IDL.Float64,
+    InvalidSquare: GridError,
+    NotNumeric: IDL.Text,
+    InvalidPercentile: IDL.Nat8,
+    TooManyObservations: IDL.Nat32,
+    TooManyInputs: IDL.Nat32,
+    RateLimited: IDL.Nat64,
+    InvalidTimestamp: IDL.Nat64,
+    InvalidSourceReference: IDL.Text,
+    UnknownFeed: IDL.Text,
+    FetchFailed: IDL.Text,
+  });
+
+  const ContributionStatus = IDL.Variant({
+    Pending: IDL.Null,
+    Accepted: IDL.Null,
+    Rejected: IDL.Null,
+  });
+
+  const ContributionError = IDL.Variant({
+    AnonymousCaller: IDL.Null,
+    Unauthorized: IDL.Null,
+    UnknownContribution: IDL.Nat64,
+    InvalidObservation: MetricError,
+    InvalidDescription: IDL.Text,
+    InvalidPolicy: IDL.Text,
+    OwnContribution: IDL.Null,
+    AlreadyVoted: IDL.Null,
+    AlreadyDecided: ContributionStatus,
+    InsufficientReputation: IDL.Int64,
+  });
+
+  const GeohashError = IDL.Variant({
+    InvalidCoordinate: IDL.Text,
+    InvalidGeohash: IDL.Text,
+    NotASquare: IDL.Text,
+    InvalidInput: IDL.Text,
+    TooManyInputs: IDL.Nat32,
+    NotFound: IDL.Text,
+    NotMinted: IDL.Text,
+    AlreadyClaimed: IDL.Record({ geohash: IDL.Text, token_id: IDL.Nat64 }),
+    MintInProgress: IDL.Text,
+    MintFailed: IDL.Record({ geohash: IDL.Text, reason: IDL.Text }),
+    TransferFailed: IDL.Record({ token_id: IDL.Nat64, reason: IDL.Text }),
+    DependencyUnavailable: IDL.Record({ canister: IDL.Text, reason: IDL.Text }),
+    NotConfigured: IDL.Text,
+    LocationRequired: IDL.Null,
+    OutsideSquare: IDL.Text,
+    ClaimLimitReached: IDL.Nat32,
+    AnonymousCaller: IDL.Null,
+    Unauthorized: IDL.Null,
+    RefreshRunning: IDL.Null,
+    StorageFailed: IDL.Text,
+    Metric: MetricError,
+    Contribution: ContributionError,
+  });
+
+  const ComputeResult = IDL.Variant({ 'Ok' : AreaResponse, 'Err' : GeohashError });
+
+  const ClaimResult = IDL.Variant({ 'Ok' : AreaResponse, 'Err' : GeohashError });
+
+  const RatingResult = IDL.Variant({ 'Ok' : RatingSummary, 'Err' : GeohashError });
   
   return IDL.Service({
-    compute_area: IDL.Func([IDL.Text], [IDL.Text], []),
-    //compute_area: IDL.Func([IDL.Text], [AreaResponse], []), // Uncomment if returning AreaResponse
-    compute_geohash: IDL.Func([Geolocation], [IDL.Text], []),
-    //compute_geohash: IDL.Func([Geolocation], [AreaResponse], []), // Uncomment if returning AreaResponse
-    update_rating: IDL.Func([IDL.Text, IDL.Nat32], [Result], []),
+    compute_area: IDL.Func([IDL.Text], [ComputeResult], []),
+    compute_geohash: IDL.Func([Geolocation], [ComputeResult], []),
+    claim_square: IDL.Func([IDL.Text, IDL.Opt(Geolocation)], [ClaimResult], []),
+    update_rating: IDL.Func([IDL.Text, IDL.Nat32], [RatingResult], []),
   });
 };
 
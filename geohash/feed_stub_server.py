@@ -0,0 +1,48 @@
+#!/usr/bin/env python3
+# Stub of a public air quality API to test feeds against a local replica, e.g.
+#   python3 geohash/feed_stub_server.py 8088
+#   dfx canister call geohash add_feed '(record { name = "stub"; url_template = "http://localhost:8088/air-quality?latitude={lat}&longitude={lon}";
+#     fields = vec { record { path = "current.us_aqi"; metric = "Air quality index" } }; max_response_bytes = 10000 })'
+#   dfx canister call geohash run_refresh_now
+# Values are derived from the coordinate, so every replica gets the same values for a square; generated_at differs per
+# request like in real APIs and is dropped by the transform function of the canister.
+import json
+import sys
+import time
+from http.server import BaseHTTPRequestHandler, HTTPServer
+from urllib.parse import parse_qs, urlparse
+
+
+class AirQualityHandler(BaseHTTPRequestHandler):
+    def do_GET(self):
+        url = urlparse(self.path)
+        query = parse_qs(url.query)
+        if url.path != "/air-quality" or "latitude" not in query or "longitude" not in query:
+            self.send_error(404, "Use /air-quality?latitude=..&longitude=..")
+            return
+
+        latitude = float(query["latitude"][0])
+        longitude = float(query["longitude"][0])
+        seed = abs(round(latitude * 1000) * 31 + round(longitude * 1000))
+        body = json.dumps({
+            "latitude": latitude,
+            "longitude": longitude,
+            "generated_at": time.time(),
+            "current": {
+                "us_aqi": 20 + seed % 120,
+                "pm10": round(5 + (seed % 400) / 10, 1),
+                "basement_flooding": seed % 7 == 0,
+            },
+        }).encode()
+
+        self.send_response(200)
+        self.send_header("Content-Type", "application/json")
+        self.send_header("Content-Length", str(len(body)))
+        self.end_headers()
+        self.wfile.write(body)
+
+
+if __name__ == "__main__":
+    port = int(sys.argv[1]) if len(sys.argv) > 1 else 8088
+    print(f"Serving the air quality stub on http://localhost:{port}/air-quality")
+    HTTPServer(("", port), AirQualityHandler).serve_forever()
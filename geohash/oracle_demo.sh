@@ -0,0 +1,32 @@
+#!/usr/bin/env bash
+# Plays an oracle against a locally deployed geohash canister: registers a dfx identity as oracle and submits a batch
+# of observations (one of them out of range), then shows the stored metrics and the audit log.
+# Run from the repository root after `dfx deploy geohash`, with a controller of the canister as the current identity.
+set -e
+
+dfx identity new oracle --disable-encryption || true
+ORACLE=$(dfx --identity oracle identity get-principal)
+NOW=$(($(date +%s) * 1000000000))
+HOUR_AGO=$((NOW - 3600 * 1000000000))
+SQUARE='variant{Coordinate=record{latitude=48.137:float64;longitude=11.575:float64}}'
+
+echo "(*) Registering oracle $ORACLE with a limit of 100 observations per hour:"
+dfx canister call geohash register_oracle "(principal\"$ORACLE\",\"Local test oracle\",100:nat32)"
+dfx canister call geohash list_oracles
+
+echo '(*) Submitting a batch of observations for a square in Munich:'
+dfx --identity oracle canister call geohash submit_observations "(vec{
+    record{square=$SQUARE;metric=\"Air quality index\";value=variant{Nat=38:nat64};observed_at=$HOUR_AGO:nat64;source_reference=\"local-test/aqi/1\"};
+    record{square=$SQUARE;metric=\"Air quality index\";value=variant{Nat=41:nat64};observed_at=$NOW:nat64;source_reference=\"local-test/aqi/2\"};
+    record{square=$SQUARE;metric=\"Crime rate\";value=variant{Float=3.2:float64};observed_at=$NOW:nat64;source_reference=\"local-test/crime/1\"};
+    record{square=$SQUARE;metric=\"Air quality index\";value=variant{Nat=9000:nat64};observed_at=$NOW:nat64;source_reference=\"local-test/aqi/3\"};
+})"
+
+echo '(*) Latest metrics of the square:'
+dfx canister call geohash latest_metrics "($SQUARE)"
+echo '(*) Air quality history of the square:'
+dfx canister call geohash metric_history "($SQUARE,\"Air quality index\",null,null,null)"
+echo '(*) Audit log of the submissions:'
+dfx canister call geohash oracle_audit_log '(null,null)'
+echo '(*) Submissions by identities that are not oracles are rejected:'
+dfx --identity anonymous canister call geohash submit_observations '(vec{})'
@@ -1,21 +1,23 @@
 use ic_cdk::api::call::call;
 use candid::{Principal};
+use crate::error::{dependency_unavailable, BITCOIN_CANISTER};
+use crate::types::GeohashError;
 
 
 // get a Bitcoin address by passing in the geohash, same geohash yields same address
-pub async fn get_bitcoin_address(basic_bitcoin_canister_id: Principal, geohash: String) -> Result<String, String> {
+pub async fn get_bitcoin_address(basic_bitcoin_canister_id: Principal, geohash: String) -> Result<String, GeohashError> {
     let (address,): (String,) = call(basic_bitcoin_canister_id, "get_p2pkh_address", (geohash,))
         .await
-        .map_err(|err| format!("Failed to get Bitcoin address: {:?}", err))?;
+        .map_err(|err| dependency_unavailable(BITCOIN_CANISTER, err))?;
     Ok(address)
 }
 
 
 
 // get balance of a Bitcoin address by passing in the address
-pub async fn get_bitcoin_balance(basic_bitcoin_canister_id: Principal, address: String) -> Result<u64, String> {
+pub async fn get_bitcoin_balance(basic_bitcoin_canister_id: Principal, address: String) -> Result<u64, GeohashError> {
     let (balance,): (u64,) = call(basic_bitcoin_canister_id, "get_balance", (address,))
         .await
-        .map_err(|err| format!("Failed to get Bitcoin balance: {:?}", err))?;
+        .map_err(|err| dependency_unavailable(BITCOIN_CANISTER, err))?;
     Ok(balance)
 }
\ No newline at end of file
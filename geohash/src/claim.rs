@@ -0,0 +1,43 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use candid::Principal;
+use crate::types::{ClaimPolicy, GeohashError};
+
+// A square belongs to the first signed-in user who claims it. Controllers set the rules of claiming: whether users have
+// to submit a location inside the square they claim, and how many squares one principal can claim.
+
+// Most squares a policy can allow one principal to claim
+const MAX_CLAIMS_PER_PRINCIPAL: u32 = 1_000_000;
+
+// Function to check a claim policy
+pub fn validate_claim_policy(policy: &ClaimPolicy) -> Result<(), GeohashError> {
+    if let Some(max_claims) = policy.max_claims_per_principal {
+        if max_claims == 0 || max_claims > MAX_CLAIMS_PER_PRINCIPAL {
+            return Err(GeohashError::InvalidInput(format!("A principal can claim 1 to {} squares", MAX_CLAIMS_PER_PRINCIPAL)));
+        }
+    }
+    Ok(())
+}
+
+/// Function to check a new claim of a square against the policy: the caller has to be signed in, has to have claimed fewer
+/// squares than the limit and, if the policy asks for it, has to be located in the square. located_in_square is None if
+/// the caller did not submit a location.
+pub fn check_claim(caller: &Principal, geohash: &str, claims: u32, located_in_square: Option<bool>, policy: &ClaimPolicy) -> Result<(), GeohashError> {
+    if *caller == Principal::anonymous() {
+        return Err(GeohashError::AnonymousCaller);
+    }
+    if let Some(max_claims) = policy.max_claims_per_principal {
+        if claims >= max_claims {
+            return Err(GeohashError::ClaimLimitReached(max_claims));
+        }
+    }
+    if policy.require_location {
+        match located_in_square {
+            None => return Err(GeohashError::LocationRequired),
+            Some(false) => return Err(GeohashError::OutsideSquare(geohash.to_string())),
+            Some(true) => {}
+        }
+    }
+    Ok(())
+}
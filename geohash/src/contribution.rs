@@ -0,0 +1,146 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use candid::Principal;
+use crate::types::{Contribution, ContributionError, ContributionPolicy, ContributionStatus, Reputation, Vote};
+
+// Users log facts about squares as typed observations of a metric. Other users corroborate or dispute them: once enough
+// users corroborated a contribution it is accepted and stored as an observation of the square, once enough disputed it
+// it is rejected. Contributors and voters gain or lose reputation by the outcome.
+
+// Longest description of a contribution
+const MAX_DESCRIPTION_LENGTH: usize = 500;
+
+// Most votes a policy can ask for to decide a contribution
+const MAX_VOTES_TO_DECIDE: u32 = 100;
+
+// Number of contributions returned if no limit is given, and the most returned at once
+pub const DEFAULT_CONTRIBUTION_LIMIT: u32 = 100;
+pub const MAX_CONTRIBUTION_LIMIT: u32 = 1000;
+
+// Changes of the reputation score by the outcome of a contribution
+const ACCEPTED_CONTRIBUTION_SCORE: i64 = 10;
+const REJECTED_CONTRIBUTION_SCORE: i64 = -5;
+const AGREEING_VOTE_SCORE: i64 = 1;
+const DISAGREEING_VOTE_SCORE: i64 = -1;
+
+// Voters need some reputation by default, so new principals cannot decide contributions on their own; controllers lower
+// the minimum to let the first users vote before anyone earned reputation
+impl Default for ContributionPolicy {
+    fn default() -> Self {
+        ContributionPolicy {
+            corroborations_to_accept: 3,
+            disputes_to_reject: 3,
+            min_voter_reputation: 1,
+        }
+    }
+}
+
+impl Reputation {
+    /// Function to create the reputation of a user who never contributed or voted
+    pub fn new(principal: Principal) -> Self {
+        Reputation {
+            principal,
+            score: 0,
+            contributions: 0,
+            accepted: 0,
+            rejected: 0,
+            votes: 0,
+            agreeing_votes: 0,
+        }
+    }
+}
+
+// Function to reject the anonymous principal, contributions and votes have to be signed
+pub fn require_authenticated(caller: &Principal) -> Result<(), ContributionError> {
+    if *caller == Principal::anonymous() {
+        return Err(ContributionError::AnonymousCaller);
+    }
+    Ok(())
+}
+
+// Function to check the description of a contribution
+pub fn validate_description(description: &str) -> Result<(), ContributionError> {
+    if description.trim().is_empty() || description.chars().count() > MAX_DESCRIPTION_LENGTH {
+        return Err(ContributionError::InvalidDescription(description.chars().take(MAX_DESCRIPTION_LENGTH).collect()));
+    }
+    Ok(())
+}
+
+// Function to check a policy before controllers set it
+pub fn validate_policy(policy: &ContributionPolicy) -> Result<(), ContributionError> {
+    let votes = 1..=MAX_VOTES_TO_DECIDE;
+    if !votes.contains(&policy.corroborations_to_accept) || !votes.contains(&policy.disputes_to_reject) {
+        return Err(ContributionError::InvalidPolicy(format!("A contribution is decided by 1 to {} votes", MAX_VOTES_TO_DECIDE)));
+    }
+    Ok(())
+}
+
+/// Function to count the vote of a user on a pending contribution and decide it once a threshold of the policy is reached.
+/// Contributors cannot vote on their own contributions and every user votes once.
+pub fn apply_vote(contribution: &mut Contribution, voter: Principal, vote: Vote, policy: &ContributionPolicy, now: u64) -> Result<(), ContributionError> {
+    if contribution.status != ContributionStatus::Pending {
+        return Err(ContributionError::AlreadyDecided(contribution.status));
+    }
+    if voter == contribution.contributor {
+        return Err(ContributionError::OwnContribution);
+    }
+    if contribution.corroborations.contains(&voter) || contribution.disputes.contains(&voter) {
+        return Err(ContributionError::AlreadyVoted);
+    }
+
+    match vote {
+        Vote::Corroborate => contribution.corroborations.push(voter),
+        Vote::Dispute => contribution.disputes.push(voter),
+    }
+    if contribution.corroborations.len() >= policy.corroborations_to_accept as usize {
+        contribution.status = ContributionStatus::Accepted;
+    } else if contribution.disputes.len() >= policy.disputes_to_reject as usize {
+        contribution.status = ContributionStatus::Rejected;
+    }
+    if contribution.status != ContributionStatus::Pending {
+        contribution.decided_at = Some(now);
+    }
+    Ok(())
+}
+
+/// Function to credit the contributor and the voters of a decided contribution.
+/// Takes the current reputation of every user involved and returns their updated reputations.
+pub fn settle_reputations(contribution: &Contribution, mut reputation_of: impl FnMut(&Principal) -> Reputation) -> Vec<Reputation> {
+    let accepted = match contribution.status {
+        ContributionStatus::Pending => return vec![],
+        ContributionStatus::Accepted => true,
+        ContributionStatus::Rejected => false,
+    };
+
+    let mut contributor = reputation_of(&contribution.contributor);
+    if accepted {
+        contributor.score += ACCEPTED_CONTRIBUTION_SCORE;
+        contributor.accepted += 1;
+    } else {
+        contributor.score += REJECTED_CONTRIBUTION_SCORE;
+        contributor.rejected += 1;
+    }
+
+    let votes = contribution
+        .corroborations
+        .iter()
+        .map(|voter| (voter, accepted))
+        .chain(contribution.disputes.iter().map(|voter| (voter, !accepted)));
+    let voters = votes.map(|(voter, agreeing)| {
+        let mut reputation = reputation_of(voter);
+        if agreeing {
+            reputation.score += AGREEING_VOTE_SCORE;
+            reputation.agreeing_votes += 1;
+        } else {
+            reputation.score += DISAGREEING_VOTE_SCORE;
+        }
+        reputation
+    });
+    std::iter::once(contributor).chain(voters).collect()
+}
+
+// Function to build the key of a contribution in the index of the contributions of a square
+pub fn square_contribution_key(geohash: &str, id: u64) -> String {
+    format!("{}/{:020}", geohash, id)
+}
@@ -0,0 +1,231 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use ciborium::value::Value;
+use sha2::{Digest, Sha256};
+use crate::types::{MetricObservation, MetricValue, SquareDocument};
+
+// The data of every minted square is published as a content-addressed document: the document is encoded as canonical
+// DAG-CBOR and addressed by a CIDv1 of its SHA-256 hash, so anyone can check a document against its CID. Documents
+// are never changed; every change of a square publishes a new version linking to the previous one, and the name of
+// the square points to its latest version like an IPNS name.
+
+// Multicodec of DAG-CBOR blocks and multihash code of SHA-256
+const DAG_CBOR_CODEC: u8 = 0x71;
+const SHA2_256_CODE: u8 = 0x12;
+const CID_VERSION: u8 = 1;
+
+// CBOR tag of links to other documents in DAG-CBOR
+const CID_TAG: u64 = 42;
+
+// Lowercase RFC 4648 base32 alphabet, the default multibase of CIDv1 (prefix "b")
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+// Number of versions returned by a history query if no limit is given, and the most returned at once
+pub const DEFAULT_DOCUMENT_HISTORY_LIMIT: u32 = 100;
+pub const MAX_DOCUMENT_HISTORY_LIMIT: u32 = 1000;
+
+// Function to get the name pointing to the latest document of a square
+pub fn document_name(geohash: &str) -> String {
+    format!("geohash:{}", geohash)
+}
+
+// Function to encode bytes as lowercase base32 without padding
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 8 / 5 + 1);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    encoded
+}
+
+// Function to decode lowercase base32 without padding, None if it is not base32
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for character in text.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|letter| *letter == character)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
+
+// Function to compute the CIDv1 (DAG-CBOR, SHA-256) of a block in its base32 text form
+pub fn cid_of_block(block: &[u8]) -> String {
+    let mut cid = vec![CID_VERSION, DAG_CBOR_CODEC, SHA2_256_CODE, 32];
+    cid.extend_from_slice(&Sha256::digest(block));
+    format!("b{}", base32_encode(&cid))
+}
+
+// Function to get the binary form of a CID of a document from its text form, None if it is not one
+fn cid_bytes(cid: &str) -> Option<Vec<u8>> {
+    let bytes = base32_decode(cid.strip_prefix('b')?)?;
+    (bytes.len() == 36 && bytes[..4] == [CID_VERSION, DAG_CBOR_CODEC, SHA2_256_CODE, 32]).then_some(bytes)
+}
+
+// Function to check that a text is the CID of a document
+pub fn is_document_cid(cid: &str) -> bool {
+    cid_bytes(cid).is_some()
+}
+
+// Helper function to build a CBOR map with text keys
+fn text_map(entries: Vec<(&str, Value)>) -> Value {
+    Value::Map(entries.into_iter().map(|(key, value)| (Value::Text(key.to_string()), value)).collect())
+}
+
+// Helper function to turn an optional value into CBOR, None becoming null
+fn optional(value: Option<Value>) -> Value {
+    value.unwrap_or(Value::Null)
+}
+
+// Function to turn a value of a metric into CBOR, a map from the value type to the value
+fn metric_value(value: &MetricValue) -> Value {
+    let (value_type, value) = match value {
+        MetricValue::Nat(number) => ("Nat", Value::Integer((*number).into())),
+        MetricValue::Int(number) => ("Int", Value::Integer((*number).into())),
+        MetricValue::Float(number) => ("Float", Value::Float(*number)),
+        MetricValue::Bool(flag) => ("Bool", Value::Bool(*flag)),
+        MetricValue::Text(text) => ("Text", Value::Text(text.clone())),
+    };
+    text_map(vec![(value_type, value)])
+}
+
+// Function to turn an observation into CBOR
+fn observation(observation: &MetricObservation) -> Value {
+    text_map(vec![
+        ("metric", Value::Text(observation.metric.clone())),
+        ("value", metric_value(&observation.value)),
+        ("observed_at", Value::Integer(observation.observed_at.into())),
+        ("submitter", Value::Bytes(observation.submitter.as_slice().to_vec())),
+    ])
+}
+
+// Function to turn a document into its CBOR data model; principals are bytes and the previous version is a link
+fn document_value(document: &SquareDocument) -> Value {
+    let previous = document.previous.as_deref().and_then(cid_bytes).map(|cid| {
+        // Links are the binary CID behind the identity multibase prefix 0x00
+        Value::Tag(CID_TAG, Box::new(Value::Bytes([vec![0], cid].concat())))
+    });
+    let rating = document.rating.as_ref().map(|rating| {
+        text_map(vec![
+            ("count", Value::Integer(rating.count.into())),
+            ("mean", Value::Float(rating.mean)),
+            ("distribution", Value::Array(rating.distribution.iter().map(|users| Value::Integer((*users).into())).collect())),
+        ])
+    });
+
+    text_map(vec![
+        ("geohash", Value::Text(document.geohash.clone())),
+        ("version", Value::Integer(document.version.into())),
+        ("previous", optional(previous)),
+        ("updated_at", Value::Integer(document.updated_at.into())),
+        ("token_id", Value::Integer(document.token_id.into())),
+        ("owner", Value::Bytes(document.owner.as_slice().to_vec())),
+        ("bitcoin_address", Value::Text(document.bitcoin_address.clone())),
+        ("ethereum_address", Value::Text(document.ethereum_address.clone())),
+        ("minted_at", Value::Integer(document.minted_at.into())),
+        ("metrics", Value::Array(document.metrics.iter().map(observation).collect())),
+        ("rating", optional(rating)),
+    ])
+}
+
+// Function to write the head of a CBOR data item with the shortest encoding of its argument
+fn write_head(major_type: u8, argument: u64, block: &mut Vec<u8>) {
+    let major_type = major_type << 5;
+    match argument {
+        0..=23 => block.push(major_type | argument as u8),
+        24..=0xff => block.extend_from_slice(&[major_type | 24, argument as u8]),
+        0x100..=0xffff => {
+            block.push(major_type | 25);
+            block.extend_from_slice(&(argument as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            block.push(major_type | 26);
+            block.extend_from_slice(&(argument as u32).to_be_bytes());
+        }
+        _ => {
+            block.push(major_type | 27);
+            block.extend_from_slice(&argument.to_be_bytes());
+        }
+    }
+}
+
+/// Function to encode a CBOR value as canonical DAG-CBOR: shortest integer heads, definite lengths, 64-bit floats and
+/// map keys sorted by length, then bytewise. The same value always gives the same bytes.
+fn write_canonical(value: &Value, block: &mut Vec<u8>) {
+    match value {
+        Value::Integer(integer) => {
+            let integer = i128::from(*integer);
+            if integer >= 0 {
+                write_head(0, integer as u64, block);
+            } else {
+                write_head(1, (-1 - integer) as u64, block);
+            }
+        }
+        Value::Bytes(bytes) => {
+            write_head(2, bytes.len() as u64, block);
+            block.extend_from_slice(bytes);
+        }
+        Value::Text(text) => {
+            write_head(3, text.len() as u64, block);
+            block.extend_from_slice(text.as_bytes());
+        }
+        Value::Array(items) => {
+            write_head(4, items.len() as u64, block);
+            items.iter().for_each(|item| write_canonical(item, block));
+        }
+        Value::Map(entries) => {
+            let mut encoded: Vec<(Vec<u8>, &Value)> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut key_bytes = vec![];
+                    write_canonical(key, &mut key_bytes);
+                    (key_bytes, value)
+                })
+                .collect();
+            encoded.sort_by(|(left, _), (right, _)| left.len().cmp(&right.len()).then_with(|| left.cmp(right)));
+            write_head(5, encoded.len() as u64, block);
+            for (key, value) in encoded {
+                block.extend_from_slice(&key);
+                write_canonical(value, block);
+            }
+        }
+        Value::Tag(tag, value) => {
+            write_head(6, *tag, block);
+            write_canonical(value, block);
+        }
+        Value::Bool(flag) => block.push(if *flag { 0xf5 } else { 0xf4 }),
+        Value::Float(number) => {
+            block.push(0xfb);
+            block.extend_from_slice(&number.to_be_bytes());
+        }
+        // Documents are built from the values above only
+        _ => block.push(0xf6),
+    }
+}
+
+// Function to encode a document as a canonical DAG-CBOR block
+pub fn encode_document(document: &SquareDocument) -> Vec<u8> {
+    let mut block = vec![];
+    write_canonical(&document_value(document), &mut block);
+    block
+}
+
+// Function to check whether two versions of a document have the same content, apart from their place in the history
+pub fn same_content(left: &SquareDocument, right: &SquareDocument) -> bool {
+    SquareDocument { version: 0, previous: None, updated_at: 0, ..left.clone() } == SquareDocument { version: 0, previous: None, updated_at: 0, ..right.clone() }
+}
@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use std::fmt::Debug;
+use crate::types::{ContributionError, GeohashError, GridError, MetricError};
+
+// Every endpoint returns a GeohashError. The grid, metrics and contribution functions have their own errors; invalid
+// input, missing records and unauthorized callers among them become the matching GeohashError, the other errors are
+// kept as they are.
+
+// Names of the dependency canisters in errors
+pub const DIP721_CANISTER: &str = "DIP721";
+pub const BITCOIN_CANISTER: &str = "Bitcoin";
+pub const ETHEREUM_CANISTER: &str = "Ethereum";
+
+// Function to build the error of a call to a dependency canister that failed or was rejected
+pub fn dependency_unavailable(canister: &str, err: impl Debug) -> GeohashError {
+    GeohashError::DependencyUnavailable { canister: canister.to_string(), reason: format!("{:?}", err) }
+}
+
+impl From<GridError> for GeohashError {
+    fn from(err: GridError) -> Self {
+        match err {
+            GridError::InvalidLatitude(lat) => GeohashError::InvalidCoordinate(format!("Latitude {} is not between -90 and 90", lat)),
+            GridError::InvalidLongitude(lon) => GeohashError::InvalidCoordinate(format!("Longitude {} is not between -180 and 180", lon)),
+            GridError::InvalidGeohash(geohash) => GeohashError::InvalidGeohash(geohash),
+            GridError::NotASquare(geohash) => GeohashError::NotASquare(geohash),
+            GridError::InvalidRegion(reason) => GeohashError::InvalidInput(reason),
+            GridError::InvalidCode(code) => GeohashError::InvalidInput(format!("{} is not the code of a square", code)),
+            GridError::TooManyInputs(max) => GeohashError::TooManyInputs(max),
+        }
+    }
+}
+
+impl From<MetricError> for GeohashError {
+    fn from(err: MetricError) -> Self {
+        match err {
+            MetricError::Unauthorized => GeohashError::Unauthorized,
+            MetricError::InvalidSquare(err) => err.into(),
+            MetricError::TooManyInputs(max) => GeohashError::TooManyInputs(max),
+            err => GeohashError::Metric(err),
+        }
+    }
+}
+
+impl From<ContributionError> for GeohashError {
+    fn from(err: ContributionError) -> Self {
+        match err {
+            ContributionError::AnonymousCaller => GeohashError::AnonymousCaller,
+            ContributionError::Unauthorized => GeohashError::Unauthorized,
+            ContributionError::UnknownContribution(id) => GeohashError::NotFound(format!("Contribution {}", id)),
+            ContributionError::InvalidObservation(err) => err.into(),
+            err => GeohashError::Contribution(err),
+        }
+    }
+}
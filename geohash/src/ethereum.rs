@@ -1,9 +1,11 @@
 use ic_cdk::api::call::call;
 use candid::{Principal};
+use crate::error::{dependency_unavailable, ETHEREUM_CANISTER};
+use crate::types::GeohashError;
 
-pub async fn get_ethereum_address(basic_ethereum_canister_id: Principal, geohash: String) -> Result<String, String> {
+pub async fn get_ethereum_address(basic_ethereum_canister_id: Principal, geohash: String) -> Result<String, GeohashError> {
     let (address,): (String,) = call(basic_ethereum_canister_id, "ethereum_address", (None::<Option<Principal>>, geohash,))
         .await
-        .map_err(|err| format!("Failed to get Ethereum address: {:?}", err))?;
+        .map_err(|err| dependency_unavailable(ETHEREUM_CANISTER, err))?;
     Ok(address)
 }
\ No newline at end of file
@@ -0,0 +1,134 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use ic_cdk::api::management_canister::http_request::{http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse};
+use ic_cdk::api::management_canister::http_request::TransformContext;
+use candid::Nat;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use crate::types::{FeedConfig, MetricError, MetricValue, MetricValueType};
+
+// Feeds pull public data (e.g. air quality) from JSON APIs with HTTPS outcalls. Every replica of the subnet makes the
+// request, so the transform function reduces each response to the mapped fields; responses then agree even if the
+// API adds time stamps or request IDs.
+
+// Name of the query function transforming the responses of feeds
+pub const TRANSFORM_FUNCTION: &str = "transform_feed_response";
+
+// Cycles attached to an outcall; unused cycles are refunded
+const HTTP_OUTCALL_CYCLES: u128 = 2_000_000_000;
+
+// Limits of the configuration of a feed
+const MAX_RESPONSE_BYTES: u64 = 2_000_000;
+const MAX_FEED_FIELDS: usize = 20;
+
+// Function to check the configuration of a feed before it is added
+pub fn validate_feed(config: &FeedConfig) -> Result<(), MetricError> {
+    let invalid = |reason: &str| Err(MetricError::InvalidDefinition(reason.to_string()));
+
+    if config.name.trim().is_empty() {
+        return invalid("A feed needs a name");
+    }
+    // The replicas of the IC only connect with https, plain http is only useful against a local stub server
+    if !(config.url_template.starts_with("https://") || is_local_http(&config.url_template)) {
+        return invalid("The URL of a feed starts with https://, or with http://localhost for a local stub server");
+    }
+    if !config.url_template.contains("{lat}") || !config.url_template.contains("{lon}") {
+        return invalid("The URL of a feed contains {lat} and {lon}");
+    }
+    if config.fields.is_empty() || config.fields.len() > MAX_FEED_FIELDS {
+        return invalid("A feed maps 1 to 20 fields");
+    }
+    if config.max_response_bytes == 0 || config.max_response_bytes > MAX_RESPONSE_BYTES {
+        return invalid("The responses of a feed have at most 2 MB");
+    }
+    Ok(())
+}
+
+// Function to check whether a URL is plain http to the local machine, where the stub server for testing feeds runs
+fn is_local_http(url: &str) -> bool {
+    url.strip_prefix("http://").is_some_and(|rest| {
+        let host = rest.split(['/', ':', '?']).next().unwrap_or_default();
+        host == "localhost" || host == "127.0.0.1"
+    })
+}
+
+// Function to fill in the coordinate of a square centre in the URL template of a feed
+pub fn feed_url(url_template: &str, lat: f64, lon: f64) -> String {
+    url_template.replace("{lat}", &format!("{:.6}", lat)).replace("{lon}", &format!("{:.6}", lon))
+}
+
+// Function to find a field in a JSON document by its dot-separated path
+fn json_path<'a>(document: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.').try_fold(document, |value, segment| match value {
+        Value::Array(items) => items.get(segment.parse::<usize>().ok()?),
+        value => value.get(segment),
+    })
+}
+
+// Function to reduce a JSON response to the fields at the given paths, in a deterministic encoding
+pub fn extract_fields(body: &[u8], paths: &[String]) -> Result<BTreeMap<String, Value>, String> {
+    let document: Value = serde_json::from_slice(body).map_err(|err| format!("Response is not JSON: {}", err))?;
+    Ok(paths
+        .iter()
+        .filter_map(|path| json_path(&document, path).map(|value| (path.clone(), value.clone())))
+        .collect())
+}
+
+// Function to turn a JSON value into a value of a metric of the given type, None if it does not fit
+pub fn json_to_metric_value(value: &Value, value_type: MetricValueType) -> Option<MetricValue> {
+    match value_type {
+        MetricValueType::Nat => value
+            .as_u64()
+            .or_else(|| value.as_f64().filter(|number| number.is_finite() && *number >= 0.0).map(|number| number.round() as u64))
+            .map(MetricValue::Nat),
+        MetricValueType::Int => value
+            .as_i64()
+            .or_else(|| value.as_f64().filter(|number| number.is_finite()).map(|number| number.round() as i64))
+            .map(MetricValue::Int),
+        MetricValueType::Float => value.as_f64().map(MetricValue::Float),
+        MetricValueType::Bool => value.as_bool().map(MetricValue::Bool),
+        MetricValueType::Text => value.as_str().map(|text| MetricValue::Text(text.to_string())),
+    }
+}
+
+// Function to transform a response on every replica: drop the headers and keep only the mapped fields of the body
+// (the paths come as JSON in the context of the transform)
+pub fn transform_response(response: HttpResponse, context: &[u8]) -> HttpResponse {
+    let paths: Vec<String> = serde_json::from_slice(context).unwrap_or_default();
+    let body = extract_fields(&response.body, &paths)
+        .ok()
+        .and_then(|fields| serde_json::to_vec(&fields).ok())
+        .unwrap_or_default();
+
+    HttpResponse {
+        status: response.status,
+        headers: vec![],
+        body,
+    }
+}
+
+// Function to pull the mapped fields of a feed for a coordinate
+pub async fn fetch_feed_fields(config: &FeedConfig, lat: f64, lon: f64) -> Result<BTreeMap<String, Value>, MetricError> {
+    let paths: Vec<String> = config.fields.iter().map(|field| field.path.clone()).collect();
+    let request = CanisterHttpRequestArgument {
+        url: feed_url(&config.url_template, lat, lon),
+        max_response_bytes: Some(config.max_response_bytes),
+        method: HttpMethod::GET,
+        headers: vec![HttpHeader { name: "Accept".to_string(), value: "application/json".to_string() }],
+        body: None,
+        transform: Some(TransformContext::from_name(
+            TRANSFORM_FUNCTION.to_string(),
+            serde_json::to_vec(&paths).unwrap_or_default(),
+        )),
+    };
+
+    let (response,) = http_request(request, HTTP_OUTCALL_CYCLES)
+        .await
+        .map_err(|(code, message)| MetricError::FetchFailed(format!("{:?}: {}", code, message)))?;
+    if response.status != Nat::from(200u32) {
+        return Err(MetricError::FetchFailed(format!("HTTP status {}", response.status)));
+    }
+    // The transform already reduced the body to the mapped fields
+    serde_json::from_slice(&response.body).map_err(|err| MetricError::FetchFailed(format!("Unexpected response: {}", err)))
+}
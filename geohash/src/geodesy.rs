@@ -0,0 +1,78 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+// Mean earth radius in meters (IUGG)
+pub const EARTH_RADIUS_METERS: f64 = 6_371_008.8;
+
+// WGS84 ellipsoid
+const WGS84_SEMI_MAJOR_AXIS: f64 = 6_378_137.0;
+const WGS84_FLATTENING: f64 = 1.0 / 298.257_223_563;
+
+// Iteration limits of the Vincenty formula
+const VINCENTY_MAX_ITERATIONS: usize = 200;
+const VINCENTY_TOLERANCE: f64 = 1e-12;
+
+/// Function to calculate the great circle distance in meters between two coordinates on a spherical earth
+pub fn haversine_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    let (phi1, phi2) = (lat1.to_radians(), lat2.to_radians());
+    let delta_phi = (lat2 - lat1).to_radians();
+    let delta_lambda = (lon2 - lon1).to_radians();
+
+    let a = (delta_phi / 2.0).sin().powi(2) + phi1.cos() * phi2.cos() * (delta_lambda / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_METERS * a.sqrt().min(1.0).asin()
+}
+
+/// Function to calculate the distance in meters between two coordinates on the WGS84 ellipsoid (Vincenty's inverse formula).
+/// Returns None if the formula does not converge, which only happens for nearly antipodal points.
+pub fn vincenty_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> Option<f64> {
+    let a = WGS84_SEMI_MAJOR_AXIS;
+    let f = WGS84_FLATTENING;
+    let b = a * (1.0 - f);
+
+    let u1 = ((1.0 - f) * lat1.to_radians().tan()).atan();
+    let u2 = ((1.0 - f) * lat2.to_radians().tan()).atan();
+    let (sin_u1, cos_u1) = u1.sin_cos();
+    let (sin_u2, cos_u2) = u2.sin_cos();
+    let l = (lon2 - lon1).to_radians();
+
+    let mut lambda = l;
+    for _ in 0..VINCENTY_MAX_ITERATIONS {
+        let (sin_lambda, cos_lambda) = lambda.sin_cos();
+        let sin_sigma = ((cos_u2 * sin_lambda).powi(2) + (cos_u1 * sin_u2 - sin_u1 * cos_u2 * cos_lambda).powi(2)).sqrt();
+        if sin_sigma == 0.0 {
+            // Coincident points
+            return Some(0.0);
+        }
+        let cos_sigma = sin_u1 * sin_u2 + cos_u1 * cos_u2 * cos_lambda;
+        let sigma = sin_sigma.atan2(cos_sigma);
+        let sin_alpha = cos_u1 * cos_u2 * sin_lambda / sin_sigma;
+        let cos_sq_alpha = 1.0 - sin_alpha * sin_alpha;
+        // Both points on the equator
+        let cos_2sigma_m = if cos_sq_alpha == 0.0 { 0.0 } else { cos_sigma - 2.0 * sin_u1 * sin_u2 / cos_sq_alpha };
+        let c = f / 16.0 * cos_sq_alpha * (4.0 + f * (4.0 - 3.0 * cos_sq_alpha));
+
+        let previous_lambda = lambda;
+        lambda = l + (1.0 - c) * f * sin_alpha * (sigma + c * sin_sigma * (cos_2sigma_m + c * cos_sigma * (-1.0 + 2.0 * cos_2sigma_m * cos_2sigma_m)));
+
+        if (lambda - previous_lambda).abs() < VINCENTY_TOLERANCE {
+            let u_sq = cos_sq_alpha * (a * a - b * b) / (b * b);
+            let big_a = 1.0 + u_sq / 16384.0 * (4096.0 + u_sq * (-768.0 + u_sq * (320.0 - 175.0 * u_sq)));
+            let big_b = u_sq / 1024.0 * (256.0 + u_sq * (-128.0 + u_sq * (74.0 - 47.0 * u_sq)));
+            let delta_sigma = big_b
+                * sin_sigma
+                * (cos_2sigma_m
+                    + big_b / 4.0
+                        * (cos_sigma * (-1.0 + 2.0 * cos_2sigma_m * cos_2sigma_m)
+                            - big_b / 6.0 * cos_2sigma_m * (-3.0 + 4.0 * sin_sigma * sin_sigma) * (-3.0 + 4.0 * cos_2sigma_m * cos_2sigma_m)));
+            return Some(b * big_a * (sigma - delta_sigma));
+        }
+    }
+
+    None
+}
+
+/// Function to calculate the ground distance in meters between two coordinates.
+/// Uses the WGS84 ellipsoid and falls back to the spherical distance where the ellipsoidal formula does not converge.
+pub fn ground_distance(lat1: f64, lon1: f64, lat2: f64, lon2: f64) -> f64 {
+    vincenty_distance(lat1, lon1, lat2, lon2).unwrap_or_else(|| haversine_distance(lat1, lon1, lat2, lon2))
+}
@@ -0,0 +1,309 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use crate::area_generator::Area;
+use crate::grid_generator::{Resolution, SquareIndex, grid_rows, square_bounds, validate_bounds};
+use crate::types::GridError;
+use serde_json::Value;
+
+// Default and maximum number of squares returned per page
+pub const DEFAULT_PAGE_SIZE: u32 = 100;
+pub const MAX_PAGE_SIZE: u32 = 500;
+
+// Maximum number of squares the bounds of a region may span at the requested resolution
+pub const MAX_REGION_SQUARES: u64 = 1_000_000;
+
+// Maximum number of candidate squares checked against a polygon in one call
+const MAX_SCANNED_SQUARES: u64 = 20_000;
+
+// Maximum number of region vertices checked in one call; every candidate square is checked against all vertices of the
+// region, so regions with many vertices scan fewer squares per page to stay within the instruction limit
+const MAX_SCANNED_VERTICES: u64 = 5_000_000;
+
+// Maximum number of vertices of a GeoJSON region
+const MAX_REGION_VERTICES: usize = 5_000;
+
+// A closed ring of (longitude, latitude) points
+type Ring = Vec<(f64, f64)>;
+
+// A region made of one or more polygons, each with an outer ring followed by optional holes
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    polygons: Vec<Vec<Ring>>,
+}
+
+// One page of squares covering a region
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageScan {
+    pub squares: Vec<SquareIndex>,
+    pub next_cursor: Option<u64>,
+    pub total_candidates: u64,
+}
+
+impl Region {
+    /// Function to parse a GeoJSON Polygon or MultiPolygon geometry (optionally wrapped in a Feature)
+    pub fn from_geojson(geojson: &str) -> Result<Region, GridError> {
+        Self::parse(geojson).map_err(GridError::InvalidRegion)
+    }
+
+    fn parse(geojson: &str) -> Result<Region, String> {
+        let value: Value = serde_json::from_str(geojson).map_err(|err| format!("Invalid GeoJSON: {}", err))?;
+
+        let geometry = match value.get("type").and_then(Value::as_str) {
+            Some("Feature") => value.get("geometry").ok_or("GeoJSON feature has no geometry")?,
+            _ => &value,
+        };
+        let coordinates = geometry.get("coordinates").ok_or("GeoJSON geometry has no coordinates")?;
+
+        let polygons = match geometry.get("type").and_then(Value::as_str) {
+            Some("Polygon") => vec![parse_polygon(coordinates)?],
+            Some("MultiPolygon") => coordinates
+                .as_array()
+                .ok_or("MultiPolygon coordinates must be an array")?
+                .iter()
+                .map(parse_polygon)
+                .collect::<Result<Vec<_>, String>>()?,
+            other => return Err(format!("Unsupported GeoJSON geometry type: {:?}", other)),
+        };
+
+        let region = Region { polygons };
+        if region.polygons.is_empty() {
+            return Err("GeoJSON region has no polygons".to_string());
+        }
+        if region.vertex_count() > MAX_REGION_VERTICES {
+            return Err(format!("GeoJSON region has {} vertices, at most {} are allowed", region.vertex_count(), MAX_REGION_VERTICES));
+        }
+
+        Ok(region)
+    }
+
+    // Function to count the vertices of all rings of the region
+    fn vertex_count(&self) -> usize {
+        self.polygons.iter().flatten().map(Vec::len).sum()
+    }
+
+    /// Function to calculate the bounding box of the region. Regions split at the antimeridian get bounds crossing it
+    /// (lon_start greater than lon_end) instead of bounds around the whole globe.
+    pub fn bounds(&self) -> Area {
+        let outer_rings = || self.polygons.iter().filter_map(|polygon| polygon.first());
+        let mut bounds = Area {
+            lat_start: f64::MAX,
+            lon_start: f64::MAX,
+            lat_end: f64::MIN,
+            lon_end: f64::MIN,
+        };
+
+        for &(_, lat) in outer_rings().flatten() {
+            bounds.lat_start = bounds.lat_start.min(lat);
+            bounds.lat_end = bounds.lat_end.max(lat);
+        }
+
+        // Longitude ranges of the polygons, merged where they overlap
+        let mut ranges: Vec<(f64, f64)> = outer_rings()
+            .map(|ring| ring.iter().fold((f64::MAX, f64::MIN), |(start, end), &(lon, _)| (start.min(lon), end.max(lon))))
+            .collect();
+        ranges.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let mut merged: Vec<(f64, f64)> = Vec::new();
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        // The bounds leave out the widest gap between the ranges, which is the gap across the antimeridian unless the
+        // polygons lie on both sides of it
+        let (first, last) = (merged[0], merged[merged.len() - 1]);
+        let mut widest_gap = first.0 + 360.0 - last.1;
+        (bounds.lon_start, bounds.lon_end) = (first.0, last.1);
+        for pair in merged.windows(2) {
+            if pair[1].0 - pair[0].1 > widest_gap {
+                widest_gap = pair[1].0 - pair[0].1;
+                (bounds.lon_start, bounds.lon_end) = (pair[1].0, pair[0].1);
+            }
+        }
+
+        bounds
+    }
+
+    /// Function to check if a square intersects the region (touching the boundary counts as intersecting)
+    pub fn intersects(&self, area: &Area) -> bool {
+        let corners = [
+            (area.lon_start, area.lat_start),
+            (area.lon_end, area.lat_start),
+            (area.lon_end, area.lat_end),
+            (area.lon_start, area.lat_end),
+        ];
+
+        // A corner of the square lies inside the region
+        if corners.iter().any(|&(lon, lat)| self.contains(lon, lat)) {
+            return true;
+        }
+
+        let rings = self.polygons.iter().flatten();
+
+        // A vertex of the region lies inside the square
+        for &(lon, lat) in rings.clone().flatten() {
+            if lon >= area.lon_start && lon <= area.lon_end && lat >= area.lat_start && lat <= area.lat_end {
+                return true;
+            }
+        }
+
+        // An edge of the region crosses an edge of the square
+        for ring in rings {
+            for (start, end) in ring_edges(ring) {
+                for side in 0..4 {
+                    if segments_intersect(start, end, corners[side], corners[(side + 1) % 4]) {
+                        return true;
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    // Function to check if a point lies inside the region (inside an outer ring and outside its holes)
+    fn contains(&self, lon: f64, lat: f64) -> bool {
+        self.polygons.iter().any(|polygon| {
+            let mut rings = polygon.iter();
+            let inside_outer = rings.next().is_some_and(|outer| ring_contains(outer, lon, lat));
+            inside_outer && !rings.any(|hole| ring_contains(hole, lon, lat))
+        })
+    }
+}
+
+// Function to parse the rings of a GeoJSON polygon
+fn parse_polygon(coordinates: &Value) -> Result<Vec<Ring>, String> {
+    let rings = coordinates.as_array().ok_or("Polygon coordinates must be an array of rings")?;
+    if rings.is_empty() {
+        return Err("Polygon has no rings".to_string());
+    }
+
+    rings
+        .iter()
+        .map(|ring| {
+            let points = ring.as_array().ok_or("Polygon ring must be an array of positions")?;
+            let ring = points
+                .iter()
+                .map(|point| match point.as_array().map(|position| (position.first().and_then(Value::as_f64), position.get(1).and_then(Value::as_f64))) {
+                    Some((Some(lon), Some(lat))) if (-180.0..=180.0).contains(&lon) && (-90.0..=90.0).contains(&lat) => Ok((lon, lat)),
+                    _ => Err(format!("Invalid polygon position: {}", point)),
+                })
+                .collect::<Result<Ring, String>>()?;
+
+            if ring.len() < 3 {
+                return Err("Polygon ring needs at least 3 positions".to_string());
+            }
+
+            // Edges are straight lines in longitude/latitude; an edge spanning more than half the globe is taken to cross the
+            // antimeridian, unless it runs from one side of the map to the other (e.g. along a band around the globe)
+            let crosses_antimeridian = |((lon_a, _), (lon_b, _)): ((f64, f64), (f64, f64))| {
+                (lon_b - lon_a).abs() > 180.0 && !(lon_a.abs() == 180.0 && lon_b.abs() == 180.0)
+            };
+            if ring_edges(&ring).any(crosses_antimeridian) {
+                return Err("Polygon ring crosses the antimeridian, split it into a MultiPolygon at 180 degrees".to_string());
+            }
+            Ok(ring)
+        })
+        .collect()
+}
+
+// Function to list the edges of a ring, closing it if the last position does not repeat the first one
+fn ring_edges(ring: &Ring) -> impl Iterator<Item = ((f64, f64), (f64, f64))> + '_ {
+    (0..ring.len()).map(move |i| (ring[i], ring[(i + 1) % ring.len()]))
+}
+
+// Function to check if a point lies inside a ring (ray casting)
+fn ring_contains(ring: &Ring, lon: f64, lat: f64) -> bool {
+    let mut inside = false;
+    for ((lon_a, lat_a), (lon_b, lat_b)) in ring_edges(ring) {
+        if (lat_a > lat) != (lat_b > lat) && lon < (lon_b - lon_a) * (lat - lat_a) / (lat_b - lat_a) + lon_a {
+            inside = !inside;
+        }
+    }
+    inside
+}
+
+// Function to check if two segments intersect, including touching and collinear overlaps
+fn segments_intersect(p1: (f64, f64), p2: (f64, f64), q1: (f64, f64), q2: (f64, f64)) -> bool {
+    fn orientation(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> f64 {
+        (b.0 - a.0) * (c.1 - a.1) - (b.1 - a.1) * (c.0 - a.0)
+    }
+    fn on_segment(a: (f64, f64), b: (f64, f64), c: (f64, f64)) -> bool {
+        c.0 >= a.0.min(b.0) && c.0 <= a.0.max(b.0) && c.1 >= a.1.min(b.1) && c.1 <= a.1.max(b.1)
+    }
+
+    let d1 = orientation(q1, q2, p1);
+    let d2 = orientation(q1, q2, p2);
+    let d3 = orientation(p1, p2, q1);
+    let d4 = orientation(p1, p2, q2);
+
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0)) && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0)) {
+        return true;
+    }
+
+    (d1 == 0.0 && on_segment(q1, q2, p1))
+        || (d2 == 0.0 && on_segment(q1, q2, p2))
+        || (d3 == 0.0 && on_segment(p1, p2, q1))
+        || (d4 == 0.0 && on_segment(p1, p2, q2))
+}
+
+/// Function to list one page of the squares intersecting bounds (and optionally a region within them).
+/// The cursor counts the candidate squares of the bounds that were already scanned, row by row from south to north.
+/// Bounds with lon_start greater than lon_end cross the antimeridian.
+pub fn cover_region(bounds: &Area, resolution: Resolution, region: Option<&Region>, cursor: u64, limit: u32) -> Result<CoverageScan, GridError> {
+    validate_bounds(bounds)?;
+
+    let rows = grid_rows(bounds.lat_start, bounds.lon_start, bounds.lat_end, bounds.lon_end, resolution);
+    let total_candidates: u64 = rows.iter().map(|grid_row| grid_row.count as u64).sum();
+    if total_candidates > MAX_REGION_SQUARES {
+        return Err(GridError::InvalidRegion(format!(
+            "Region spans {} squares, at most {} are allowed; use a coarser resolution",
+            total_candidates, MAX_REGION_SQUARES
+        )));
+    }
+
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+    let max_scanned = match region {
+        Some(region) => (MAX_SCANNED_VERTICES / region.vertex_count().max(1) as u64).clamp(1, MAX_SCANNED_SQUARES),
+        None => MAX_SCANNED_SQUARES,
+    };
+    let mut squares = Vec::new();
+    let mut position = 0u64;
+    let mut scanned = 0u64;
+
+    'rows: for grid_row in rows {
+        // Skip rows that were completely scanned by previous pages
+        if position + grid_row.count as u64 <= cursor {
+            position += grid_row.count as u64;
+            continue;
+        }
+
+        let first_offset = cursor.saturating_sub(position) as u32;
+        position += first_offset as u64;
+
+        for offset in first_offset..grid_row.count {
+            if squares.len() == limit || scanned == max_scanned {
+                break 'rows;
+            }
+
+            let index = grid_row.square(offset);
+            let covered = match region {
+                Some(region) => region.intersects(&square_bounds(index)),
+                None => true,
+            };
+            if covered {
+                squares.push(index);
+            }
+            position += 1;
+            scanned += 1;
+        }
+    }
+
+    Ok(CoverageScan {
+        squares,
+        next_cursor: (position < total_candidates).then_some(position),
+        total_candidates,
+    })
+}
@@ -2,77 +2,387 @@
 // (C) 2024 Thomas Magerl
 
 use crate::area_generator::Area;
+use crate::types::GridError;
+use candid::{CandidType, Deserialize};
 use geohash::{encode, decode, Coord};
+use std::collections::HashSet;
+
+// Conversion factor from meters to degrees latitude
+const METERS_IN_DEGREE_LAT: f64 = 111320.0;
+
+// Square sizes available in the grid, from city level down to street level.
+// Every square is split into four squares of the next finer resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, CandidType, Deserialize)]
+pub enum Resolution {
+    Meters4000,
+    Meters2000,
+    Meters1000,
+    Meters500,
+    Meters250,
+}
+
+impl Resolution {
+    // All resolutions ordered from the coarsest to the finest
+    pub const ALL: [Resolution; 5] = [
+        Resolution::Meters4000,
+        Resolution::Meters2000,
+        Resolution::Meters1000,
+        Resolution::Meters500,
+        Resolution::Meters250,
+    ];
+
+    // Level of the resolution in the hierarchy (0 is the coarsest)
+    pub fn level(self) -> u32 {
+        Resolution::ALL.iter().position(|&resolution| resolution == self).unwrap() as u32
+    }
+
+    // Nominal edge length of a square in meters
+    pub fn step_meters(self) -> f64 {
+        4000.0 / (1u32 << self.level()) as f64
+    }
+
+    // Next coarser resolution, None for the coarsest one
+    pub fn parent(self) -> Option<Resolution> {
+        self.level().checked_sub(1).map(|level| Resolution::ALL[level as usize])
+    }
+
+    // Next finer resolution, None for the finest one
+    pub fn child(self) -> Option<Resolution> {
+        Resolution::ALL.get(self.level() as usize + 1).copied()
+    }
+}
+
+// Integer position of a square in the global grid (row counted from the south pole, column from the antimeridian)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SquareIndex {
+    pub resolution: Resolution,
+    pub row: u32,
+    pub col: u32,
+}
 
 // Function to decode a geohash back into coordinates
 pub fn decode_geohash(geohash: &str) -> Result<Coord, &'static str> {
     decode(geohash).map(|(coord, _, _)| coord).map_err(|_| "Invalid geohash")
 }
 
-// Function to calculate the bounds of a square based on its center and step sizes
-fn calculate_square_bounds(center_lat: f64, center_lon: f64, lat_step: f64, lon_step: f64) -> Area {
-    let half_lat = lat_step / 2.0;
-    let half_lon = lon_step / 2.0;
+/// Function to validate a coordinate: latitudes must be within [-90, 90] and longitudes within [-180, 180].
+/// Both 180 and -180 denote the antimeridian and map to the same squares.
+pub fn validate_coordinate(lat: f64, lon: f64) -> Result<(), GridError> {
+    if !(-90.0..=90.0).contains(&lat) {
+        return Err(GridError::InvalidLatitude(lat));
+    }
+    if !(-180.0..=180.0).contains(&lon) {
+        return Err(GridError::InvalidLongitude(lon));
+    }
+    Ok(())
+}
+
+/// Function to validate latitude and longitude bounds. Bounds with lon_start greater than lon_end cross the antimeridian.
+pub fn validate_bounds(bounds: &Area) -> Result<(), GridError> {
+    validate_coordinate(bounds.lat_start, bounds.lon_start)?;
+    validate_coordinate(bounds.lat_end, bounds.lon_end)?;
+    if bounds.lat_start > bounds.lat_end {
+        return Err(GridError::InvalidRegion("Bounds must start south of their end".to_string()));
+    }
+    Ok(())
+}
+
+// Function to calculate the number of rows that tile the globe from pole to pole.
+// The coarsest resolution defines the rows, every finer resolution splits each row in two.
+fn row_count(resolution: Resolution) -> u32 {
+    let nominal_lat_step = Resolution::Meters4000.step_meters() / METERS_IN_DEGREE_LAT;
+    ((180.0 / nominal_lat_step).round() as u32).max(1) << resolution.level()
+}
+
+// Function to calculate the southern edge of a grid row (rows tile the latitudes exactly, the last row ends at 90 degrees)
+fn row_lat_start(row: u32, resolution: Resolution) -> f64 {
+    let rows = row_count(resolution);
+    if row >= rows {
+        90.0
+    } else {
+        -90.0 + row as f64 * 180.0 / rows as f64
+    }
+}
+
+// Function to calculate the number of squares that tile a grid row around the globe.
+// The count is derived from the square width at the center latitude of the enclosing coarsest row, so squares stay
+// roughly square and every square is split into exactly two columns at the next finer resolution.
+// Polar cap policy: the coarsest row touching each pole is a single square covering all longitudes (a disc of about
+// 4 km around the pole), which splits into 2, 4, 8 and 16 columns at the finer resolutions.
+fn column_count(row: u32, resolution: Resolution) -> u32 {
+    let coarsest_row = row >> resolution.level();
+    if coarsest_row == 0 || coarsest_row + 1 == row_count(Resolution::Meters4000) {
+        return 1 << resolution.level();
+    }
+
+    let center_lat = (row_lat_start(coarsest_row, Resolution::Meters4000) + row_lat_start(coarsest_row + 1, Resolution::Meters4000)) / 2.0;
+    let meters_in_degree_lon = METERS_IN_DEGREE_LAT * center_lat.to_radians().cos();
+    ((360.0 * meters_in_degree_lon / Resolution::Meters4000.step_meters()).round() as u32).max(1) << resolution.level()
+}
+
+// Function to calculate the western edge of a square within its row (the last column ends at 180 degrees)
+fn col_lon_start(row: u32, col: u32, resolution: Resolution) -> f64 {
+    let cols = column_count(row, resolution);
+    if col >= cols {
+        180.0
+    } else {
+        -180.0 + col as f64 * 360.0 / cols as f64
+    }
+}
+
+// Function to find the row containing a latitude; rows are half-open [start, end) except the northernmost row which includes the pole
+fn row_of(lat: f64, resolution: Resolution) -> u32 {
+    let rows = row_count(resolution);
+    let mut row = (((lat + 90.0) / 180.0 * rows as f64).floor().max(0.0) as u32).min(rows - 1);
+
+    // Correct for floating point rounding so the row always agrees with the row bounds
+    while row > 0 && lat < row_lat_start(row, resolution) {
+        row -= 1;
+    }
+    while row + 1 < rows && lat >= row_lat_start(row + 1, resolution) {
+        row += 1;
+    }
+    row
+}
+
+// Function to find the column containing a longitude within a row; longitudes wrap around at the antimeridian
+fn col_of(row: u32, lon: f64, resolution: Resolution) -> u32 {
+    let cols = column_count(row, resolution);
+    let lon = if (-180.0..180.0).contains(&lon) { lon } else { (lon + 180.0).rem_euclid(360.0) - 180.0 };
+    let mut col = (((lon + 180.0) / 360.0 * cols as f64).floor().max(0.0) as u32).min(cols - 1);
+
+    // Correct for floating point rounding so the column always agrees with the square bounds
+    while col > 0 && lon < col_lon_start(row, col, resolution) {
+        col -= 1;
+    }
+    while col + 1 < cols && lon >= col_lon_start(row, col + 1, resolution) {
+        col += 1;
+    }
+    col
+}
+
+/// Function to compute the index of the square containing a given latitude and longitude at a given resolution.
+/// The squares tile the whole globe, so every point belongs to exactly one square per resolution and the index
+/// only depends on the coordinate.
+pub fn square_index(lat: f64, lon: f64, resolution: Resolution) -> SquareIndex {
+    let row = row_of(lat, resolution);
+    let col = col_of(row, lon, resolution);
+    SquareIndex { resolution, row, col }
+}
 
+/// Function to calculate the bounds of the square at a given index
+pub fn square_bounds(index: SquareIndex) -> Area {
     Area {
-        lat_start: center_lat - half_lat,
-        lon_start: center_lon - half_lon,
-        lat_end: center_lat + half_lat,
-        lon_end: center_lon + half_lon,
+        lat_start: row_lat_start(index.row, index.resolution),
+        lon_start: col_lon_start(index.row, index.col, index.resolution),
+        lat_end: row_lat_start(index.row + 1, index.resolution),
+        lon_end: col_lon_start(index.row, index.col + 1, index.resolution),
     }
 }
 
-// Function to create a grid with geohashes within given latitude and longitude bounds
-pub fn create_grid_with_geohash(lat_start: f64, lon_start: f64, lat_end: f64, lon_end: f64, step_meters: f64) -> Vec<(f64, f64, String, Area)> {
-    // Conversion factor from meters to degrees latitude
-    const METERS_IN_DEGREE_LAT: f64 = 111320.0;
+/// Function to calculate the center point (latitude, longitude) of a square
+pub fn square_center(index: SquareIndex) -> (f64, f64) {
+    let bounds = square_bounds(index);
+    ((bounds.lat_start + bounds.lat_end) / 2.0, (bounds.lon_start + bounds.lon_end) / 2.0)
+}
 
-    let mut grid_points_with_geohash = Vec::new();
+/// Function to compute the canonical geohash of a square (the 12 character geohash of its center point).
+/// The center of a square lies on the edges of its children, so geohashes never repeat across resolutions.
+pub fn square_geohash(index: SquareIndex) -> String {
+    let (center_lat, center_lon) = square_center(index);
+    encode(Coord { x: center_lon, y: center_lat }, 12).unwrap()
+}
 
-    // Calculate step size in degrees for latitude
-    let step_degrees_lat = step_meters / METERS_IN_DEGREE_LAT;
-
-    // Populate latitudes within the specified range
-    let mut latitudes = Vec::new();
-    let mut lat = lat_start;
-    while lat <= lat_end {
-        latitudes.push(lat);
-        lat += step_degrees_lat;
-    }
-
-    for &lat in &latitudes {
-        // Convert latitude from degrees to radians for accurate calculation
-        let radians_lat = (lat * std::f64::consts::PI) / 180.0;
-        // Conversion factor from meters to degrees longitude, adjusted for this latitude
-        let meters_in_degree_lon = METERS_IN_DEGREE_LAT * radians_lat.cos();
-        // Calculate step size in degrees for longitude
-        let step_degrees_lon = step_meters / meters_in_degree_lon;
-
-        let mut longitudes = Vec::new();
-        let mut lon = lon_start;
-        while lon <= lon_end {
-            longitudes.push(lon);
-            lon += step_degrees_lon;
+/// Function to find the square at a row and column of the grid, if the grid has such a square
+pub fn square_at(resolution: Resolution, row: u32, col: u32) -> Option<SquareIndex> {
+    (row < row_count(resolution) && col < column_count(row, resolution)).then_some(SquareIndex { resolution, row, col })
+}
+
+/// Function to find the square identified by a canonical square geohash, trying every resolution
+pub fn square_from_geohash(geohash: &str) -> Option<SquareIndex> {
+    let coord = decode_geohash(geohash).ok()?;
+
+    Resolution::ALL
+        .iter()
+        .map(|&resolution| square_index(coord.y, coord.x, resolution))
+        .find(|&index| square_geohash(index) == geohash)
+}
+
+/// Function to find the enclosing square at the next coarser resolution
+pub fn parent_square(index: SquareIndex) -> Option<SquareIndex> {
+    index.resolution.parent().map(|resolution| SquareIndex {
+        resolution,
+        row: index.row / 2,
+        col: index.col / 2,
+    })
+}
+
+/// Function to list the four squares at the next finer resolution that make up a square
+pub fn child_squares(index: SquareIndex) -> Vec<SquareIndex> {
+    match index.resolution.child() {
+        Some(resolution) => [(0, 0), (0, 1), (1, 0), (1, 1)]
+            .iter()
+            .map(|&(row_offset, col_offset)| SquareIndex {
+                resolution,
+                row: index.row * 2 + row_offset,
+                col: index.col * 2 + col_offset,
+            })
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+// Maximum number of steps for ring queries (a ring of 10 steps covers about 21 x 21 squares)
+pub const MAX_RING_STEPS: u32 = 10;
+
+// Function to list the squares of a row that touch a longitude range, wrapping around the antimeridian
+fn touching_squares_in_row(row: u32, lon_start: f64, lon_end: f64, resolution: Resolution) -> Vec<SquareIndex> {
+    let cols = column_count(row, resolution);
+
+    // Include the square ending exactly at the western edge, it touches at a corner
+    let mut first_col = col_of(row, lon_start, resolution);
+    if col_lon_start(row, first_col, resolution) == lon_start {
+        first_col = (first_col + cols - 1) % cols;
+    }
+    let last_col = col_of(row, lon_end, resolution);
+    let count = ((last_col + cols - first_col) % cols + 1).min(cols);
+
+    (0..count)
+        .map(|offset| SquareIndex { resolution, row, col: (first_col + offset) % cols })
+        .collect()
+}
+
+/// Function to list the squares sharing an edge or a corner with a square, ordered from north-west to south-east.
+/// Inside a 4 km band there are 8 neighbours. Where two bands meet, the columns of the adjacent row are offset,
+/// so a square may touch 2 or 3 squares of that row. Next to the poles there are fewer neighbours.
+pub fn neighbour_squares(index: SquareIndex) -> Vec<SquareIndex> {
+    let bounds = square_bounds(index);
+    let rows = row_count(index.resolution);
+    let cols = column_count(index.row, index.resolution);
+    let mut neighbours = Vec::new();
+
+    // Squares in the row to the north
+    if index.row + 1 < rows {
+        neighbours.extend(touching_squares_in_row(index.row + 1, bounds.lon_start, bounds.lon_end, index.resolution));
+    }
+
+    // Squares to the west and to the east in the same row
+    for col in [(index.col + cols - 1) % cols, (index.col + 1) % cols] {
+        neighbours.push(SquareIndex { col, ..index });
+    }
+
+    // Squares in the row to the south
+    if index.row > 0 {
+        neighbours.extend(touching_squares_in_row(index.row - 1, bounds.lon_start, bounds.lon_end, index.resolution));
+    }
+
+    // Rows with very few columns may wrap around to the same square
+    let mut unique = Vec::new();
+    for neighbour in neighbours {
+        if neighbour != index && !unique.contains(&neighbour) {
+            unique.push(neighbour);
         }
+    }
+    unique
+}
+
+/// Function to list all squares within a number of steps around a square, including the square itself.
+/// Squares are ordered by their distance in steps (the square first), and within a ring from north-west to south-east.
+/// Callers reject more than MAX_RING_STEPS steps, the ring stops there anyway.
+pub fn square_ring(index: SquareIndex, steps: u32) -> Vec<SquareIndex> {
+    let mut ring = vec![index];
+    let mut seen: HashSet<SquareIndex> = HashSet::from([index]);
+    let mut frontier = vec![index];
+
+    for _ in 0..steps.min(MAX_RING_STEPS) {
+        let mut next_frontier = Vec::new();
+        for square in &frontier {
+            for neighbour in neighbour_squares(*square) {
+                if seen.insert(neighbour) {
+                    next_frontier.push(neighbour);
+                }
+            }
+        }
+
+        next_frontier.sort_by(|a, b| b.row.cmp(&a.row).then(a.col.cmp(&b.col)));
+        ring.extend(next_frontier.iter().copied());
+        frontier = next_frontier;
+    }
 
-        for &lon in &longitudes {
-            // Calculate the center point of each grid square
-            let center_lat = lat + step_degrees_lat / 2.0;
-            let center_lon = lon + step_degrees_lon / 2.0;
+    ring
+}
+
+// A run of consecutive squares in one row of the grid, wrapping around the antimeridian
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GridRow {
+    pub resolution: Resolution,
+    pub row: u32,
+    pub first_col: u32,
+    pub count: u32,
+    columns: u32,
+}
+
+impl GridRow {
+    // Square at a position within the run
+    pub fn square(&self, offset: u32) -> SquareIndex {
+        SquareIndex {
+            resolution: self.resolution,
+            row: self.row,
+            col: (self.first_col + offset) % self.columns,
+        }
+    }
+}
+
+/// Function to list the runs of squares covering latitude and longitude bounds, row by row from south to north.
+/// Bounds with lon_start greater than lon_end cross the antimeridian. Only the column range of each row is computed,
+/// so large bounds can be counted and paged without generating every square.
+pub fn grid_rows(lat_start: f64, lon_start: f64, lat_end: f64, lon_end: f64, resolution: Resolution) -> Vec<GridRow> {
+    let first_row = row_of(lat_start, resolution);
+    let last_row = row_of(lat_end, resolution);
+
+    (first_row..=last_row)
+        .map(|row| {
+            let columns = column_count(row, resolution);
+            let (first_col, count) = if lon_start <= lon_end {
+                // 180 degrees is the end of the last column, not the start of the first one, also for bounds starting there
+                let east_col = |lon: f64| if lon >= 180.0 { columns - 1 } else { col_of(row, lon, resolution) };
+                let (first_col, last_col) = (east_col(lon_start), east_col(lon_end));
+                (first_col, last_col - first_col + 1)
+            } else {
+                // Crossing the antimeridian: if both ends fall into the same column the bounds wrap around the whole row
+                let (first_col, last_col) = (col_of(row, lon_start, resolution), col_of(row, lon_end, resolution));
+                (first_col, if last_col == first_col { columns } else { (last_col + columns - first_col) % columns + 1 })
+            };
+
+            GridRow {
+                resolution,
+                row,
+                first_col,
+                count,
+                columns,
+            }
+        })
+        .collect()
+}
+
+// Function to create a grid with geohashes within given latitude and longitude bounds.
+// The returned squares are taken from the global grid, so overlapping bounds always yield identical squares; only the
+// tests use it, the canister pages through grid_rows instead.
+#[cfg(test)]
+pub fn create_grid_with_geohash(lat_start: f64, lon_start: f64, lat_end: f64, lon_end: f64, resolution: Resolution) -> Vec<(f64, f64, String, Area)> {
+    let mut grid_points_with_geohash = Vec::new();
+
+    for grid_row in grid_rows(lat_start, lon_start, lat_end, lon_end, resolution) {
+        for offset in 0..grid_row.count {
+            let index = grid_row.square(offset);
+
+            // Calculate bounds and the center point of the square
+            let square_bounds = square_bounds(index);
+            let (center_lat, center_lon) = square_center(index);
 
             // Generate geohash for the center point
-            let geohash_key = encode(Coord { x: center_lon, y: center_lat }, 12).unwrap();
-
-            // Calculate bounds for the square
-            let square_bounds = calculate_square_bounds(center_lat, center_lon, step_degrees_lat, step_degrees_lon);
-
-            // Print the details of the area and the square
-            /*
-            println!("GRID_GENERATOR_ area bounds: lat_start = {}, lon_start = {}, lat_end = {}, lon_end = {}", lat_start, lon_start, lat_end, lon_end);
-            println!("GRID_GENERATOR_ step size in degrees: lat_step = {}", step_degrees_lat);
-            println!("GRID_GENERATOR_ latitude: {}, step size in degrees for longitude: {}", lat, step_degrees_lon);
-            println!("GRID_GENERATOR_ square center: ({}, {}), geohash: {}, bounds: {:?}", center_lat, center_lon, geohash_key, square_bounds);
-            */
+            let geohash_key = square_geohash(index);
+
             // Add the grid point with its geohash and bounds to the array
             grid_points_with_geohash.push((center_lat, center_lon, geohash_key, square_bounds));
         }
@@ -80,4 +390,3 @@ pub fn create_grid_with_geohash(lat_start: f64, lon_start: f64, lat_end: f64, lo
 
     grid_points_with_geohash
 }
-
@@ -1,40 +1,32 @@
 // SPDX-License-Identifier: MIT
 // (C) 2024 Thomas Magerl
 
-use crate::grid_generator::create_grid_with_geohash;
+use crate::grid_generator::{Resolution, SquareIndex, square_index, square_bounds, square_geohash, square_center};
 use crate::area_generator::Area;
+use crate::geodesy::ground_distance;
 
-/// Function to find the geohash for the nearest area (square) for given latitude and longitude
-pub fn find_nearest_geohash_with_bounds(lat: f64, lon: f64) -> (String, Area) {
-    let step_meters = 500.0;
+// Resolution of the squares that are minted as NFTs
+pub const DEFAULT_RESOLUTION: Resolution = Resolution::Meters500;
 
-    // Calculate the grid boundaries
-    let area = crate::area_generator::calculate_area(lat, lon);
+/// Function to find the geohash for the nearest area (square) for given latitude, longitude and resolution.
+/// The square is matched by containment in the global grid, not by comparing distances in degrees,
+/// so the match is not skewed at high latitudes where a degree of longitude is short.
+pub fn find_nearest_geohash_with_bounds(lat: f64, lon: f64, resolution: Resolution) -> (String, Area) {
+    // Compute the square containing the location directly from its coordinates
+    let index = square_index(lat, lon, resolution);
 
-    // Generate grid points with their corresponding geohashes and bounds within these boundaries
-    let grid_points_with_geohash = create_grid_with_geohash(area.lat_start, area.lon_start, area.lat_end, area.lon_end, step_meters);
-
-    let mut closest_geohash = String::new();
-    let mut closest_bounds = Area {
-        lat_start: 0.0,
-        lon_start: 0.0,
-        lat_end: 0.0,
-        lon_end: 0.0,
-    };
-    let mut min_distance = f64::MAX;
-
-    // Iterate through each grid point to find the nearest one
-    for (center_lat, center_lon, geohash_key, square_bounds) in grid_points_with_geohash {
-        // Calculate Euclidean distance to the given location
-        let distance = ((center_lat - lat).powi(2) + (center_lon - lon).powi(2)).sqrt();
+    // Return the geohash of the square center and its bounds
+    (square_geohash(index), square_bounds(index))
+}
 
-        if distance < min_distance {
-            min_distance = distance;
-            closest_geohash = geohash_key;
-            closest_bounds = square_bounds;
-        }
-    }
+/// Function to calculate the ground distance in meters from a location to the center of a square
+pub fn distance_to_center(lat: f64, lon: f64, index: SquareIndex) -> f64 {
+    let (center_lat, center_lon) = square_center(index);
+    ground_distance(lat, lon, center_lat, center_lon)
+}
 
-    // Return the nearest geohash and its bounds
-    (closest_geohash, closest_bounds)
+/// Function to calculate the ground distance in meters between the centers of two squares
+pub fn distance_between_centers(from: SquareIndex, to: SquareIndex) -> f64 {
+    let (lat, lon) = square_center(from);
+    distance_to_center(lat, lon, to)
 }
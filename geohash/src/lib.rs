@@ -12,13 +12,28 @@ mod nft_lookup;
 mod area_generator;
 mod grid_generator;
 mod grid_match;
+mod grid_coverage;
+mod geodesy;
+mod square_code;
+mod storage;
+mod metrics;
+mod oracle;
+mod feed;
+mod refresh;
+mod contribution;
+mod document;
+mod error;
+mod provisioning;
+mod claim;
+mod nft_transfer;
+mod reversion;
 
 // IC-Related Imports
 use candid::{Principal};
 use ic_cdk_macros::*;
 
 // Types
-use crate::types::{Geolocation, Nft, SquareProperties, GetEthereumAddressInput, Wallet, MetadataVal};
+use crate::types::{InitArgs, Geolocation, AreaResponse, Nft, SquareProperties, GetEthereumAddressInput, Wallet, MetadataVal, SquareInfo, CoveredSquare, CoveragePage, GridError, SquareRecord, SquareStatus, SquareLookup, SquareInput, MetricDefinition, MetricObservation, MetricValue, MetricValueType, MetricError, AggregationPeriod, MetricAggregate, Oracle, OracleObservation, AuditEntry, FeedConfig, FeedStatus, SquareBalances, RefreshFailure, RefreshJobStatus, PendingMint, Contribution, ContributionError, ContributionPolicy, ContributionStatus, Reputation, Vote, ClaimPolicy, ReversionPolicy, SquareReversion, ReversionEvent, ReversionEventKind, RatingSummary, SquareDocument, DocumentPointer, GeohashError, ProvisioningState, ProvisioningStage, StuckSquares};
 
 // Functions from bitcoin
 use bitcoin::{get_bitcoin_address, get_bitcoin_balance};
@@ -27,23 +42,67 @@ use bitcoin::{get_bitcoin_address, get_bitcoin_balance};
 use ethereum::get_ethereum_address;
 
 // Functions from nft_mint
-use nft_mint::{mint_nft, create_metadata};
+use nft_mint::{mint_nft, mint_idempotency_key};
 
 
 // Functions from nft_lookup
-use nft_lookup::{get_nft_by_geohash};
+use nft_lookup::{get_nft_by_geohash, get_nft_owner};
 
 
 // Functions from grid_match and grid_generator
-use grid_match::find_nearest_geohash_with_bounds;
-use grid_generator::decode_geohash;
+use grid_match::{find_nearest_geohash_with_bounds, distance_to_center, distance_between_centers, DEFAULT_RESOLUTION};
+use grid_generator::{decode_geohash, validate_coordinate, Resolution, SquareIndex, square_from_geohash, square_geohash, square_bounds, square_center, parent_square, child_squares, neighbour_squares, square_ring, MAX_RING_STEPS};
+
+// Functions from square_code
+use square_code::{square_code, square_from_code};
+
+// Functions from metrics
+use metrics::{validate_definition, validate_value, aggregatable_value, aggregate_series, MIN_RATING, MAX_RATING, DEFAULT_HISTORY_LIMIT, MAX_HISTORY_LIMIT, MAX_SERIES_OBSERVATIONS};
+
+// Functions from oracle
+use oracle::{OracleState, validate_timestamp, validate_source_reference, MAX_ORACLE_BATCH, DEFAULT_AUDIT_LIMIT, MAX_AUDIT_LIMIT};
+
+// Functions from feed
+use feed::{validate_feed, fetch_feed_fields, json_to_metric_value, transform_response};
+use ic_cdk::api::management_canister::http_request::{HttpResponse, TransformArgs};
+use ic_cdk_timers::TimerId;
+
+// Import refresh job functions
+use refresh::{validate_refresh_settings, record_batch};
+
+// Import document functions
+use document::{document_name, encode_document, is_document_cid, DEFAULT_DOCUMENT_HISTORY_LIMIT, MAX_DOCUMENT_HISTORY_LIMIT};
+
+// Import provisioning functions
+use provisioning::{record_failure, due_for_retry, should_abandon, group_by_stage, RETRY_INTERVAL_SECONDS, RETRY_BATCH_SIZE};
+
+// Import claim functions
+use claim::{check_claim, validate_claim_policy};
+
+// Import NFT transfer functions
+use nft_transfer::transfer_nft;
+
+// Import reversion functions
+use reversion::{validate_reversion_policy, risk_reasons, next_reversion, donations_since, due_for_evaluation, due_for_reversion, EVALUATION_TICK_SECONDS, EVALUATION_BATCH_SIZE, DEFAULT_EVENT_LIMIT, MAX_EVENT_LIMIT};
+
+// Import error functions
+use error::{BITCOIN_CANISTER, DIP721_CANISTER, ETHEREUM_CANISTER};
+
+// Import contribution functions
+use contribution::{require_authenticated, validate_description, validate_policy, apply_vote, settle_reputations, square_contribution_key, DEFAULT_CONTRIBUTION_LIMIT, MAX_CONTRIBUTION_LIMIT};
+
+// Stable memory
+use storage::{StableState, Cbor, Config};
+use ic_stable_structures::DefaultMemoryImpl;
+
+// Functions from grid_coverage
+use grid_coverage::{Region, CoverageScan, cover_region, DEFAULT_PAGE_SIZE};
+use area_generator::Area;
 
 // Standard Library Imports
 use std::cell::RefCell;
-use std::collections::HashMap;
-use serde_json::json;
-use sha2::{Sha256, Digest};
-use base58::{ToBase58};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
 //use rand::Rng;
 use std::time::{SystemTime, UNIX_EPOCH};
 
@@ -65,333 +124,1007 @@ async fn fetch_ethereum_address(input: GetEthereumAddressInput) -> Result<String
 
 thread_local! {
 
-    // NFT canister ID
-    static DIP721_CANISTER_ID: RefCell<Option<Principal>> = RefCell::new(None);
-    
-    // Bitcoin canister ID
-    static BASIC_BITCOIN_CANISTER_ID: RefCell<Option<Principal>> = RefCell::new(None);
+    // All state lives in stable memory: canister IDs, the mapping of geohash to token ID, the records of minted squares and
+    // of mints in progress, their metrics and the content-addressed documents of the squares
+    static STATE: RefCell<StableState> = RefCell::new(StableState::init(DefaultMemoryImpl::default()));
+}
 
-    // Ethereum canister ID
-    static BASIC_ETHEREUM_CANISTER_ID: RefCell<Option<Principal>> = RefCell::new(None);
-    // Mapping of geohash to token ID
-    static GEOHASH_TO_TOKEN_ID: RefCell<HashMap<String, u64>> = RefCell::new(HashMap::new());
+// Timer of the refresh job and whether a batch is running; timers do not survive upgrades, so post_upgrade schedules
+// the job again
+thread_local! {
+    static REFRESH_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+    static REFRESH_RUNNING: RefCell<bool> = const { RefCell::new(false) };
+}
 
-    // Mapping of IPNS data (mocked for now, it should point to the changing IPFS CID but we don't have IPFS integration yet, so we map to a HashMap)
-    static IPNS_DATA: RefCell<HashMap<String, HashMap<String, u32>>> = RefCell::new(HashMap::new());
+// Timer retrying failed and interrupted mints, scheduled again after upgrades
+thread_local! {
+    static PROVISIONING_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+// Timer evaluating the owned squares against the reversion policy in batches, scheduled again after upgrades
+thread_local! {
+    static REVERSION_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
 }
 
+// Squares whose mint is awaiting the Bitcoin, Ethereum and DIP721 canisters; nobody else can mint them meanwhile
+thread_local! {
+    static MINTS_IN_FLIGHT: RefCell<BTreeSet<String>> = const { RefCell::new(BTreeSet::new()) };
+}
+
+// Maximum number of inputs resolved in one batch call
+const MAX_BATCH_INPUTS: u32 = 20;
+
 // END LOCAL STORAGE
 
 
 // START INIT FUNCTIONS
 
 #[init]
-fn init() {
+fn init(args: Option<InitArgs>) {
+
+    // Canister IDs are passed at install time (dfx deploy geohash --argument '(opt record { ... })'); without them
+    // the canister starts unconfigured until a controller calls set_dependency_canisters
+    match args {
+        Some(args) => apply_init_args(args),
+        None => ic_cdk::println!("Initializing without dependency canisters, call set_dependency_canisters to configure them"),
+    }
 
-    // manually loading canister ids for now (mvp only)
-    let dip721_canister_id = "br5f7-7uaaa-aaaaa-qaaca-cai";
-    ic_cdk::println!("Initializing with DIP721_CANISTER_ID: {:?}", dip721_canister_id);
-    let dip721_canister_principal = Principal::from_text(dip721_canister_id).expect("Invalid hardcoded DIP721_CANISTER_ID principal");
-    set_dip721_canister_id(Some(dip721_canister_principal));
-    ic_cdk::println!("DIP721_CANISTER_ID set to: {:?}", dip721_canister_principal);
+    // Set up the fresh stable memory: current schema version and the default metrics
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap_or_else(|err| ic_cdk::trap(&err));
 
+    // Start refreshing the minted squares retrying failed mints and evaluating the owned squares
+    schedule_refresh_job(STATE.with(|state| state.borrow().refresh_job().interval_seconds));
+    schedule_provisioning_retries();
+    schedule_reversion_job();
 
-    let basic_bitcoin_canister_id = "b77ix-eeaaa-aaaaa-qaada-cai";
-    ic_cdk::println!("Initializing with BASIC_BITCOIN_CANISTER_ID: {:?}", basic_bitcoin_canister_id);
-    let basic_bitcoin_canister_principal = Principal::from_text(basic_bitcoin_canister_id).expect("Invalid BASIC_BITCOIN_CANISTER_ID principal");
-    set_bitcoin_canister_id(Some(basic_bitcoin_canister_principal));
-    ic_cdk::println!("BASIC_BITCOIN_CANISTER_ID set to: {:?}", basic_bitcoin_canister_principal);
+    // Logging to verify initialization
+    let stored_bitcoin_canister_id = STATE.with(|state| state.borrow().config().bitcoin_canister_id);
+    ic_cdk::println!("Initialized BASIC_BITCOIN_CANISTER_ID: {:?}", stored_bitcoin_canister_id);
+}
 
+// State is kept in stable memory, so upgrades only have to bring its schema up to date (and optionally rewire the canister IDs)
+#[post_upgrade]
+fn post_upgrade(args: Option<InitArgs>) {
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap_or_else(|err| ic_cdk::trap(&err));
 
-    let basic_ethereum_canister_id = "bd3sg-teaaa-aaaaa-qaaba-cai";
-    ic_cdk::println!("Initializing with BASIC_ETHEREUM_CANISTER_ID: {:?}", basic_ethereum_canister_id);
-    let basic_ethereum_canister_principal = Principal::from_text(basic_ethereum_canister_id).expect("Invalid BASIC_ETHEREUM_CANISTER_ID principal");
-    set_ethereum_canister_id(Some(basic_ethereum_canister_principal));
-    ic_cdk::println!("BASIC_ETHEREUM_CANISTER_ID set to: {:?}", basic_ethereum_canister_principal);
+    if let Some(args) = args {
+        apply_init_args(args);
+    }
 
+    // Restart refreshing the minted squares retrying failed mints and evaluating the owned squares
+    schedule_refresh_job(STATE.with(|state| state.borrow().refresh_job().interval_seconds));
+    schedule_provisioning_retries();
+    schedule_reversion_job();
 
-    // Clear stable storage
-    GEOHASH_TO_TOKEN_ID.with(|map| *map.borrow_mut() = HashMap::new());
-    //set_dip721_canister_id(None);
+    let config = STATE.with(|state| state.borrow().config());
+    ic_cdk::println!("Post-upgrade schema version: {:?}", config.schema_version);
+    ic_cdk::println!("Post-upgrade DIP721_CANISTER_ID: {:?}", config.dip721_canister_id);
+    ic_cdk::println!("Post-upgrade BASIC_BITCOIN_CANISTER_ID: {:?}", config.bitcoin_canister_id);
+}
 
-    // Logging to verify initialization
-    let stored_bitcoin_canister_id = BASIC_BITCOIN_CANISTER_ID.with(|id| id.borrow().clone());
-    ic_cdk::println!("Initialized BASIC_BITCOIN_CANISTER_ID: {:?}", stored_bitcoin_canister_id);
+// Function to validate and store the canister IDs passed on install or upgrade, refusing to start with invalid IDs
+fn apply_init_args(args: InitArgs) {
+    validate_dependency_canisters(&args).unwrap_or_else(|err| ic_cdk::trap(&err));
+    store_dependency_canisters(args).unwrap_or_else(|err| ic_cdk::trap(&format!("{:?}", err)));
 }
 
 // END INIT FUNCTIONS
 
 // START HELPER FUNCTIONS
 
-// Helper functions to get and set state
-pub fn set_dip721_canister_id(dip721_canister_id: Option<Principal>) {
-    DIP721_CANISTER_ID.with(|id| *id.borrow_mut() = dip721_canister_id);
+// Helper function to check the canister IDs of the dependencies: real canisters, each one different
+fn validate_dependency_canisters(args: &InitArgs) -> Result<(), String> {
+    let canisters = [
+        ("DIP721", args.dip721_canister_id),
+        ("Bitcoin", args.bitcoin_canister_id),
+        ("Ethereum", args.ethereum_canister_id),
+    ];
+
+    for (name, id) in canisters {
+        if id == Principal::anonymous() || id == Principal::management_canister() {
+            return Err(format!("{} canister ID {} is not a canister", name, id.to_text()));
+        }
+    }
+    for (position, (name, id)) in canisters.iter().enumerate() {
+        if let Some((other, _)) = canisters[position + 1..].iter().find(|(_, other_id)| other_id == id) {
+            return Err(format!("{} and {} canister IDs are both {}", name, other, id.to_text()));
+        }
+    }
+    Ok(())
 }
 
-pub fn get_dip721_canister_id() -> Principal {
-    DIP721_CANISTER_ID.with(|id| id.borrow().expect("DIP721_CANISTER_ID must be set"))
+// Helper function to store the canister IDs of the dependencies
+fn store_dependency_canisters(args: InitArgs) -> Result<(), GeohashError> {
+    ic_cdk::println!("Setting dependency canisters: {:?}", args);
+    set_dip721_canister_id(Some(args.dip721_canister_id))?;
+    set_bitcoin_canister_id(Some(args.bitcoin_canister_id))?;
+    set_ethereum_canister_id(Some(args.ethereum_canister_id))
 }
 
-pub fn set_bitcoin_canister_id(bitcoin_canister_id: Option<Principal>) {
-    BASIC_BITCOIN_CANISTER_ID.with(|id| *id.borrow_mut() = bitcoin_canister_id);
+// Helper function to restrict an endpoint to the controllers of the canister
+fn require_controller() -> Result<(), GeohashError> {
+    if ic_cdk::api::is_controller(&ic_cdk::api::caller()) {
+        Ok(())
+    } else {
+        Err(GeohashError::Unauthorized)
+    }
 }
 
-pub fn get_bitcoin_canister_id() -> Principal {
-    BASIC_BITCOIN_CANISTER_ID.with(|id| id.borrow().expect("Bitcoin canister ID must be set"))
+// Helper functions to get and set state
+pub fn set_dip721_canister_id(dip721_canister_id: Option<Principal>) -> Result<(), GeohashError> {
+    STATE.with(|state| state.borrow_mut().update_config(|config| config.dip721_canister_id = dip721_canister_id))
 }
 
-pub fn set_ethereum_canister_id(ethereum_canister_id: Option<Principal>) {
-    BASIC_ETHEREUM_CANISTER_ID.with(|id| *id.borrow_mut() = ethereum_canister_id);
+pub fn get_dip721_canister_id() -> Result<Principal, GeohashError> {
+    STATE.with(|state| state.borrow().config().dip721_canister_id).ok_or_else(|| GeohashError::NotConfigured(DIP721_CANISTER.to_string()))
 }
 
-pub fn get_ethereum_canister_id() -> Principal {
-    BASIC_ETHEREUM_CANISTER_ID.with(|id| id.borrow().expect("Ethereum canister ID must be set"))
+pub fn set_bitcoin_canister_id(bitcoin_canister_id: Option<Principal>) -> Result<(), GeohashError> {
+    STATE.with(|state| state.borrow_mut().update_config(|config| config.bitcoin_canister_id = bitcoin_canister_id))
 }
 
-pub fn update_geohash_to_token_id(geohash: String, token_id: u64) {
-    GEOHASH_TO_TOKEN_ID.with(|map| map.borrow_mut().insert(geohash, token_id));
+pub fn get_bitcoin_canister_id() -> Result<Principal, GeohashError> {
+    STATE.with(|state| state.borrow().config().bitcoin_canister_id).ok_or_else(|| GeohashError::NotConfigured(BITCOIN_CANISTER.to_string()))
+}
+
+pub fn set_ethereum_canister_id(ethereum_canister_id: Option<Principal>) -> Result<(), GeohashError> {
+    STATE.with(|state| state.borrow_mut().update_config(|config| config.ethereum_canister_id = ethereum_canister_id))
+}
+
+pub fn get_ethereum_canister_id() -> Result<Principal, GeohashError> {
+    STATE.with(|state| state.borrow().config().ethereum_canister_id).ok_or_else(|| GeohashError::NotConfigured(ETHEREUM_CANISTER.to_string()))
 }
 
 pub fn get_token_id_by_geohash(geohash: &str) -> Option<u64> {
-    GEOHASH_TO_TOKEN_ID.with(|map| map.borrow().get(geohash).cloned())
+    STATE.with(|state| state.borrow().token_ids.get(&geohash.to_string()))
 }
 
-pub fn pre_upgrade() {
-    let dip721_id = DIP721_CANISTER_ID.with(|id| id.borrow().clone());
-    let bitcoin_canister_id = BASIC_BITCOIN_CANISTER_ID.with(|id| id.borrow().clone());
-    let geohash_to_token_id: Vec<(String, u64)> = GEOHASH_TO_TOKEN_ID.with(|map| map.borrow().clone().into_iter().collect());
-    let ethereum_canister_id = BASIC_ETHEREUM_CANISTER_ID.with(|id| id.borrow().clone());
-    ic_cdk::storage::stable_save((dip721_id, bitcoin_canister_id, ethereum_canister_id, geohash_to_token_id)).expect("Failed to save to stable storage");
-    //ic_cdk::storage::stable_save((dip721_id, bitcoin_canister_id, geohash_to_token_id)).expect("Failed to save to stable storage");
+pub fn update_square_record(geohash: String, record: SquareRecord) {
+    STATE.with(|state| state.borrow_mut().square_records.insert(geohash, Cbor(record)));
 }
 
-pub fn post_upgrade() {
-    let (dip721_id, bitcoin_canister_id, ethereum_canister_id, geohash_to_token_id): (Option<Principal>, Option<Principal>, Option<Principal>, Vec<(String, u64)>) = ic_cdk::storage::stable_restore().expect("Failed to restore from stable storage");
-    //let (dip721_id, bitcoin_canister_id, geohash_to_token_id): (Option<Principal>, Option<Principal>, Vec<(String, u64)>) = ic_cdk::storage::stable_restore().expect("Failed to restore from stable storage");
-    DIP721_CANISTER_ID.with(|id| *id.borrow_mut() = dip721_id);
-    BASIC_BITCOIN_CANISTER_ID.with(|id| *id.borrow_mut() = bitcoin_canister_id);
-    BASIC_ETHEREUM_CANISTER_ID.with(|id| *id.borrow_mut() = ethereum_canister_id);
-    GEOHASH_TO_TOKEN_ID.with(|map| *map.borrow_mut() = geohash_to_token_id.into_iter().collect());
-    ic_cdk::println!("Post-upgrade DIP721_CANISTER_ID: {:?}", dip721_id);
-    ic_cdk::println!("Post-upgrade BASIC_BITCOIN_CANISTER_ID: {:?}", bitcoin_canister_id);
+pub fn get_square_record(geohash: &str) -> Option<SquareRecord> {
+    STATE.with(|state| state.borrow().square_records.get(&geohash.to_string()).map(|record| record.0))
 }
 
+pub fn get_metric_definition(name: &str) -> Option<MetricDefinition> {
+    STATE.with(|state| state.borrow().metric_definitions.get(&name.to_string()).map(|definition| definition.0))
+}
 
+// Function to validate a value against the registry and append it to the observations of a square
+pub fn record_metric_value(geohash: &str, metric: &str, value: MetricValue, observed_at: u64, submitter: Principal) -> Result<MetricObservation, MetricError> {
+    let definition = get_metric_definition(metric).ok_or_else(|| MetricError::UnknownMetric(metric.to_string()))?;
+    validate_value(&definition, &value)?;
 
-// Helper function to generate a unique IPNS ID based on the geohash
-fn generate_ipns_id(geohash: &str) -> String {
-    let mut hasher = Sha256::new();
-    hasher.update(geohash);
-    let result = hasher.finalize();
-    let ipns_id = format!("ipns-{}", result.to_base58());
-    ipns_id
+    let observation = MetricObservation { metric: definition.name, value, observed_at, submitter };
+    STATE.with(|state| state.borrow_mut().record_observation(geohash, observation.clone()));
+    Ok(observation)
 }
 
+// Function to check and store a batch of observations submitted by an oracle at a time; every observation is audited,
+// accepted or not. Fails as a whole if the caller is not an oracle, the batch is too large or over the rate limit.
+pub fn submit_oracle_batch(caller: Principal, batch: Vec<OracleObservation>, now: u64) -> Result<Vec<Result<MetricObservation, MetricError>>, MetricError> {
+    if batch.len() > MAX_ORACLE_BATCH as usize {
+        return Err(MetricError::TooManyInputs(MAX_ORACLE_BATCH));
+    }
+    let mut state = STATE.with(|state| state.borrow().oracles.get(&caller)).ok_or(MetricError::Unauthorized)?.0;
+    state.window.consume(state.oracle.max_observations_per_hour, batch.len() as u32, now)?;
+    STATE.with(|stable| stable.borrow_mut().oracles.insert(caller, Cbor(state)));
+
+    let mut changed_squares = BTreeSet::new();
+    let results = batch
+        .into_iter()
+        .map(|submitted| {
+            let square = resolve_square(&submitted.square).map(|(geohash, _)| geohash);
+            let result = square.clone().map_err(MetricError::InvalidSquare).and_then(|geohash| {
+                validate_source_reference(&submitted.source_reference)?;
+                validate_timestamp(submitted.observed_at, now)?;
+                record_metric_value(&geohash, &submitted.metric, submitted.value.clone(), submitted.observed_at, caller)
+            });
+            if let (Ok(_), Ok(geohash)) = (&result, &square) {
+                changed_squares.insert(geohash.clone());
+            }
+
+            STATE.with(|state| {
+                let mut state = state.borrow_mut();
+                let sequence = state.audit_log.len();
+                state.audit_log.insert(sequence, Cbor(AuditEntry {
+                    sequence,
+                    oracle: caller,
+                    received_at: now,
+                    square: square.ok(),
+                    metric: submitted.metric,
+                    value: submitted.value,
+                    observed_at: submitted.observed_at,
+                    source_reference: submitted.source_reference,
+                    rejection: result.clone().err(),
+                }));
+            });
+            result
+        })
+        .collect();
+
+    // Publish every changed square once for the whole batch
+    for geohash in changed_squares {
+        publish_square_document(&geohash, now);
+    }
+    Ok(results)
+}
 
-// Function to print all IPNS names and their corresponding metrics
-fn print_ipns_data() {
-    IPNS_DATA.with(|ipns_data| {
-        let ipns_data = ipns_data.borrow();
-        for (ipns_id, metrics) in ipns_data.iter() {
-            ic_cdk::println!("XXXXXXXXXXXXXXXXXXXXMOCKED DATA FOR EACH IPNS Name: {}", ipns_id);
-            for (metric_name, value) in metrics {
-                ic_cdk::println!("  {}: {}", metric_name, value);
+// Function to store a contribution of a user to a square; it stays pending until other users decide it
+pub fn submit_user_contribution(caller: Principal, input: &SquareInput, metric: &str, value: MetricValue, description: String, now: u64) -> Result<Contribution, ContributionError> {
+    require_authenticated(&caller)?;
+    validate_description(&description)?;
+    let (geohash, _) = resolve_square(input).map_err(|err| ContributionError::InvalidObservation(MetricError::InvalidSquare(err)))?;
+    let definition = get_metric_definition(metric).ok_or_else(|| ContributionError::InvalidObservation(MetricError::UnknownMetric(metric.to_string())))?;
+    validate_value(&definition, &value).map_err(ContributionError::InvalidObservation)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let contribution = Contribution {
+            id: state.contributions.len(),
+            geohash,
+            metric: definition.name,
+            value,
+            description,
+            contributor: caller,
+            submitted_at: now,
+            corroborations: vec![],
+            disputes: vec![],
+            status: ContributionStatus::Pending,
+            decided_at: None,
+        };
+        state.contributions.insert(contribution.id, Cbor(contribution.clone()));
+        state.square_contributions.insert(square_contribution_key(&contribution.geohash, contribution.id), contribution.id);
+
+        let mut reputation = state.reputation(&caller);
+        reputation.contributions += 1;
+        state.reputations.insert(caller, Cbor(reputation));
+        state.owner_activity.insert(caller, now);
+        Ok(contribution)
+    })
+}
+
+// Function to count the vote of a user on a contribution. Once the contribution is decided the users involved are
+// credited, and an accepted contribution is stored as an observation of the square at the time it was submitted.
+pub fn vote_on_contribution(caller: Principal, id: u64, vote: Vote, now: u64) -> Result<Contribution, ContributionError> {
+    require_authenticated(&caller)?;
+    let (mut contribution, policy, mut voter) = STATE.with(|state| {
+        let state = state.borrow();
+        let contribution = state.contributions.get(&id).ok_or(ContributionError::UnknownContribution(id))?.0;
+        Ok((contribution, state.contribution_policy(), state.reputation(&caller)))
+    })?;
+    if voter.score < policy.min_voter_reputation {
+        return Err(ContributionError::InsufficientReputation(voter.score));
+    }
+    apply_vote(&mut contribution, caller, vote, &policy, now)?;
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        voter.votes += 1;
+        state.reputations.insert(caller, Cbor(voter));
+        state.owner_activity.insert(caller, now);
+        for reputation in settle_reputations(&contribution, |principal| state.reputation(principal)) {
+            state.reputations.insert(reputation.principal, Cbor(reputation));
+        }
+        state.contributions.insert(id, Cbor(contribution.clone()));
+    });
+
+    if contribution.status == ContributionStatus::Accepted {
+        // The definition of the metric may have changed since the contribution was submitted
+        match record_metric_value(&contribution.geohash, &contribution.metric, contribution.value.clone(), contribution.submitted_at, contribution.contributor) {
+            Ok(_) => {
+                publish_square_document(&contribution.geohash, now);
             }
+            Err(err) => ic_cdk::println!("Failed to store accepted contribution {}: {:?}", id, err),
         }
+    }
+    Ok(contribution)
+}
+
+// Function to store the rating of a minted square by a signed-in user, replacing their previous rating
+pub fn rate_minted_square(caller: Principal, geohash: &str, rating: u32, now: u64) -> Result<RatingSummary, GeohashError> {
+    if caller == Principal::anonymous() {
+        return Err(GeohashError::AnonymousCaller);
+    }
+    if !(MIN_RATING..=MAX_RATING).contains(&rating) {
+        return Err(GeohashError::InvalidInput(format!("Rating must be between {} and {}", MIN_RATING, MAX_RATING)));
+    }
+    lookup_square_index(geohash)?;
+    if get_square_record(geohash).is_none() {
+        return Err(GeohashError::NotMinted(geohash.to_string()));
+    }
+    let summary = STATE.with(|state| state.borrow_mut().rate_square(geohash, &caller, rating as u8));
+    record_activity(caller, now);
+    publish_square_document(geohash, now);
+    Ok(summary)
+}
+
+// Helper function to (re)start the timer of the refresh job
+fn schedule_refresh_job(interval_seconds: u64) {
+    let timer = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval_seconds), || {
+        ic_cdk::spawn(async {
+            if let Err(err @ GeohashError::StorageFailed(_)) = run_refresh_batch().await {
+                ic_cdk::println!("GEOHASH_LIB.RS_Failed to record the refresh batch: {:?}", err);
+            }
+        });
     });
+    if let Some(previous) = REFRESH_TIMER.with(|current| current.borrow_mut().replace(timer)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
 }
 
+// Helper function to get the minted squares the next batch refreshes: the squares after the one the last batch stopped
+// at, starting over at the first square once it reached the last one
+fn next_minted_squares(after: Option<&String>, limit: usize) -> Vec<String> {
+    STATE.with(|state| {
+        let state = state.borrow();
+        let mut squares: Vec<String> = match after {
+            Some(after) => state
+                .square_records
+                .range(after.clone()..)
+                .map(|(geohash, _)| geohash)
+                .filter(|geohash| geohash != after)
+                .take(limit)
+                .collect(),
+            None => vec![],
+        };
+        if squares.is_empty() {
+            squares = state.square_records.iter().map(|(geohash, _)| geohash).take(limit).collect();
+        }
+        squares
+    })
+}
 
-// Function to mint NFT or get existing NFT for a given geohash
-async fn get_or_mint_nft_square(nearest_geohash: &String) -> (Option<Nft>, u64, u64, Option<HashMap<String, u32>>, bool) {
+// Function to pull a feed for a square and store the mapped fields as observations of the square.
+// Returns the number of stored observations; the result is also kept in the status of the feed.
+async fn pull_feed(config: &FeedConfig, geohash: &str) -> Result<u32, MetricError> {
+    let index = lookup_square_index(geohash).map_err(MetricError::InvalidSquare)?;
+    let (lat, lon) = square_center(index);
+
+    let mut stored = 0;
+    let mut last_error = None;
+    match fetch_feed_fields(config, lat, lon).await {
+        Ok(fields) => {
+            for mapping in &config.fields {
+                let value = fields.get(&mapping.path).and_then(|value| {
+                    get_metric_definition(&mapping.metric).and_then(|definition| json_to_metric_value(value, definition.value_type))
+                });
+                let result = match value {
+                    Some(value) => record_metric_value(geohash, &mapping.metric, value, ic_cdk::api::time(), ic_cdk::api::id()),
+                    None => Err(MetricError::FetchFailed(format!("No {} value at {}", mapping.metric, mapping.path))),
+                };
+                match result {
+                    Ok(_) => stored += 1,
+                    Err(err) => last_error = Some(err),
+                }
+            }
+        }
+        Err(err) => last_error = Some(err),
+    }
 
-    let bitcoin_canister_id = get_bitcoin_canister_id();
-    //let ethereum_canister_id = get_ethereum_canister_id();
+    // The feed may have been removed or changed while the request was running
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(mut current) = state.feeds.get(&config.name).map(|current| current.0) {
+            current.last_run = Some(ic_cdk::api::time());
+            current.last_error = last_error.as_ref().map(|err| format!("{}: {:?}", geohash, err));
+            current.stored_observations += stored as u64;
+            state.feeds.insert(config.name.clone(), Cbor(current));
+        }
+    });
+    match last_error {
+        Some(err) => Err(err),
+        None => Ok(stored),
+    }
+}
+
+// Function to refresh a minted square: cache its balances and pull every feed for it.
+// Returns the errors of the parts that failed; the other parts are refreshed anyway.
+async fn refresh_square(geohash: &str) -> Vec<String> {
+    let Some(record) = get_square_record(geohash) else {
+        return vec!["Square is not minted".to_string()];
+    };
+    let mut errors = vec![];
+
+    // The cached balances stay as they are if the Bitcoin canister cannot be reached
+    let balance = match get_bitcoin_canister_id() {
+        Ok(bitcoin_canister_id) => get_bitcoin_balance(bitcoin_canister_id, record.bitcoin_address).await,
+        Err(err) => Err(err),
+    };
+    match balance {
+        Ok(bitcoin_balance) => {
+            let balances = SquareBalances {
+                bitcoin_balance,
+                // placeholder (for this mvp) for ethereum address balance
+                ethereum_balance: 0,
+                refreshed_at: ic_cdk::api::time(),
+            };
+            STATE.with(|state| state.borrow_mut().square_balances.insert(geohash.to_string(), Cbor(balances)));
+        }
+        Err(err) => errors.push(format!("{:?}", err)),
+    }
+
+    let feeds: Vec<FeedConfig> = STATE.with(|state| state.borrow().feeds.iter().map(|(_, feed)| feed.0.config).collect());
+    for feed in feeds {
+        if let Err(err) = pull_feed(&feed, geohash).await {
+            errors.push(format!("Feed {}: {:?}", feed.name, err));
+        }
+    }
+
+    // Publish the new metrics of the square
+    publish_square_document(geohash, ic_cdk::api::time());
+    errors
+}
+
+// Guard marking a batch of the refresh job as running, so timer ticks do not start overlapping batches while the
+// outcalls of a slow batch are still awaited; dropping it (also on a trap) ends the batch
+struct RefreshGuard;
+
+impl RefreshGuard {
+    fn acquire() -> Option<RefreshGuard> {
+        REFRESH_RUNNING.with(|running| (!running.replace(true)).then_some(RefreshGuard))
+    }
+}
+
+impl Drop for RefreshGuard {
+    fn drop(&mut self) {
+        REFRESH_RUNNING.with(|running| *running.borrow_mut() = false);
+    }
+}
+
+// Function to refresh the next batch of minted squares and record the run in the status of the job.
+// Returns the status; fails if a batch is already running or the status cannot be stored.
+async fn run_refresh_batch() -> Result<RefreshJobStatus, GeohashError> {
+    let _guard = RefreshGuard::acquire().ok_or(GeohashError::RefreshRunning)?;
+    let status = STATE.with(|state| state.borrow().refresh_job());
+    let squares = next_minted_squares(status.next_square.as_ref(), status.batch_size as usize);
+
+    let mut failures = vec![];
+    for geohash in &squares {
+        let errors = refresh_square(geohash).await;
+        if !errors.is_empty() {
+            ic_cdk::println!("Refreshing square {} failed: {:?}", geohash, errors);
+            failures.push(RefreshFailure { geohash: geohash.clone(), error: errors.join("; "), failed_at: ic_cdk::api::time() });
+        }
+    }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.update_refresh_job(|status| record_batch(status, squares.len() as u32, failures, squares.last().cloned(), ic_cdk::api::time()))?;
+        Ok(state.refresh_job())
+    })
+}
+
+// Helper function to get the balances of a square cached by the refresh job
+fn cached_balances(geohash: &str) -> Option<SquareBalances> {
+    STATE.with(|state| state.borrow().square_balances.get(&geohash.to_string())).map(|balances| balances.0)
+}
+
+// Helper function to get the latest value of every metric of a square, None if nothing was observed yet
+fn latest_metric_values(geohash: &str) -> Option<HashMap<String, MetricValue>> {
+    let latest = STATE.with(|state| state.borrow().latest_observations(geohash));
+    if latest.is_empty() {
+        return None;
+    }
+    Some(latest.into_iter().map(|observation| (observation.metric, observation.value)).collect())
+}
+
+// Helper function to publish a new version of the document of a square after its data changed (nothing happens for
+// squares that are not minted)
+fn publish_square_document(geohash: &str, now: u64) -> Option<DocumentPointer> {
+    STATE.with(|state| state.borrow_mut().publish_document(geohash, now))
+}
+
+
+// Guard holding the lock on the mint of a square across the calls to other canisters, so two callers cannot both pass
+// the check that a square is unminted and mint it twice; dropping it (also on a trap) releases the lock
+#[derive(Debug)]
+pub struct MintGuard(String);
+
+impl MintGuard {
+    fn acquire(geohash: &str) -> Option<MintGuard> {
+        MINTS_IN_FLIGHT.with(|mints| mints.borrow_mut().insert(geohash.to_string()).then(|| MintGuard(geohash.to_string())))
+    }
+}
+
+impl Drop for MintGuard {
+    fn drop(&mut self) {
+        MINTS_IN_FLIGHT.with(|mints| mints.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Function to reserve the mint of an unminted square for a caller before any call to another canister, if the claim
+/// policy allows the caller to claim it from the location they submitted.
+/// The pending mint is stored, so an interrupted mint is finished later with the same idempotency key; only its owner can
+/// resume it by claiming the square again (controllers finish all of them with reconcile_mints).
+pub fn reserve_mint(caller: Principal, geohash: &str, location: Option<Geolocation>, now: u64) -> Result<(MintGuard, PendingMint), GeohashError> {
+    if let Some(token_id) = get_token_id_by_geohash(geohash) {
+        return Err(GeohashError::AlreadyClaimed { geohash: geohash.to_string(), token_id });
+    }
+    let guard = MintGuard::acquire(geohash).ok_or_else(|| GeohashError::MintInProgress(geohash.to_string()))?;
+
+    // Squares whose mint was interrupted stay reserved for the caller who started it
+    let pending = match STATE.with(|state| state.borrow().pending_mints.get(&geohash.to_string())) {
+        Some(pending) if pending.0.owner != caller => {
+            return Err(GeohashError::MintInProgress(geohash.to_string()));
+        }
+        Some(pending) => PendingMint { attempts: pending.0.attempts + 1, ..pending.0 },
+        None => {
+            // New claims count towards the claims of the caller, the time they are made is the claim time
+            let claims = check_new_claim(caller, geohash, location)?;
+            STATE.with(|state| state.borrow_mut().claim_counts.insert(caller, claims + 1));
+            PendingMint {
+                geohash: geohash.to_string(),
+                owner: caller,
+                idempotency_key: mint_idempotency_key(geohash),
+                started_at: now,
+                attempts: 1,
+                last_error: None,
+                state: ProvisioningState::Reserved,
+                next_retry_at: None,
+            }
+        }
+    };
+    STATE.with(|state| state.borrow_mut().pending_mints.insert(geohash.to_string(), Cbor(pending.clone())));
+    record_activity(caller, now);
+    Ok((guard, pending))
+}
+
+// Helper function to check a new claim of a square against the claim policy; returns the number of squares the caller
+// claimed so far
+fn check_new_claim(caller: Principal, geohash: &str, location: Option<Geolocation>) -> Result<u32, GeohashError> {
+    let located_in_square = match location {
+        Some(location) => Some(resolve_square(&SquareInput::Coordinate(location))?.0 == geohash),
+        None => None,
+    };
+    let (claims, policy) = STATE.with(|state| {
+        let state = state.borrow();
+        (state.claims_of(&caller), state.claim_policy())
+    });
+    check_claim(&caller, geohash, claims, located_in_square, &policy)?;
+    Ok(claims)
+}
+
+/// Function to reserve the claim of a square whose NFT reverted to the canister, which transfers the NFT instead of
+/// minting one. The claim policy applies as to new squares, and the claim counts before the transfer so concurrent
+/// claims cannot exceed the limit (release it with release_claim if the transfer fails). Returns None if the square did
+/// not revert.
+pub fn reserve_reclaim(caller: Principal, geohash: &str, location: Option<Geolocation>, canister_id: Principal) -> Result<Option<(MintGuard, SquareRecord)>, GeohashError> {
+    let Some(record) = get_square_record(geohash).filter(|record| record.owner == canister_id) else {
+        return Ok(None);
+    };
+    let guard = MintGuard::acquire(geohash).ok_or_else(|| GeohashError::MintInProgress(geohash.to_string()))?;
+    let claims = check_new_claim(caller, geohash, location)?;
+    STATE.with(|state| state.borrow_mut().claim_counts.insert(caller, claims + 1));
+    Ok(Some((guard, record)))
+}
+
+// Helper function to keep when a principal was last active, owners inactive for too long can lose their squares
+fn record_activity(principal: Principal, now: u64) {
+    STATE.with(|state| state.borrow_mut().owner_activity.insert(principal, now));
+}
+
+// Helper function to keep why the latest attempt of a pending mint failed and schedule its retry
+fn record_mint_failure(geohash: &str, error: String, now: u64) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(mut pending) = state.pending_mints.get(&geohash.to_string()) {
+            record_failure(&mut pending.0, error, now);
+            state.pending_mints.insert(geohash.to_string(), pending);
+        }
+    });
+}
+
+// Helper function to store the state a pending mint reached
+fn store_pending_mint(pending: &PendingMint) {
+    STATE.with(|state| state.borrow_mut().pending_mints.insert(pending.geohash.clone(), Cbor(pending.clone())));
+}
+
+// Helper function to (re)start the timer retrying failed and interrupted mints
+fn schedule_provisioning_retries() {
+    let timer = ic_cdk_timers::set_timer_interval(Duration::from_secs(RETRY_INTERVAL_SECONDS), || {
+        ic_cdk::spawn(async {
+            retry_pending_mints(ic_cdk::api::time(), RETRY_BATCH_SIZE, true).await;
+        });
+    });
+    if let Some(previous) = PROVISIONING_TIMER.with(|current| current.borrow_mut().replace(timer)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+// Helper function to retry pending mints for their owners, only those whose retry is due if due_only is set; mints
+// still in flight finish by themselves, failures are kept in the pending mints and mints that failed too often before
+// minting are dropped
+async fn retry_pending_mints(now: u64, limit: usize, due_only: bool) {
+    let pending_mints: Vec<PendingMint> = STATE.with(|state| {
+        state
+            .borrow()
+            .pending_mints
+            .iter()
+            .map(|(_, pending)| pending.0)
+            .filter(|pending| !due_only || due_for_retry(pending, now))
+            .take(limit)
+            .collect()
+    });
+    for pending in pending_mints {
+        let Some(_guard) = MintGuard::acquire(&pending.geohash) else {
+            continue;
+        };
+        if should_abandon(&pending) {
+            ic_cdk::println!("GEOHASH_LIB.RS_Giving up the mint of square {:?} after {} attempts", pending.geohash, pending.attempts);
+            STATE.with(|state| state.borrow_mut().cancel_mint(&pending.geohash));
+            continue;
+        }
+        let pending = PendingMint { attempts: pending.attempts + 1, ..pending };
+        store_pending_mint(&pending);
+        let _ = mint_nft_square(&pending.geohash, &pending).await;
+    }
+}
+
+// Helper function to (re)start the timer evaluating the owned squares against the reversion policy
+fn schedule_reversion_job() {
+    let timer = ic_cdk_timers::set_timer_interval(Duration::from_secs(EVALUATION_TICK_SECONDS), || {
+        ic_cdk::spawn(async {
+            if let Err(err) = run_reversions(ic_cdk::api::id(), ic_cdk::api::time(), true).await {
+                ic_cdk::println!("GEOHASH_LIB.RS_Failed to evaluate the owned squares: {:?}", err);
+            }
+        });
+    });
+    if let Some(previous) = REVERSION_TIMER.with(|current| current.borrow_mut().replace(timer)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+/// Function to evaluate the next batch of minted squares against the reversion policy, continuing after the square the
+/// last batch stopped at. Squares owned by a user are evaluated once per evaluation interval, or every time unless
+/// due_only is set; the evaluations are stored and the squares that became or stopped being at risk are logged.
+/// Donations are measured by the Bitcoin balances cached by the refresh job. Returns the squares that were at risk for
+/// the whole grace period; fails if the position of the batch cannot be stored.
+pub fn evaluate_squares(canister_id: Principal, now: u64, limit: usize, due_only: bool) -> Result<Vec<(String, SquareRecord)>, GeohashError> {
+    let cursor = STATE.with(|state| state.borrow().reversion_cursor());
+    let squares = next_minted_squares(cursor.as_ref(), limit);
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.set_reversion_cursor(squares.last().cloned())?;
+        let policy = state.reversion_policy();
+
+        let mut due = vec![];
+        for geohash in squares {
+            let Some(record) = state.square_records.get(&geohash).map(|record| record.0).filter(|record| record.owner != canister_id) else {
+                continue;
+            };
+            let previous = state.square_reversions.get(&geohash).map(|reversion| reversion.0);
+            if due_only && !due_for_evaluation(previous.as_ref(), record.owner, &policy, now) {
+                continue;
+            }
+            let balance = state.square_balances.get(&geohash).map(|balances| balances.0.bitcoin_balance);
+            let donations = donations_since(previous.as_ref(), record.owner, balance);
+            let last_active_at = state.owner_activity.get(&record.owner).unwrap_or(0).max(record.claimed_at);
+            let reasons = risk_reasons(&policy, &state.latest_observations(&geohash), donations, last_active_at, now);
+
+            let (reversion, event) = next_reversion(previous, &geohash, record.owner, balance, reasons, now);
+            if let Some(kind) = event {
+                state.log_reversion_event(&geohash, record.owner, kind, now);
+            }
+            if due_for_reversion(&reversion, &policy, now) {
+                due.push((geohash.clone(), record));
+            }
+            state.square_reversions.insert(geohash, Cbor(reversion));
+        }
+        Ok(due)
+    })
+}
+
+// Function to evaluate the next batch of squares and transfer the NFTs of the squares at risk for the whole grace period
+// back to the canister; failed transfers are logged and tried again at the next evaluation
+async fn run_reversions(canister_id: Principal, now: u64, due_only: bool) -> Result<(), GeohashError> {
+    for (geohash, record) in evaluate_squares(canister_id, now, EVALUATION_BATCH_SIZE, due_only)? {
+        // Squares being claimed are left for the next evaluation, as are squares whose owner changed meanwhile
+        let Some(_guard) = MintGuard::acquire(&geohash) else {
+            continue;
+        };
+        if get_square_record(&geohash).map(|current| current.owner) != Some(record.owner) {
+            continue;
+        }
+
+        // Owners can transfer their NFTs outside the canister; the record follows the DIP721 canister instead of
+        // failing to revert the square at every evaluation
+        match get_nft_owner(record.token_id).await {
+            Ok(owner) if owner != record.owner => {
+                ic_cdk::println!("GEOHASH_LIB.RS_Square {:?} was transferred to {:?} outside the canister", geohash, owner);
+                STATE.with(|state| state.borrow_mut().resync_owner(&geohash, owner, canister_id, ic_cdk::api::time()));
+                continue;
+            }
+            Ok(_) => {}
+            Err(err) => {
+                ic_cdk::println!("GEOHASH_LIB.RS_Failed to get the owner of square {:?}: {:?}", geohash, err);
+                let kind = ReversionEventKind::RevertFailed(format!("{:?}", err));
+                STATE.with(|state| state.borrow_mut().log_reversion_event(&geohash, record.owner, kind, ic_cdk::api::time()));
+                continue;
+            }
+        }
+
+        match transfer_nft(record.owner, canister_id, record.token_id).await {
+            Ok(transaction_id) => {
+                ic_cdk::println!("GEOHASH_LIB.RS_Square {:?} reverted to the canister", geohash);
+                STATE.with(|state| state.borrow_mut().revert_square(&geohash, canister_id, transaction_id, ic_cdk::api::time()));
+            }
+            Err(err) => {
+                ic_cdk::println!("GEOHASH_LIB.RS_Failed to revert square {:?}: {:?}", geohash, err);
+                let kind = ReversionEventKind::RevertFailed(format!("{:?}", err));
+                STATE.with(|state| state.borrow_mut().log_reversion_event(&geohash, record.owner, kind, ic_cdk::api::time()));
+            }
+        }
+    }
+    Ok(())
+}
+
+
+// NFT of a square with its Bitcoin and Ethereum balances and whether it was just minted
+type SquareData = (Option<Nft>, u64, u64, bool);
+
+// Function to get the existing NFT for a given geohash with its balances; unminted squares are not minted.
+// Fails if the DIP721 or Bitcoin canister cannot be reached.
+async fn get_nft_square(nearest_geohash: &String) -> Result<SquareData, GeohashError> {
 
     // placeholder (for this mvp) for ethereum address balance
     let ethereum_balance = 0;
 
+    if get_token_id_by_geohash(nearest_geohash).is_none() {
+        ic_cdk::println!("GEOHASH_LIB.RS_Square not minted yet: {:?}", nearest_geohash);
+        return Ok((None, 0, ethereum_balance, false));
+    }
 
-    match get_token_id_by_geohash(nearest_geohash) {
-        Some(token_id) => {
-            // Token ID exists, fetch the NFT information
-            match get_nft_by_geohash(nearest_geohash.clone()).await {
-                Ok(nft) => {
-                    // Print statement to log the NFT data
-                    ic_cdk::println!("GEOHASH_LIB.RS_Existing NFT data: {:?}", nft);
-
-                    // START retrieving addresses / ids from NFT metadata to then query real time metrics
-
-                    // Extract the Bitcoin address from the NFT metadata
-                    let bitcoin_address = nft.metadata.iter().find_map(|metadata| {
-                        metadata.key_val_data.iter().find_map(|kv| {
-                            if kv.key == "bitcoin_address" {
-                                if let MetadataVal::TextContent(address) = &kv.val {
-                                    Some(address.clone())
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        })
-                    });
-                    
-                    // Extract the IPNS name from the NFT metadata
-                    let ipns_name = nft.metadata.iter().find_map(|metadata| {
-                        metadata.key_val_data.iter().find_map(|kv| {
-                            if kv.key == "ipns_id" {
-                                if let MetadataVal::TextContent(id) = &kv.val {
-                                    Some(id.clone())
-                                } else {
-                                    None
-                                }
-                            } else {
-                                None
-                            }
-                        })
-                    });
-
-                    // START MOCKED REAL TIME METRICS OF SQUARES
-
-                    // Query the Bitcoin balance if the address was found
-                    let bitcoin_balance = if let Some(address) = bitcoin_address {
-                        get_bitcoin_balance(bitcoin_canister_id, address).await.unwrap_or_else(|err| {
-                            ic_cdk::println!("Failed to get Bitcoin balance: {:?}", err);
-                            0 // Default to 0 if balance retrieval fails
-                        })
-                    } else {
-                        ic_cdk::println!("Bitcoin address not found in NFT metadata");
-                        0
-                    };
-                    
-                    // Query the metrics from the IPNS data if the IPNS name was found
-                    let real_time_metrics = if let Some(ipns_name) = ipns_name {
-                        IPNS_DATA.with(|ipns_data| {
-                            let ipns_data = ipns_data.borrow();
-                            ipns_data.get(&ipns_name).cloned()
-                        })
+    // Token ID exists, fetch the NFT information
+    match get_nft_by_geohash(nearest_geohash.clone()).await {
+        Ok(nft) => {
+            // Print statement to log the NFT data
+            ic_cdk::println!("GEOHASH_LIB.RS_Existing NFT data: {:?}", nft);
+
+            // Serve the balances cached by the refresh job, they are at most one refresh interval old
+            if let Some(balances) = cached_balances(nearest_geohash) {
+                return Ok((Some(nft), balances.bitcoin_balance, balances.ethereum_balance, false));
+            }
+
+            // START retrieving addresses / ids from NFT metadata to then query balances
+
+            // Extract the Bitcoin address from the NFT metadata
+            let bitcoin_address = nft.metadata.iter().find_map(|metadata| {
+                metadata.key_val_data.iter().find_map(|kv| {
+                    if kv.key == "bitcoin_address" {
+                        if let MetadataVal::TextContent(address) = &kv.val {
+                            Some(address.clone())
+                        } else {
+                            None
+                        }
                     } else {
-                        ic_cdk::println!("IPNS name not found in NFT metadata");
                         None
-                    };
-
-                    if let Some(metrics) = &real_time_metrics {
-                        ic_cdk::println!("Real-time metrics: {:?}", metrics);
                     }
+                })
+            });
 
-                    // END MOCKED REAL TIME METRICS OF SQUARES
+            // Query the Bitcoin balance if the address was found, caching it until the refresh job comes by
+            let bitcoin_balance = if let Some(address) = bitcoin_address {
+                let balance = get_bitcoin_balance(get_bitcoin_canister_id()?, address).await?;
+                let balances = SquareBalances { bitcoin_balance: balance, ethereum_balance, refreshed_at: ic_cdk::api::time() };
+                STATE.with(|state| state.borrow_mut().square_balances.insert(nearest_geohash.clone(), Cbor(balances)));
+                balance
+            } else {
+                ic_cdk::println!("Bitcoin address not found in NFT metadata");
+                0
+            };
 
-                    (Some(nft), bitcoin_balance, ethereum_balance, real_time_metrics, false)
-                },
-                Err(err) => {
-                    ic_cdk::println!("GEOHASH_LIB.RS_Failed to get NFT by geohash: {:?}", err);
-                    (None, 0, ethereum_balance, None, false)
-                }
-            }
+            Ok((Some(nft), bitcoin_balance, ethereum_balance, false))
         },
-        None => {
-            // Token ID does not exist, mint a new NFT
-            ic_cdk::println!("GEOHASH_LIB.RS_New square detected: {:?}", nearest_geohash);
-
-
-            // START MOCKED REAL TIME METRICS OF SQUARES
-            // Generate mocked IPNS ID (to be implemented: create real ID, INPS then maps to the changing IPFS CIDs for the data of each square)
-            let ipns_id = generate_ipns_id(nearest_geohash);  // we pass in the geohash to generate the ipns_id          
-            
-            let mut rng = 0;
-            let air_quality_index = 50;
-            let crime_rate = 50;
-            let car_accident_rate = 50;;
-
-            IPNS_DATA.with(|ipns_data| {
-                let mut ipns_data = ipns_data.borrow_mut();
-                ipns_data.insert(ipns_id.clone(), {
-                    let mut metrics = HashMap::new();
-                    metrics.insert("Rating".to_string(), 0);
-                    metrics.insert("Air quality index".to_string(), air_quality_index);
-                    metrics.insert("Crime rate".to_string(), crime_rate);
-                    metrics.insert("Car accident rate".to_string(), car_accident_rate);
-                    metrics
-                });
-            });
+        Err(err) => {
+            ic_cdk::println!("GEOHASH_LIB.RS_Failed to get NFT by geohash: {:?}", err);
+            Err(err)
+        }
+    }
+}
 
-            // Print the IPNS data after insertion
-            ic_cdk::println!("IPNS data after insertion:");
-            print_ipns_data();
-            // END MOCKED REAL TIME METRICS OF SQUARES
-            
-            
-            // Get the Bitcoin address
-            ic_cdk::println!("Retrieving Bitcoin canister ID before calling get_bitcoin_canister_id()");
-            //let bitcoin_canister_id = get_bitcoin_canister_id();
-            ic_cdk::println!("Retrieved Bitcoin canister ID: {:?}", bitcoin_canister_id);
-            
-            let bitcoin_address = get_bitcoin_address(bitcoin_canister_id, nearest_geohash.clone()).await.expect("Failed to get Bitcoin address");
-            //let bitcoin_address = get_bitcoin_address(bitcoin_canister_id).await.expect("Failed to get Bitcoin address");
-            ic_cdk::println!("Retrieved Bitcoin address: {:?}", bitcoin_address);
-
-            // Get the Bitcoin balance
-
-            let bitcoin_balance = get_bitcoin_balance(bitcoin_canister_id, bitcoin_address.clone()).await.unwrap_or_else(|err| {
-                ic_cdk::println!("Failed to get Bitcoin balance: {:?}", err);
-                0 // Default to 0 if balance retrieval fails
-            });
-            ic_cdk::println!("Retrieved Bitcoin balance: {:?}", bitcoin_balance);
 
+// Function to mint the NFT for a given geohash, owned by the owner of the pending mint (hold its MintGuard while awaiting).
+// A failed attempt is kept in the pending mint and retried by the timer.
+async fn mint_nft_square(nearest_geohash: &String, pending: &PendingMint) -> Result<SquareData, GeohashError> {
+    let result = mint_pending_square(nearest_geohash, pending).await;
+    if let Err(err) = &result {
+        // The square stays reserved for the owner until a retry succeeds
+        ic_cdk::println!("GEOHASH_LIB.RS_Failed to mint NFT: {:?}", err);
+        record_mint_failure(nearest_geohash, format!("{:?}", err), ic_cdk::api::time());
+    }
+    result
+}
 
-            // Get the Ethereum address
-            let ethereum_canister_id = get_ethereum_canister_id();
-            let ethereum_address = get_ethereum_address(ethereum_canister_id, nearest_geohash.clone()).await.expect("Failed to get Ethereum address");
-            
-            let wallet = Wallet {
-                ether: ethereum_address,
-                bitcoin: bitcoin_address,
-            };
+// Helper function to make one attempt at a pending mint, resuming at the step it got to
+async fn mint_pending_square(nearest_geohash: &String, pending: &PendingMint) -> Result<SquareData, GeohashError> {
 
-            let properties = SquareProperties {
-                geohash: nearest_geohash.clone(),
-                metadata: ipns_id.clone(),
-                //metadata: "".to_string(), 
-                wallet,
-            };
+    // placeholder (for this mvp) for ethereum address balance
+    let ethereum_balance = 0;
 
+    ic_cdk::println!("GEOHASH_LIB.RS_Provisioning square {:?} from {:?}", nearest_geohash, pending.state.stage());
 
-            // Get the principal of the caller
-            let caller = ic_cdk::api::caller();
-
-            // Empty content for the blob (no additional data)
-            let blob_content = vec![];
-
-            // Create metadata containing only the geohash
-            let metadata = create_metadata(properties.clone());
-
-            // Attempt to mint the NFT
-            match mint_nft(caller, properties, blob_content).await {
-                Ok((txid, token_id)) => {
-                    // Fetch the newly minted NFT
-                    match get_nft_by_geohash(nearest_geohash.clone()).await {
-                        Ok(nft) => {
-                            ic_cdk::println!("GEOHASH_LIB.RS_NFT minted successfully with nft: {:?}", nft);
-                            
-                            (Some(nft), bitcoin_balance, ethereum_balance, Some(IPNS_DATA.with(|ipns_data| {
-                                let ipns_data = ipns_data.borrow();
-                                ipns_data.get(&ipns_id).cloned()
-                            }).unwrap()), true)
-                        },
-                        Err(err) => {
-                            ic_cdk::println!("GEOHASH_LIB.RS_Failed to get NFT by geohash after minting: {:?}", err);
-                            (None, bitcoin_balance, ethereum_balance, None, false)
-                        }
-                    }
-                },
-                Err(err) => {
-                    // Handle error minting NFT
-                    ic_cdk::println!("GEOHASH_LIB.RS_Failed to mint NFT: {:?}", err);
-                    (None, bitcoin_balance, ethereum_balance, None, false)
-                },
+    let record = provision_square(pending.clone()).await?;
+
+    // Get the Bitcoin balance; the square is minted either way
+    let bitcoin_balance = match get_bitcoin_canister_id() {
+        Ok(bitcoin_canister_id) => get_bitcoin_balance(bitcoin_canister_id, record.bitcoin_address.clone()).await.unwrap_or_else(|err| {
+            ic_cdk::println!("Failed to get Bitcoin balance: {:?}", err);
+            0 // Default to 0 if balance retrieval fails
+        }),
+        Err(_) => 0,
+    };
+    ic_cdk::println!("Retrieved Bitcoin balance: {:?}", bitcoin_balance);
+
+    // Fetch the newly minted NFT; the square is minted even if the DIP721 canister cannot return it right now
+    match get_nft_by_geohash(nearest_geohash.clone()).await {
+        Ok(nft) => {
+            ic_cdk::println!("GEOHASH_LIB.RS_NFT minted successfully with nft: {:?}", nft);
+            Ok((Some(nft), bitcoin_balance, ethereum_balance, true))
+        },
+        Err(err) => {
+            ic_cdk::println!("GEOHASH_LIB.RS_Failed to get NFT by geohash after minting: {:?}", err);
+            Ok((None, bitcoin_balance, ethereum_balance, true))
+        }
+    }
+}
+
+// Helper function to run the remaining steps of provisioning a square, storing the state after every step so a failed
+// step is retried without repeating the ones before it. Indexing the token ends the pending mint.
+async fn provision_square(mut pending: PendingMint) -> Result<SquareRecord, GeohashError> {
+    let geohash = pending.geohash.clone();
+    loop {
+        pending.state = match pending.state.clone() {
+            ProvisioningState::Reserved => {
+                let bitcoin_address = get_bitcoin_address(get_bitcoin_canister_id()?, geohash.clone()).await?;
+                ic_cdk::println!("Retrieved Bitcoin address: {:?}", bitcoin_address);
+                ProvisioningState::BitcoinAddress { bitcoin_address }
+            }
+            ProvisioningState::BitcoinAddress { bitcoin_address } => {
+                let ethereum_address = get_ethereum_address(get_ethereum_canister_id()?, geohash.clone()).await?;
+                ic_cdk::println!("Retrieved Ethereum address: {:?}", ethereum_address);
+                ProvisioningState::EthereumAddress { bitcoin_address, ethereum_address }
             }
+            ProvisioningState::EthereumAddress { bitcoin_address, ethereum_address } => {
+                // The metadata holds the name pointing to the latest document of the square, published once the NFT is minted
+                let properties = SquareProperties {
+                    geohash: geohash.clone(),
+                    metadata: document_name(&geohash),
+                    wallet: Wallet { ether: ethereum_address, bitcoin: bitcoin_address },
+                };
+                ProvisioningState::Metadata { properties }
+            }
+            ProvisioningState::Metadata { properties } => {
+                // The square goes to whoever started the mint, also when it is retried; a retry gets the NFT minted by
+                // an earlier attempt
+                let (txid, token_id) = mint_nft(pending.owner, properties.clone(), vec![], pending.idempotency_key.clone()).await?;
+                ic_cdk::println!("GEOHASH_LIB.RS_Minted token {:?} in transaction {:?}", token_id, txid);
+                ProvisioningState::Minted { properties, token_id }
+            }
+            ProvisioningState::Minted { properties, token_id } => {
+                // Map the square to its token and cache its record so it can be looked up from query calls
+                let record = SquareRecord {
+                    token_id,
+                    owner: pending.owner,
+                    bitcoin_address: properties.wallet.bitcoin,
+                    ethereum_address: properties.wallet.ether,
+                    document_name: properties.metadata,
+                    minted_at: ic_cdk::api::time(),
+                    claimed_at: pending.started_at,
+                };
+                STATE.with(|state| state.borrow_mut().finish_mint(&geohash, record.clone()));
+                return Ok(record);
+            }
+        };
+        store_pending_mint(&pending);
+    }
+}
+
+
+// Helper function to describe a square of the grid
+fn square_info(index: SquareIndex) -> SquareInfo {
+    SquareInfo {
+        geohash: square_geohash(index),
+        resolution: index.resolution,
+        bounds: square_bounds(index),
+    }
+}
+
+// Helper function to find the square for a canonical square geohash
+fn lookup_square_index(geohash: &str) -> Result<SquareIndex, GridError> {
+    if decode_geohash(geohash).is_err() {
+        return Err(GridError::InvalidGeohash(geohash.to_string()));
+    }
+    square_from_geohash(geohash).ok_or_else(|| GridError::NotASquare(geohash.to_string()))
+}
+
+
+// Helper function to resolve any square input to the square (geohash and bounds) at the resolution of the NFTs
+fn resolve_square(input: &SquareInput) -> Result<(String, Area), GridError> {
+    let index = match input {
+        SquareInput::Coordinate(geolocation) => {
+            validate_coordinate(geolocation.latitude, geolocation.longitude)?;
+            return Ok(find_nearest_geohash_with_bounds(geolocation.latitude, geolocation.longitude, DEFAULT_RESOLUTION));
         }
+        SquareInput::Geohash(geohash) => {
+            let coord = decode_geohash(geohash).map_err(|_| GridError::InvalidGeohash(geohash.clone()))?;
+            return Ok(find_nearest_geohash_with_bounds(coord.y, coord.x, DEFAULT_RESOLUTION));
+        }
+        SquareInput::SquareId(geohash) => lookup_square_index(geohash)?,
+        SquareInput::Code(code) => square_from_code(code).ok_or_else(|| GridError::InvalidCode(code.clone()))?,
+    };
+
+    // Square ids and codes name a square directly, which has to be one of the squares minted as NFTs
+    let geohash = square_geohash(index);
+    if index.resolution != DEFAULT_RESOLUTION {
+        return Err(GridError::NotASquare(geohash));
+    }
+    Ok((geohash, square_bounds(index)))
+}
+
+// Helper function to build the response for a square from its NFT, balances and metrics
+fn area_response(geohash: String, bounds: Area, square: SquareData) -> AreaResponse {
+    let (nft_square, bitcoin_balance, ethereum_balance, created) = square;
+    AreaResponse {
+        real_time_metrics: latest_metric_values(&geohash),
+        rating: STATE.with(|state| state.borrow().rating_summary(&geohash)),
+        lat_start: bounds.lat_start,
+        lon_start: bounds.lon_start,
+        lat_end: bounds.lat_end,
+        lon_end: bounds.lon_end,
+        geohash,
+        nft_square,
+        bitcoin_balance,
+        ethereum_balance,
+        created,
+    }
+}
+
+// Function to resolve a square input and get the NFT of the square if it was minted (use claim_square to mint it)
+async fn compute_square_response(input: SquareInput) -> Result<AreaResponse, GeohashError> {
+    let (nearest_geohash, bounds) = resolve_square(&input)?;
+
+    let square = get_nft_square(&nearest_geohash).await?;
+    let response = area_response(nearest_geohash, bounds, square);
+
+    // Log the response
+    ic_cdk::println!("GEOHASH_LIB:RS_COMPUTE_SQUARE_Response: {:?}", response);
+
+    Ok(response)
+}
+
+// Helper function to describe a square with what is known about it locally, without calling other canisters
+fn square_lookup(geohash: String, bounds: Area) -> SquareLookup {
+    SquareLookup {
+        real_time_metrics: latest_metric_values(&geohash),
+        rating: STATE.with(|state| state.borrow().rating_summary(&geohash)),
+        status: get_square_record(&geohash).map_or(SquareStatus::Unminted, SquareStatus::Minted),
+        balances: cached_balances(&geohash),
+        document: get_square_record(&geohash).and_then(|record| STATE.with(|state| state.borrow().document_names.get(&record.document_name))).map(|pointer| pointer.0),
+        geohash,
+        bounds,
+    }
+}
+
+// Helper function to turn a page of covering squares into the response, flagging minted squares
+fn coverage_page(scan: CoverageScan) -> CoveragePage {
+    CoveragePage {
+        squares: scan
+            .squares
+            .into_iter()
+            .map(|index| {
+                let geohash = square_geohash(index);
+                CoveredSquare {
+                    minted: get_token_id_by_geohash(&geohash).is_some(),
+                    geohash,
+                    bounds: square_bounds(index),
+                }
+            })
+            .collect(),
+        next_cursor: scan.next_cursor,
+        total_candidates: scan.total_candidates,
     }
 }
 
@@ -406,106 +1139,627 @@ async fn get_or_mint_nft_square(nearest_geohash: &String) -> (Option<Nft>, u64,
 
 // Define an update function to compute the area and geohash for a given geolocation
 #[update]
-async fn compute_geohash(geolocation: Geolocation) -> String {
-    // Calculate the grid and match the geolocation to the nearest grid square
-    let (nearest_geohash, bounds) = find_nearest_geohash_with_bounds(geolocation.latitude, geolocation.longitude);
-
-    // Helper function to get or mint the NFT square
-    let (nft_square, bitcoin_balance, ethereum_balance, real_time_metrics, created) = get_or_mint_nft_square(&nearest_geohash).await;
-
-    // Simplified logging
-    ic_cdk::println!("GEOHASH_LIB:RS_COMPUTE_GEOHASH_NFT_SQUARE: {:?}, CREATED: {:?}", nft_square, created);
-
-    // Create the response as a JSON object
-    let response = json!({
-        "lat_start": bounds.lat_start,
-        "lon_start": bounds.lon_start,
-        "lat_end": bounds.lat_end,
-        "lon_end": bounds.lon_end,
-        "geohash": nearest_geohash,
-        "nft_square": nft_square.map(|nft| {
-            json!({
-                "owner": nft.owner.to_text(),
-                "token_id": nft.token_id,
-                "metadata": nft.metadata, // Assuming metadata is serializable to JSON
-                "content": nft.content,
-            })
-        }),
-        "bitcoin_balance": bitcoin_balance,
-        "ethereum_balance": ethereum_balance,
-        "real_time_metrics": real_time_metrics,
-        "created": created,
-    });
+async fn compute_geohash(geolocation: Geolocation) -> Result<AreaResponse, GeohashError> {
+    compute_square_response(SquareInput::Coordinate(geolocation)).await
+}
 
-    // Log the response
-    ic_cdk::println!("GEOHASH_LIB:RS_COMPUTE_GEOHASH_AreaResponse: {:?}", response);
+// Define an update function to compute the area for a given geohash
+#[update]
+async fn compute_area(geohash: String) -> Result<AreaResponse, GeohashError> {
+    compute_square_response(SquareInput::Geohash(geohash)).await
+}
 
-    // Return the response as a JSON string
-    response.to_string()
+// Define an update function to compute the area for any kind of square input
+#[update]
+async fn compute_square(input: SquareInput) -> Result<AreaResponse, GeohashError> {
+    compute_square_response(input).await
 }
 
+// Define an update function to compute the areas for many square inputs in one call (at most 20 inputs)
+#[update]
+async fn compute_squares(inputs: Vec<SquareInput>) -> Result<Vec<Result<AreaResponse, GeohashError>>, GeohashError> {
+    if inputs.len() > MAX_BATCH_INPUTS as usize {
+        return Err(GeohashError::TooManyInputs(MAX_BATCH_INPUTS));
+    }
 
+    let mut responses = Vec::with_capacity(inputs.len());
+    for input in inputs {
+        responses.push(compute_square_response(input).await);
+    }
+    Ok(responses)
+}
 
-// Define an update function to compute the area for a given geohash
+// Define an update function to mint the NFT for the square containing a geohash, owned by the caller; the location of
+// the caller is checked if the claim policy requires it
 #[update]
-async fn compute_area(geohash: String) -> String {
-    // Decode the geohash back into coordinates
-    let coord = decode_geohash(&geohash).unwrap();
-
-    // Calculate the grid and match the coordinates to the nearest grid square
-    let (nearest_geohash, bounds) = find_nearest_geohash_with_bounds(coord.y, coord.x);
-
-    // Helper function to get or mint the NFT square
-    let (nft_square, bitcoin_balance, ethereum_balance, real_time_metrics, created) = get_or_mint_nft_square(&nearest_geohash).await;
-
-    ic_cdk::println!("GEOHASH_LIB:RS_COMPUTE_AREA_NFT_SQUARE: {:?}, CREATED: {:?}", nft_square, created);
-
-    // Create the response as a JSON object
-    let response = json!({
-        "lat_start": bounds.lat_start,
-        "lon_start": bounds.lon_start,
-        "lat_end": bounds.lat_end,
-        "lon_end": bounds.lon_end,
-        "geohash": nearest_geohash,
-        "nft_square": nft_square.map(|nft| {
-            json!({
-                "owner": nft.owner.to_text(),
-                "token_id": nft.token_id,
-                "metadata": nft.metadata, // Assuming metadata is serializable to JSON
-                "content": nft.content,
-            })
-        }),
-        "bitcoin_balance": bitcoin_balance,
-        "ethereum_balance": ethereum_balance,
-        "real_time_metrics": real_time_metrics,
-        "created": created,
+async fn claim_square(geohash: String, location: Option<Geolocation>) -> Result<AreaResponse, GeohashError> {
+    let (nearest_geohash, bounds) = resolve_square(&SquareInput::Geohash(geohash))?;
+    let caller = ic_cdk::api::caller();
+
+    // Squares that reverted to the canister go to the next claimer with the NFT they already have
+    if let Some((_guard, record)) = reserve_reclaim(caller, &nearest_geohash, location.clone(), ic_cdk::api::id())? {
+        let transaction_id = transfer_nft(ic_cdk::api::id(), caller, record.token_id).await.inspect_err(|_| {
+            STATE.with(|state| state.borrow_mut().release_claim(&caller));
+        })?;
+        STATE.with(|state| state.borrow_mut().reclaim_square(&nearest_geohash, caller, transaction_id, ic_cdk::api::time()));
+
+        // The square belongs to the caller now, even if the DIP721 or Bitcoin canister cannot return it right now
+        let square = get_nft_square(&nearest_geohash).await.unwrap_or_else(|err| {
+            ic_cdk::println!("GEOHASH_LIB.RS_Failed to get NFT by geohash after reclaiming: {:?}", err);
+            let balances = cached_balances(&nearest_geohash);
+            (None, balances.as_ref().map_or(0, |balances| balances.bitcoin_balance), balances.map_or(0, |balances| balances.ethereum_balance), false)
+        });
+        return Ok(area_response(nearest_geohash, bounds, square));
+    }
+
+    // Lock the square until the mint is done
+    let (_guard, pending) = reserve_mint(caller, &nearest_geohash, location, ic_cdk::api::time())?;
+
+    // Helper function to mint the NFT square
+    let square = mint_nft_square(&nearest_geohash, &pending).await?;
+
+    ic_cdk::println!("GEOHASH_LIB:RS_CLAIM_SQUARE_NFT_SQUARE: {:?}, CREATED: {:?}", square.0, square.3);
+
+    Ok(area_response(nearest_geohash, bounds, square))
+}
+
+// Query function to get the rules of claiming squares
+#[query]
+fn get_claim_policy() -> ClaimPolicy {
+    STATE.with(|state| state.borrow().claim_policy())
+}
+
+// Update function for controllers to change the rules of claiming squares; squares claimed before keep their owners
+#[update]
+fn set_claim_policy(policy: ClaimPolicy) -> Result<(), GeohashError> {
+    require_controller()?;
+    validate_claim_policy(&policy)?;
+    STATE.with(|state| state.borrow_mut().set_claim_policy(policy))
+}
+
+// Query function to get the rules reverting the NFTs of inactive squares
+#[query]
+fn get_reversion_policy() -> ReversionPolicy {
+    STATE.with(|state| state.borrow().reversion_policy())
+}
+
+// Update function for controllers to change the rules reverting the NFTs of inactive squares; squares already at risk
+// are evaluated against the new rules at their next evaluation
+#[update]
+fn set_reversion_policy(policy: ReversionPolicy) -> Result<(), GeohashError> {
+    require_controller()?;
+    validate_reversion_policy(&policy, get_metric_definition)?;
+    STATE.with(|state| state.borrow_mut().set_reversion_policy(policy))
+}
+
+// Query function to list the squares at risk of reverting, with since when and why
+#[query]
+fn squares_at_risk() -> Vec<SquareReversion> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .square_reversions
+            .iter()
+            .map(|(_, reversion)| reversion.0)
+            .filter(|reversion| reversion.at_risk_since.is_some())
+            .collect()
+    })
+}
+
+// Query function to read the log of squares that became at risk, recovered, reverted or were claimed again, oldest first,
+// starting at a sequence number (default 100, at most 1000 events)
+#[query]
+fn reversion_events(start: Option<u64>, limit: Option<u32>) -> Vec<ReversionEvent> {
+    let limit = limit.unwrap_or(DEFAULT_EVENT_LIMIT).clamp(1, MAX_EVENT_LIMIT) as usize;
+    STATE.with(|state| state.borrow().reversion_events.range(start.unwrap_or(0)..).take(limit).map(|(_, event)| event.0).collect())
+}
+
+// Update function for controllers to evaluate the next batch of squares immediately, also those evaluated within the
+// evaluation interval, e.g. to test a policy; returns the squares still at risk
+#[update]
+async fn run_reversion_now() -> Result<Vec<SquareReversion>, GeohashError> {
+    require_controller()?;
+    run_reversions(ic_cdk::api::id(), ic_cdk::api::time(), false).await?;
+    Ok(squares_at_risk())
+}
+
+// Update function for controllers to finish the mints that were interrupted (e.g. by a trap after the DIP721 canister
+// minted the NFT) for their owners without waiting for the timer; returns the mints that are still pending
+#[update]
+async fn reconcile_mints() -> Result<Vec<PendingMint>, GeohashError> {
+    require_controller()?;
+    retry_pending_mints(ic_cdk::api::time(), usize::MAX, false).await;
+    Ok(STATE.with(|state| state.borrow().pending_mints.iter().map(|(_, pending)| pending.0).collect()))
+}
+
+// Update function for controllers to give up a stuck mint, releasing the square and the claim of its owner
+#[update]
+fn cancel_mint(geohash: String) -> Result<PendingMint, GeohashError> {
+    require_controller()?;
+    cancel_pending_mint(&geohash)
+}
+
+/// Function to drop a pending mint that is not in flight and stop counting it towards the claims of its owner. Mints
+/// whose NFT was minted are finished by reconcile_mints instead. A mint stuck at the Metadata stage may have been minted
+/// by the DIP721 canister without an answer; the next claim of the square would get that NFT with the same idempotency
+/// key, so check the DIP721 canister before cancelling it.
+pub fn cancel_pending_mint(geohash: &str) -> Result<PendingMint, GeohashError> {
+    let _guard = MintGuard::acquire(geohash).ok_or_else(|| GeohashError::MintInProgress(geohash.to_string()))?;
+    let pending = STATE.with(|state| state.borrow().pending_mints.get(&geohash.to_string()))
+        .ok_or_else(|| GeohashError::NotFound(geohash.to_string()))?
+        .0;
+    if pending.state.stage() == ProvisioningStage::Minted {
+        return Err(GeohashError::InvalidInput(format!("The NFT of square {} is minted, finish it with reconcile_mints", geohash)));
+    }
+    STATE.with(|state| state.borrow_mut().cancel_mint(geohash));
+    Ok(pending)
+}
+
+// Query function for controllers to list the mints that were started but not finished
+#[query]
+fn pending_mints() -> Result<Vec<PendingMint>, GeohashError> {
+    require_controller()?;
+    Ok(STATE.with(|state| state.borrow().pending_mints.iter().map(|(_, pending)| pending.0).collect()))
+}
+
+// Query function for controllers to list the squares stuck in each state of provisioning, leaving out the mints in flight
+#[query]
+fn stuck_squares() -> Result<Vec<StuckSquares>, GeohashError> {
+    require_controller()?;
+    Ok(group_by_stage(stuck_pending_mints()))
+}
+
+// Helper function to get the pending mints that are not in flight
+pub fn stuck_pending_mints() -> Vec<PendingMint> {
+    let in_flight = MINTS_IN_FLIGHT.with(|mints| mints.borrow().clone());
+    STATE.with(|state| {
+        state
+            .borrow()
+            .pending_mints
+            .iter()
+            .map(|(_, pending)| pending.0)
+            .filter(|pending| !in_flight.contains(&pending.geohash))
+            .collect()
+    })
+}
+
+// Query function to look up the square containing a geolocation without minting it
+#[query]
+fn lookup_geohash(geolocation: Geolocation) -> Result<SquareLookup, GeohashError> {
+    lookup_square(SquareInput::Coordinate(geolocation))
+}
+
+// Query function to look up the square containing a geohash without minting it
+#[query]
+fn lookup_area(geohash: String) -> Result<SquareLookup, GeohashError> {
+    lookup_square(SquareInput::Geohash(geohash))
+}
+
+// Query function to look up the square for any kind of square input without minting it
+#[query]
+fn lookup_square(input: SquareInput) -> Result<SquareLookup, GeohashError> {
+    let (geohash, bounds) = resolve_square(&input)?;
+    Ok(square_lookup(geohash, bounds))
+}
+
+// Query function to look up the squares for many square inputs in one call without minting them (at most 20 inputs)
+#[query]
+fn lookup_squares(inputs: Vec<SquareInput>) -> Result<Vec<Result<SquareLookup, GeohashError>>, GeohashError> {
+    if inputs.len() > MAX_BATCH_INPUTS as usize {
+        return Err(GeohashError::TooManyInputs(MAX_BATCH_INPUTS));
+    }
+    Ok(inputs.into_iter().map(lookup_square).collect())
+}
+
+// Query function to get the three-word code of a square at any resolution
+#[query]
+fn code_of(geohash: String) -> Result<String, GeohashError> {
+    Ok(square_code(lookup_square_index(&geohash)?))
+}
+
+// Update function for signed-in users to rate a minted square from 1 to 10; every user has one rating per square,
+// rating again replaces it. Returns the ratings of the square.
+#[update]
+fn update_rating(geohash: String, rating: u32) -> Result<RatingSummary, GeohashError> {
+    rate_minted_square(ic_cdk::api::caller(), &geohash, rating, ic_cdk::api::time())
+}
+
+// Update function for controllers to add a metric to the registry or update its unit, range and source
+#[update]
+fn register_metric(definition: MetricDefinition) -> Result<(), GeohashError> {
+    require_controller()?;
+    validate_definition(&definition)?;
+
+    // Stored observations have to stay valid, so the type of a metric is fixed once it is registered
+    if let Some(existing) = get_metric_definition(&definition.name) {
+        if existing.value_type != definition.value_type {
+            return Err(MetricError::InvalidDefinition("The value type of a registered metric cannot change".to_string()).into());
+        }
+    }
+    STATE.with(|state| state.borrow_mut().metric_definitions.insert(definition.name.clone(), Cbor(definition)));
+    Ok(())
+}
+
+// Query function to list the registered metrics
+#[query]
+fn list_metrics() -> Vec<MetricDefinition> {
+    STATE.with(|state| state.borrow().metric_definitions.iter().map(|(_, definition)| definition.0).collect())
+}
+
+// Update function for controllers to record the current value of a metric for a square
+#[update]
+fn record_metric(input: SquareInput, metric: String, value: MetricValue) -> Result<MetricObservation, GeohashError> {
+    require_controller()?;
+    let (geohash, _) = resolve_square(&input)?;
+    let observation = record_metric_value(&geohash, &metric, value, ic_cdk::api::time(), ic_cdk::api::caller())?;
+    publish_square_document(&geohash, ic_cdk::api::time());
+    Ok(observation)
+}
+
+// Update function for controllers to register an oracle or change its name and rate limit
+#[update]
+fn register_oracle(principal: Principal, name: String, max_observations_per_hour: u32) -> Result<(), GeohashError> {
+    require_controller()?;
+    if principal == Principal::anonymous() || name.trim().is_empty() || max_observations_per_hour == 0 {
+        return Err(MetricError::InvalidDefinition("An oracle needs a principal, a name and a rate limit".to_string()).into());
+    }
+
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        let existing = state.oracles.get(&principal).map(|existing| existing.0);
+        let oracle = Oracle {
+            principal,
+            name,
+            max_observations_per_hour,
+            registered_at: existing.as_ref().map_or_else(ic_cdk::api::time, |existing| existing.oracle.registered_at),
+        };
+        let window = existing.map(|existing| existing.window).unwrap_or_default();
+        state.oracles.insert(principal, Cbor(OracleState { oracle, window }));
     });
+    Ok(())
+}
 
-    // Log the response
-    ic_cdk::println!("GEOHASH_LIB:RS_COMPUTE_AREA_Response: {:?}", response);
+// Update function for controllers to remove an oracle; its observations and audit entries are kept
+#[update]
+fn remove_oracle(principal: Principal) -> Result<(), GeohashError> {
+    require_controller()?;
+    STATE.with(|state| state.borrow_mut().oracles.remove(&principal));
+    Ok(())
+}
+
+// Query function to list the registered oracles
+#[query]
+fn list_oracles() -> Vec<Oracle> {
+    STATE.with(|state| state.borrow().oracles.iter().map(|(_, state)| state.0.oracle).collect())
+}
+
+// Update function for oracles to submit a batch of observations (at most 100); each observation gets its own result
+#[update]
+fn submit_observations(batch: Vec<OracleObservation>) -> Result<Vec<Result<MetricObservation, GeohashError>>, GeohashError> {
+    let results = submit_oracle_batch(ic_cdk::api::caller(), batch, ic_cdk::api::time())?;
+    Ok(results.into_iter().map(|result| result.map_err(GeohashError::from)).collect())
+}
+
+// Query function to read the audit log of the observations submitted by oracles, oldest first, starting at a sequence
+// number (default 100, at most 1000 entries)
+#[query]
+fn oracle_audit_log(start: Option<u64>, limit: Option<u32>) -> Vec<AuditEntry> {
+    let limit = limit.unwrap_or(DEFAULT_AUDIT_LIMIT).clamp(1, MAX_AUDIT_LIMIT) as usize;
+    STATE.with(|state| state.borrow().audit_log.range(start.unwrap_or(0)..).take(limit).map(|(_, entry)| entry.0).collect())
+}
+
+// Update function for signed-in users to log a fact about a square as an observation of a metric, with a description
+#[update]
+fn submit_contribution(input: SquareInput, metric: String, value: MetricValue, description: String) -> Result<Contribution, GeohashError> {
+    Ok(submit_user_contribution(ic_cdk::api::caller(), &input, &metric, value, description, ic_cdk::api::time())?)
+}
+
+// Update function for signed-in users to corroborate the contribution of another user
+#[update]
+fn corroborate_contribution(id: u64) -> Result<Contribution, GeohashError> {
+    Ok(vote_on_contribution(ic_cdk::api::caller(), id, Vote::Corroborate, ic_cdk::api::time())?)
+}
+
+// Update function for signed-in users to dispute the contribution of another user
+#[update]
+fn dispute_contribution(id: u64) -> Result<Contribution, GeohashError> {
+    Ok(vote_on_contribution(ic_cdk::api::caller(), id, Vote::Dispute, ic_cdk::api::time())?)
+}
+
+// Query function to get a contribution by its ID
+#[query]
+fn get_contribution(id: u64) -> Result<Contribution, GeohashError> {
+    STATE.with(|state| state.borrow().contributions.get(&id)).map(|contribution| contribution.0).ok_or_else(|| ContributionError::UnknownContribution(id).into())
+}
+
+// Query function to list the contributions to a square, oldest first, optionally only those with a status, starting at
+// a contribution ID (default 100, at most 1000 contributions)
+#[query]
+fn square_contributions(input: SquareInput, status: Option<ContributionStatus>, start: Option<u64>, limit: Option<u32>) -> Result<Vec<Contribution>, GeohashError> {
+    let (geohash, _) = resolve_square(&input)?;
+    let limit = limit.unwrap_or(DEFAULT_CONTRIBUTION_LIMIT).clamp(1, MAX_CONTRIBUTION_LIMIT) as usize;
+    let prefix = format!("{}/", geohash);
+
+    Ok(STATE.with(|state| {
+        let state = state.borrow();
+        state
+            .square_contributions
+            .range(square_contribution_key(&geohash, start.unwrap_or(0))..)
+            .take_while(|(key, _)| key.starts_with(&prefix))
+            .filter_map(|(_, id)| state.contributions.get(&id).map(|contribution| contribution.0))
+            .filter(|contribution| status.is_none_or(|status| contribution.status == status))
+            .take(limit)
+            .collect()
+    }))
+}
+
+// Query function to get the reputation of a user
+#[query]
+fn reputation_of(principal: Principal) -> Reputation {
+    STATE.with(|state| state.borrow().reputation(&principal))
+}
+
+// Query function to get the votes deciding contributions and the reputation needed to vote
+#[query]
+fn get_contribution_policy() -> ContributionPolicy {
+    STATE.with(|state| state.borrow().contribution_policy())
+}
+
+// Update function for controllers to change the votes deciding contributions; decided contributions stay decided
+#[update]
+fn set_contribution_policy(policy: ContributionPolicy) -> Result<(), GeohashError> {
+    require_controller()?;
+    validate_policy(&policy)?;
+    STATE.with(|state| state.borrow_mut().set_contribution_policy(policy))
+}
+
+// Update function for controllers to add a feed, or replace the feed with the same name; the refresh job pulls it
+#[update]
+fn add_feed(config: FeedConfig) -> Result<(), GeohashError> {
+    require_controller()?;
+    validate_feed(&config)?;
+    if let Some(mapping) = config.fields.iter().find(|mapping| get_metric_definition(&mapping.metric).is_none()) {
+        return Err(MetricError::UnknownMetric(mapping.metric.clone()).into());
+    }
+
+    let previous = STATE.with(|state| state.borrow().feeds.get(&config.name)).map(|previous| previous.0);
+    let status = FeedStatus {
+        config: config.clone(),
+        last_run: previous.as_ref().and_then(|previous| previous.last_run),
+        last_error: None,
+        stored_observations: previous.map_or(0, |previous| previous.stored_observations),
+    };
+    STATE.with(|state| state.borrow_mut().feeds.insert(config.name.clone(), Cbor(status)));
+    Ok(())
+}
+
+// Update function for controllers to stop pulling a feed and remove it; stored observations are kept
+#[update]
+fn remove_feed(name: String) -> Result<(), GeohashError> {
+    require_controller()?;
+    STATE.with(|state| state.borrow_mut().feeds.remove(&name)).ok_or(MetricError::UnknownFeed(name))?;
+    Ok(())
+}
+
+// Query function to list the feeds with the state of their runs
+#[query]
+fn list_feeds() -> Vec<FeedStatus> {
+    STATE.with(|state| state.borrow().feeds.iter().map(|(_, feed)| feed.0).collect())
+}
+
+// Update function for controllers to change how often the refresh job runs and how many squares a batch refreshes
+#[update]
+fn configure_refresh(interval_seconds: u64, batch_size: u32) -> Result<(), GeohashError> {
+    require_controller()?;
+    validate_refresh_settings(interval_seconds, batch_size).map_err(GeohashError::InvalidInput)?;
+    STATE.with(|state| {
+        state.borrow_mut().update_refresh_job(|status| {
+            status.interval_seconds = interval_seconds;
+            status.batch_size = batch_size;
+        })
+    })?;
+    schedule_refresh_job(interval_seconds);
+    Ok(())
+}
 
-    // Return the response as a JSON string
-    response.to_string()
+// Query function to get the status of the refresh job: last run, squares processed and failures
+#[query]
+fn refresh_status() -> RefreshJobStatus {
+    STATE.with(|state| state.borrow().refresh_job())
 }
 
-// Simple update function to update the rating of an square (this is just a demonstrator, in a real-world scenario, this would be more complex)
+// Update function for controllers to refresh the next batch now instead of waiting for the timer, e.g. to test a feed
 #[update]
-async fn update_rating(ipns_name: String, rating: u32) -> Result<(), String> {
-    if rating < 1 || rating > 10 {
-        return Err("Rating must be between 1 and 10".to_string());
-    }
-
-    IPNS_DATA.with(|ipns_data| {
-        let mut ipns_data = ipns_data.borrow_mut();
-        if let Some(metrics) = ipns_data.get_mut(&ipns_name) {
-            metrics.insert("Rating".to_string(), rating);
-            Ok(())
-        } else {
-            Err("IPNS name not found".to_string())
+async fn run_refresh_now() -> Result<RefreshJobStatus, GeohashError> {
+    require_controller()?;
+    run_refresh_batch().await
+}
+
+// Query function the replicas call to transform the responses of feeds (see feed.rs)
+#[query]
+fn transform_feed_response(args: TransformArgs) -> HttpResponse {
+    transform_response(args.response, &args.context)
+}
+
+// Query function to resolve the name of a square document (e.g. "geohash:u281z7j5e3gr", as in the NFT metadata) to
+// its latest version
+#[query]
+fn resolve_document_name(name: String) -> Result<DocumentPointer, GeohashError> {
+    STATE.with(|state| state.borrow().document_names.get(&name)).map(|pointer| pointer.0).ok_or(GeohashError::NotFound(name))
+}
+
+// Query function to get any version of a square document by its CID
+#[query]
+fn get_document(cid: String) -> Result<SquareDocument, GeohashError> {
+    STATE.with(|state| state.borrow().documents.get(&cid)).map(|document| document.0).ok_or(GeohashError::NotFound(cid))
+}
+
+// Query function to get a square document as its DAG-CBOR block, whose SHA-256 hash is part of the CID, so clients
+// can verify the document or pin it on IPFS
+#[query]
+fn get_document_block(cid: String) -> Result<Vec<u8>, GeohashError> {
+    STATE.with(|state| state.borrow().documents.get(&cid)).map(|document| encode_document(&document.0)).ok_or(GeohashError::NotFound(cid))
+}
+
+// Query function to list the versions of a square document, newest first, following the links to previous versions
+// starting at a version (default the latest one; default 100, at most 1000 versions)
+#[query]
+fn document_history(name: String, start: Option<String>, limit: Option<u32>) -> Result<Vec<DocumentPointer>, GeohashError> {
+    let limit = limit.unwrap_or(DEFAULT_DOCUMENT_HISTORY_LIMIT).clamp(1, MAX_DOCUMENT_HISTORY_LIMIT) as usize;
+    if start.as_deref().is_some_and(|cid| !is_document_cid(cid)) {
+        return Err(GeohashError::InvalidInput("Not the CID of a document".to_string()));
+    }
+
+    STATE.with(|state| {
+        let state = state.borrow();
+        let head = state.document_names.get(&name).ok_or_else(|| GeohashError::NotFound(name.clone()))?.0;
+        let mut next = Some(start.unwrap_or(head.cid));
+        let mut versions = vec![];
+        while let Some(cid) = next.take().filter(|_| versions.len() < limit) {
+            let document = state.documents.get(&cid).ok_or_else(|| GeohashError::NotFound(cid.clone()))?.0;
+            if document_name(&document.geohash) != name {
+                return Err(GeohashError::InvalidInput(format!("Document {} is not a version of {}", cid, name)));
+            }
+            next = document.previous;
+            versions.push(DocumentPointer { name: name.clone(), cid, version: document.version, updated_at: document.updated_at });
         }
+        Ok(versions)
     })
 }
 
+// Query function to get the latest value of every metric of a square
+#[query]
+fn latest_metrics(input: SquareInput) -> Result<Vec<MetricObservation>, GeohashError> {
+    let (geohash, _) = resolve_square(&input)?;
+    Ok(STATE.with(|state| state.borrow().latest_observations(&geohash)))
+}
+
+// Query function to get the observations of a metric of a square made in a time range (from inclusive, to exclusive,
+// both optional), oldest first; pass the time of the last observation as from to get the next page
+// (default 100, at most 1000 observations)
+#[query]
+fn metric_history(input: SquareInput, metric: String, from: Option<u64>, to: Option<u64>, limit: Option<u32>) -> Result<Vec<MetricObservation>, GeohashError> {
+    let (geohash, _) = resolve_square(&input)?;
+    if get_metric_definition(&metric).is_none() {
+        return Err(MetricError::UnknownMetric(metric).into());
+    }
+
+    let limit = limit.unwrap_or(DEFAULT_HISTORY_LIMIT).clamp(1, MAX_HISTORY_LIMIT) as usize;
+    Ok(STATE.with(|state| state.borrow().observation_history(&geohash, &metric, from.unwrap_or(0), to.unwrap_or(u64::MAX), limit)))
+}
+
+// Query function to aggregate the observations of a metric of a square in a time range per day or month: count, min,
+// max, mean and a percentile (0 to 100, default 50 for the median). At most 10000 observations are aggregated at once.
+#[query]
+fn metric_series(input: SquareInput, metric: String, from: u64, to: u64, period: AggregationPeriod, percentile: Option<u8>) -> Result<Vec<MetricAggregate>, GeohashError> {
+    let (geohash, _) = resolve_square(&input)?;
+    let definition = get_metric_definition(&metric).ok_or_else(|| MetricError::UnknownMetric(metric.clone()))?;
+    if definition.value_type == MetricValueType::Text {
+        return Err(MetricError::NotNumeric(metric).into());
+    }
+    let percentile = percentile.unwrap_or(50);
+    if percentile > 100 {
+        return Err(MetricError::InvalidPercentile(percentile).into());
+    }
+
+    let observations = STATE.with(|state| {
+        state.borrow().observation_history(&geohash, &metric, from, to, MAX_SERIES_OBSERVATIONS as usize + 1)
+    });
+    if observations.len() > MAX_SERIES_OBSERVATIONS as usize {
+        return Err(MetricError::TooManyObservations(MAX_SERIES_OBSERVATIONS).into());
+    }
+
+    let values: Vec<(u64, f64)> = observations
+        .iter()
+        .filter_map(|observation| aggregatable_value(&observation.value).map(|value| (observation.observed_at, value)))
+        .collect();
+    Ok(aggregate_series(&values, period, percentile))
+}
+
+// Update function for controllers to rewire the canister IDs of the dependencies without redeploying
+#[update]
+fn set_dependency_canisters(args: InitArgs) -> Result<(), GeohashError> {
+    require_controller()?;
+    validate_dependency_canisters(&args).map_err(GeohashError::InvalidInput)?;
+    store_dependency_canisters(args)
+}
+
+// Query function for controllers to read the configuration of the canister
+#[query]
+fn get_config() -> Result<Config, GeohashError> {
+    require_controller()?;
+    Ok(STATE.with(|state| state.borrow().config()))
+}
+
+// Query function to find the square containing a geolocation at a given resolution
+#[query]
+fn locate_square(geolocation: Geolocation, resolution: Resolution) -> Result<SquareInfo, GeohashError> {
+    validate_coordinate(geolocation.latitude, geolocation.longitude)?;
+    let (geohash, bounds) = find_nearest_geohash_with_bounds(geolocation.latitude, geolocation.longitude, resolution);
+    Ok(SquareInfo { geohash, resolution, bounds })
+}
+
+// Query function to get the resolution of a square
+#[query]
+fn resolution_of(geohash: String) -> Result<Resolution, GeohashError> {
+    Ok(lookup_square_index(&geohash)?.resolution)
+}
+
+// Query function to get the enclosing square at the next coarser resolution (None for the coarsest squares)
+#[query]
+fn parent_of(geohash: String) -> Result<Option<SquareInfo>, GeohashError> {
+    Ok(parent_square(lookup_square_index(&geohash)?).map(square_info))
+}
+
+// Query function to get the four squares at the next finer resolution (empty for the finest squares)
+#[query]
+fn children_of(geohash: String) -> Result<Vec<SquareInfo>, GeohashError> {
+    Ok(child_squares(lookup_square_index(&geohash)?).into_iter().map(square_info).collect())
+}
+
+// Query function to get the squares sharing an edge or a corner with a square, ordered from north-west to south-east
+#[query]
+fn neighbours_of(geohash: String) -> Result<Vec<SquareInfo>, GeohashError> {
+    Ok(neighbour_squares(lookup_square_index(&geohash)?).into_iter().map(square_info).collect())
+}
+
+// Query function to get all squares within a number of steps around a square (the square itself comes first, at most 10 steps)
+#[query]
+fn ring_of(geohash: String, steps: u32) -> Result<Vec<SquareInfo>, GeohashError> {
+    if steps > MAX_RING_STEPS {
+        return Err(GeohashError::InvalidInput(format!("A ring has at most {} steps", MAX_RING_STEPS)));
+    }
+    Ok(square_ring(lookup_square_index(&geohash)?, steps).into_iter().map(square_info).collect())
+}
+
+// Query function to get the ground distance in meters between the centers of two squares
+#[query]
+fn distance_between_squares(from_geohash: String, to_geohash: String) -> Result<f64, GeohashError> {
+    Ok(distance_between_centers(lookup_square_index(&from_geohash)?, lookup_square_index(&to_geohash)?))
+}
+
+// Query function to get the ground distance in meters from a geolocation to the center of a square
+#[query]
+fn distance_to_square(geolocation: Geolocation, geohash: String) -> Result<f64, GeohashError> {
+    validate_coordinate(geolocation.latitude, geolocation.longitude)?;
+    Ok(distance_to_center(geolocation.latitude, geolocation.longitude, lookup_square_index(&geohash)?))
+}
+
+// Query function to list the squares intersecting a bounding box, one page at a time (at most 500 squares per page).
+// Bounds with lon_start greater than lon_end cross the antimeridian.
+#[query]
+fn squares_in_bounds(bounds: Area, resolution: Resolution, cursor: Option<u64>, limit: Option<u32>) -> Result<CoveragePage, GeohashError> {
+    Ok(coverage_page(cover_region(&bounds, resolution, None, cursor.unwrap_or(0), limit.unwrap_or(DEFAULT_PAGE_SIZE))?))
+}
+
+// Query function to list the squares intersecting a GeoJSON Polygon or MultiPolygon, one page at a time.
+// A page may hold fewer squares than requested while next_cursor is set; keep paging until it is empty.
+#[query]
+fn squares_in_polygon(geojson: String, resolution: Resolution, cursor: Option<u64>, limit: Option<u32>) -> Result<CoveragePage, GeohashError> {
+    let region = Region::from_geojson(&geojson)?;
+    Ok(coverage_page(cover_region(&region.bounds(), resolution, Some(&region), cursor.unwrap_or(0), limit.unwrap_or(DEFAULT_PAGE_SIZE))?))
+}
+
 // END METHODS
 
 
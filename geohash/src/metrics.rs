@@ -0,0 +1,230 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use candid::Principal;
+use crate::types::{AggregationPeriod, MetricAggregate, MetricDefinition, MetricError, MetricValue, MetricValueType, RatingSummary};
+
+// Metrics of squares: a registry of metric definitions and an append-only log of time-stamped observations per square.
+// Observations are stored under "<square geohash>/<metric>/<observed_at>/<sequence>" keys, so the history of one metric
+// of one square is a contiguous, time-ordered range of the stable map, and observations made at the same time are all kept.
+
+// Longest metric name accepted by the registry
+const MAX_METRIC_NAME_LENGTH: usize = 64;
+
+// Number of observations returned by a history query if no limit is given, and the most returned at once
+pub const DEFAULT_HISTORY_LIMIT: u32 = 100;
+pub const MAX_HISTORY_LIMIT: u32 = 1000;
+
+// Most observations aggregated by one series query
+pub const MAX_SERIES_OBSERVATIONS: u32 = 10_000;
+
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+
+// Name reserved for ratings, which users give with rate_minted_square and which are no metric of their own
+pub const RATING_METRIC: &str = "Rating";
+
+// Range of the ratings users give squares
+pub const MIN_RATING: u32 = 1;
+pub const MAX_RATING: u32 = 10;
+
+// Function to get the metrics registered when the canister is installed
+pub fn default_metric_definitions() -> Vec<MetricDefinition> {
+    vec![
+        MetricDefinition {
+            name: "Air quality index".to_string(),
+            unit: "AQI".to_string(),
+            value_type: MetricValueType::Nat,
+            min: Some(0.0),
+            max: Some(500.0),
+            source: "oracle".to_string(),
+        },
+        MetricDefinition {
+            name: "Crime rate".to_string(),
+            unit: "incidents per 1000 residents and year".to_string(),
+            value_type: MetricValueType::Float,
+            min: Some(0.0),
+            max: None,
+            source: "oracle".to_string(),
+        },
+        MetricDefinition {
+            name: "Car accident rate".to_string(),
+            unit: "accidents per year".to_string(),
+            value_type: MetricValueType::Float,
+            min: Some(0.0),
+            max: None,
+            source: "oracle".to_string(),
+        },
+    ]
+}
+
+// Function to get the type of a metric value
+pub fn value_type(value: &MetricValue) -> MetricValueType {
+    match value {
+        MetricValue::Nat(_) => MetricValueType::Nat,
+        MetricValue::Int(_) => MetricValueType::Int,
+        MetricValue::Float(_) => MetricValueType::Float,
+        MetricValue::Bool(_) => MetricValueType::Bool,
+        MetricValue::Text(_) => MetricValueType::Text,
+    }
+}
+
+// Function to get a metric value as a number to aggregate, Bool values counting as 0 and 1
+pub fn aggregatable_value(value: &MetricValue) -> Option<f64> {
+    match value {
+        MetricValue::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+        value => numeric_value(value),
+    }
+}
+
+// Function to get a metric value as a number, if it is numeric
+pub fn numeric_value(value: &MetricValue) -> Option<f64> {
+    match value {
+        MetricValue::Nat(value) => Some(*value as f64),
+        MetricValue::Int(value) => Some(*value as f64),
+        MetricValue::Float(value) => Some(*value),
+        MetricValue::Bool(_) | MetricValue::Text(_) => None,
+    }
+}
+
+// Function to check a metric definition before it is registered
+pub fn validate_definition(definition: &MetricDefinition) -> Result<(), MetricError> {
+    let invalid = |reason: &str| Err(MetricError::InvalidDefinition(reason.to_string()));
+
+    if definition.name.trim().is_empty() || definition.name.len() > MAX_METRIC_NAME_LENGTH {
+        return invalid("Metric names have 1 to 64 characters");
+    }
+    // The name is part of the observation keys
+    if definition.name.contains('/') {
+        return invalid("Metric names cannot contain '/'");
+    }
+    if definition.name == RATING_METRIC {
+        return invalid("Ratings are given with rate_minted_square, not recorded as a metric");
+    }
+
+    let numeric = matches!(definition.value_type, MetricValueType::Nat | MetricValueType::Int | MetricValueType::Float);
+    if !numeric && (definition.min.is_some() || definition.max.is_some()) {
+        return invalid("Only numeric metrics have a range");
+    }
+    if definition.min.is_some_and(f64::is_nan) || definition.max.is_some_and(f64::is_nan) {
+        return invalid("The range of a metric cannot be NaN");
+    }
+    if let (Some(min), Some(max)) = (definition.min, definition.max) {
+        if min > max {
+            return invalid("The minimum of a metric is above its maximum");
+        }
+    }
+    Ok(())
+}
+
+// Function to check a value against the definition of its metric
+pub fn validate_value(definition: &MetricDefinition, value: &MetricValue) -> Result<(), MetricError> {
+    // Ratings are kept per user, a rating recorded as an observation would bypass that
+    if definition.name == RATING_METRIC {
+        return Err(MetricError::UnknownMetric(definition.name.clone()));
+    }
+    if value_type(value) != definition.value_type {
+        return Err(MetricError::WrongValueType(definition.value_type));
+    }
+
+    if let Some(number) = numeric_value(value) {
+        let below = definition.min.is_some_and(|min| number < min);
+        let above = definition.max.is_some_and(|max| number > max);
+        if !number.is_finite() || below || above {
+            return Err(MetricError::OutOfRange(number));
+        }
+    }
+    Ok(())
+}
+
+// Function to get the key prefix of the observations of a metric of a square
+pub fn observation_prefix(geohash: &str, metric: &str) -> String {
+    format!("{}/{}/", geohash, metric)
+}
+
+// Function to get the key of an observation; numbers are zero-padded so keys sort by time, then by sequence
+pub fn observation_key(geohash: &str, metric: &str, observed_at: u64, sequence: u64) -> String {
+    format!("{}{:020}/{:020}", observation_prefix(geohash, metric), observed_at, sequence)
+}
+
+// Function to get the time of an observation from its key
+pub fn observed_at_of_key(prefix: &str, key: &str) -> Option<u64> {
+    key.strip_prefix(prefix)?.split('/').next()?.parse().ok()
+}
+
+// Function to get the key of the rating of a square by a user
+pub fn rating_key(geohash: &str, principal: &Principal) -> String {
+    format!("{}/{}", geohash, principal)
+}
+
+// Function to summarize the ratings of a square from the number of users who gave each rating
+pub fn rating_summary(distribution: &[u32]) -> RatingSummary {
+    let count: u32 = distribution.iter().sum();
+    let total: u64 = distribution.iter().zip(MIN_RATING..).map(|(users, rating)| *users as u64 * rating as u64).sum();
+    RatingSummary {
+        count,
+        mean: if count == 0 { 0.0 } else { total as f64 / count as f64 },
+        distribution: distribution.to_vec(),
+    }
+}
+
+// Function to convert days since 1970-01-01 to a civil date (year, month, day), see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = (if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 }) as u32;
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+// Function to convert a civil date (year, month, day) to days since 1970-01-01
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = (if month > 2 { month - 3 } else { month + 9 }) as i64;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Function to get the start (nanoseconds since the epoch) of the UTC day or calendar month containing a time
+pub fn period_start(time: u64, period: AggregationPeriod) -> u64 {
+    let days = time / NANOS_PER_DAY;
+    match period {
+        AggregationPeriod::Day => days * NANOS_PER_DAY,
+        AggregationPeriod::Month => {
+            let (year, month, _) = civil_from_days(days as i64);
+            days_from_civil(year, month, 1) as u64 * NANOS_PER_DAY
+        }
+    }
+}
+
+// Function to get a percentile (0 to 100) of sorted values, interpolating linearly between the closest ranks
+fn percentile_of_sorted(sorted: &[f64], percentile: u8) -> f64 {
+    let rank = f64::from(percentile) / 100.0 * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+// Function to aggregate time-ordered (time, value) pairs per period
+pub fn aggregate_series(values: &[(u64, f64)], period: AggregationPeriod, percentile: u8) -> Vec<MetricAggregate> {
+    values
+        .chunk_by(|(a, _), (b, _)| period_start(*a, period) == period_start(*b, period))
+        .map(|chunk| {
+            let mut sorted: Vec<f64> = chunk.iter().map(|(_, value)| *value).collect();
+            sorted.sort_by(f64::total_cmp);
+            MetricAggregate {
+                period_start: period_start(chunk[0].0, period),
+                count: sorted.len() as u32,
+                min: sorted[0],
+                max: sorted[sorted.len() - 1],
+                mean: sorted.iter().sum::<f64>() / sorted.len() as f64,
+                percentile: percentile_of_sorted(&sorted, percentile),
+            }
+        })
+        .collect()
+}
@@ -4,7 +4,8 @@
 // START IMPORTS AND PRAGMAS
 use ic_cdk::api::call::call;
 use candid::{Principal};
-use crate::types::{Nft, MetadataPartLookup, MetadataKeyVal, MetadataResult};
+use crate::types::{Nft, MetadataPartLookup, MetadataKeyVal, MetadataResult, OwnerResult, GeohashError};
+use crate::error::{dependency_unavailable, DIP721_CANISTER};
 use crate::{get_dip721_canister_id, get_token_id_by_geohash};
 
 // END IMPORTS AND PRAGMAS
@@ -12,15 +13,15 @@ use crate::{get_dip721_canister_id, get_token_id_by_geohash};
 
 // START FUNCTIONS
 
-pub async fn get_nft_by_geohash(geohash: String) -> Result<Nft, String> {
+pub async fn get_nft_by_geohash(geohash: String) -> Result<Nft, GeohashError> {
     // Get the token ID from the geohash-to-token ID mapping
     let token_id = match get_token_id_by_geohash(&geohash) {
         Some(id) => id,
-        None => return Err(format!("No token ID found for geohash: {}", geohash)),
+        None => return Err(GeohashError::NotMinted(geohash)),
     };
 
     // Get the metadata by token ID
-    let dip721_canister_id = get_dip721_canister_id();
+    let dip721_canister_id = get_dip721_canister_id()?;
     let result: Result<(MetadataResult,), _> = call(
         dip721_canister_id,
         "getMetadataDip721",
@@ -36,11 +37,11 @@ pub async fn get_nft_by_geohash(geohash: String) -> Result<Nft, String> {
         },
         Ok((MetadataResult::Err(err),)) => {
             ic_cdk::println!("GEOHASH_NFT_LOOKUP_Metadata_ERR: {:?}", err);
-            return Err(format!("Failed to get metadata: {:?}", err));
+            return Err(GeohashError::NotFound(format!("Token {} in the DIP721 canister: {:?}", token_id, err)));
         },
         Err(err) => {
             ic_cdk::println!("GEOHASH_NFT_LOOKUP_DIP721_ERR: {:?}", err);
-            return Err(format!("Failed to get metadata from DIP721: {:?}", err));
+            return Err(dependency_unavailable(DIP721_CANISTER, err));
         },
     };
 
@@ -55,8 +56,9 @@ pub async fn get_nft_by_geohash(geohash: String) -> Result<Nft, String> {
         data: part.data,
     }).collect();
 
-    // Placeholder for owner and content retrieval logic
-    let owner = Principal::anonymous(); // Replace with actual owner retrieval logic if available
+    // The owner is the current one in the DIP721 canister, not the owner who claimed the square
+    let owner = get_nft_owner(token_id).await?;
+    // Placeholder for content retrieval logic
     let content = vec![]; // Replace with actual content retrieval logic if available
 
     
@@ -70,3 +72,19 @@ pub async fn get_nft_by_geohash(geohash: String) -> Result<Nft, String> {
     })
 }
 
+// Function to get the current owner of a token in the DIP721 canister, which can differ from the record of the square
+// if the owner transferred the NFT outside the geohash canister
+pub async fn get_nft_owner(token_id: u64) -> Result<Principal, GeohashError> {
+    let dip721_canister_id = get_dip721_canister_id()?;
+    let result: Result<(OwnerResult,), _> = call(
+        dip721_canister_id,
+        "ownerOfDip721",
+        (token_id,)
+    ).await;
+
+    match result {
+        Ok((OwnerResult::Ok(owner),)) => Ok(owner),
+        Ok((OwnerResult::Err(err),)) => Err(GeohashError::NotFound(format!("Token {} in the DIP721 canister: {:?}", token_id, err))),
+        Err(err) => Err(dependency_unavailable(DIP721_CANISTER, err)),
+    }
+}
@@ -5,8 +5,9 @@
 use ic_cdk::api::call::call;
 use candid::{Principal};
 use std::collections::HashMap;
-use crate::types::{MetadataDesc, MetadataPart, MetadataPurpose, MetadataVal, SquareProperties, MintReceipt}; // Import the common types
-use crate::{get_dip721_canister_id, update_geohash_to_token_id};
+use crate::types::{MetadataDesc, MetadataPart, MetadataPurpose, MetadataVal, SquareProperties, MintReceipt, GeohashError}; // Import the common types
+use crate::error::{dependency_unavailable, DIP721_CANISTER};
+use crate::get_dip721_canister_id;
 
 
 // END IMPORTS AND PRAGMAS
@@ -20,7 +21,7 @@ pub fn create_metadata(properties: SquareProperties) -> MetadataDesc {
     key_val_data.insert("geohash".to_string(), MetadataVal::TextContent(properties.geohash));
     key_val_data.insert("bitcoin_address".to_string(), MetadataVal::TextContent(properties.wallet.bitcoin));
     key_val_data.insert("ethereum_address".to_string(), MetadataVal::TextContent(properties.wallet.ether));
-    key_val_data.insert("ipns_id".to_string(), MetadataVal::TextContent(properties.metadata.clone())); // Use metadata as ipns_id
+    key_val_data.insert("document_name".to_string(), MetadataVal::TextContent(properties.metadata.clone())); // Use metadata as the name of the square document
 
 
     // Define MetadataPart with the required fields
@@ -35,20 +36,27 @@ pub fn create_metadata(properties: SquareProperties) -> MetadataDesc {
     metadata_print
 }
 
+// Function to get the idempotency key of the mint of a square: the DIP721 canister mints one NFT per key and answers a
+// repeated mint with the NFT it already minted, so retrying an interrupted mint never mints the square twice
+pub fn mint_idempotency_key(geohash: &str) -> String {
+    format!("geohash-mint:{}", geohash)
+}
+
 // END HELPER FUNCTIONS
 
 // START FUNCTIONS
 
-// Function to mint an NFT in the DIP721 canister
+// Function to mint an NFT in the DIP721 canister, at most once per idempotency key
 pub async fn mint_nft(
     to: Principal,
     properties: SquareProperties,
     blob_content: Vec<u8>,
-) -> Result<(u128, u64), String> {
-    let dip721_canister_id = get_dip721_canister_id();
+    idempotency_key: String,
+) -> Result<(u128, u64), GeohashError> {
+    let dip721_canister_id = get_dip721_canister_id()?;
     ic_cdk::println!("GEOHASH_NFT_MINT_Minting NFT with dip721_canister_id: {:?}", dip721_canister_id);
 
-    let geohash_clone = properties.geohash.clone(); // Clone the geohash
+    let geohash = properties.geohash.clone();
 
     // Create minimal metadata
     let metadata = create_metadata(properties);
@@ -57,7 +65,7 @@ pub async fn mint_nft(
     let result: Result<(MintReceipt,), _> = call(
         dip721_canister_id,
         "mintDip721",
-        (to, metadata, blob_content),
+        (to, metadata, blob_content, Some(idempotency_key)),
     ).await;
 
     // Log the result of the call
@@ -65,13 +73,10 @@ pub async fn mint_nft(
 
     match result {
         Ok((mint_result,)) => match mint_result {
-            MintReceipt::Ok { id, token_id } => {
-                update_geohash_to_token_id(geohash_clone, token_id); // Update mapping with geohash
-                Ok((id, token_id))
-            },
-            MintReceipt::Err(api_error) => Err(format!("GEOHASH_NFT_MINT_Failed to mint NFT: {:?}", api_error)),
+            MintReceipt::Ok { id, token_id } => Ok((id, token_id)),
+            MintReceipt::Err(api_error) => Err(GeohashError::MintFailed { geohash, reason: format!("{:?}", api_error) }),
         },
-        Err(err) => Err(format!("GEOHASH_NFT_MINT_Failed to mint NFT: {:?}", err)),
+        Err(err) => Err(dependency_unavailable(DIP721_CANISTER, err)),
     }
 }
 
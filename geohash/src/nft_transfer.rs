@@ -0,0 +1,36 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+// START IMPORTS AND PRAGMAS
+use ic_cdk::api::call::call;
+use candid::{Principal};
+use crate::types::{TxReceipt, GeohashError};
+use crate::error::{dependency_unavailable, DIP721_CANISTER};
+use crate::get_dip721_canister_id;
+
+
+// END IMPORTS AND PRAGMAS
+
+// START FUNCTIONS
+
+// Function to transfer an NFT in the DIP721 canister, where the geohash canister is a custodian; returns the transaction ID
+pub async fn transfer_nft(from: Principal, to: Principal, token_id: u64) -> Result<u128, GeohashError> {
+    let dip721_canister_id = get_dip721_canister_id()?;
+
+    let result: Result<(TxReceipt,), _> = call(
+        dip721_canister_id,
+        "transferFromDip721",
+        (from, to, token_id),
+    ).await;
+
+    // Log the result of the call
+    ic_cdk::println!("GEOHASH_NFT_TRANSFER_Result of transferFromDip721 call: {:?}", result);
+
+    match result {
+        Ok((TxReceipt::Ok(transaction_id),)) => Ok(transaction_id),
+        Ok((TxReceipt::Err(api_error),)) => Err(GeohashError::TransferFailed { token_id, reason: format!("{:?}", api_error) }),
+        Err(err) => Err(dependency_unavailable(DIP721_CANISTER, err)),
+    }
+}
+
+// END FUNCTIONS
@@ -0,0 +1,69 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use serde::{Deserialize, Serialize};
+use crate::types::{MetricError, Oracle};
+
+// Oracles are principals registered by the controllers to push observations of metrics. Calls to the canister are
+// signed by the caller, so the caller principal identifies the oracle; every submitted observation is audited.
+
+// Most observations submitted in one batch
+pub const MAX_ORACLE_BATCH: u32 = 100;
+
+// Window of the per-oracle rate limit
+pub const RATE_LIMIT_WINDOW_NANOS: u64 = 3_600_000_000_000;
+
+// Observations can be time-stamped at most this far ahead of the clock of the canister
+const MAX_CLOCK_SKEW_NANOS: u64 = 300_000_000_000;
+
+// Longest source reference accepted
+const MAX_SOURCE_REFERENCE_LENGTH: usize = 256;
+
+// Number of audit entries returned if no limit is given, and the most returned at once
+pub const DEFAULT_AUDIT_LIMIT: u32 = 100;
+pub const MAX_AUDIT_LIMIT: u32 = 1000;
+
+// Number of observations an oracle submitted in the current rate limit window
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RateWindow {
+    pub start: u64,
+    pub used: u32,
+}
+
+impl RateWindow {
+    /// Function to count a batch against the limit, starting a new window once the current one is over.
+    /// Rejects the whole batch with the time until the window resets if it does not fit.
+    pub fn consume(&mut self, limit: u32, count: u32, now: u64) -> Result<(), MetricError> {
+        if now >= self.start.saturating_add(RATE_LIMIT_WINDOW_NANOS) {
+            *self = RateWindow { start: now, used: 0 };
+        }
+        if self.used.saturating_add(count) > limit {
+            return Err(MetricError::RateLimited(self.start + RATE_LIMIT_WINDOW_NANOS - now));
+        }
+        self.used += count;
+        Ok(())
+    }
+}
+
+// A registered oracle with the state of its rate limit
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OracleState {
+    pub oracle: Oracle,
+    pub window: RateWindow,
+}
+
+// Function to check the time stamp of a submitted observation
+pub fn validate_timestamp(observed_at: u64, now: u64) -> Result<(), MetricError> {
+    if observed_at == 0 || observed_at > now.saturating_add(MAX_CLOCK_SKEW_NANOS) {
+        return Err(MetricError::InvalidTimestamp(observed_at));
+    }
+    Ok(())
+}
+
+// Function to check the reference to the original measurement of a submitted observation
+pub fn validate_source_reference(source_reference: &str) -> Result<(), MetricError> {
+    if source_reference.trim().is_empty() || source_reference.len() > MAX_SOURCE_REFERENCE_LENGTH {
+        return Err(MetricError::InvalidSourceReference(source_reference.chars().take(MAX_SOURCE_REFERENCE_LENGTH).collect()));
+    }
+    Ok(())
+}
@@ -0,0 +1,77 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use crate::types::{PendingMint, ProvisioningStage, ProvisioningState, StuckSquares};
+
+// Provisioning a square runs in steps: reserve it, get its Bitcoin address, get its Ethereum address, create the metadata
+// of its NFT, mint the NFT and index the token. The state is stored after every step, so a mint that failed or was
+// interrupted resumes at the step that failed instead of starting over, and a timer retries it with a growing delay until
+// it gives up on mints that keep failing before the NFT is minted.
+
+// Interval of the timer retrying pending mints and the number of mints it retries per run
+pub const RETRY_INTERVAL_SECONDS: u64 = 60;
+pub const RETRY_BATCH_SIZE: usize = 5;
+
+// Number of attempts after which the timer gives up on a mint and releases the square and the claim of its owner
+pub const MAX_MINT_ATTEMPTS: u32 = 20;
+
+// Delay before the first retry of a failed mint, doubling with every attempt up to the maximum
+const MIN_RETRY_DELAY_SECONDS: u64 = 60;
+const MAX_RETRY_DELAY_SECONDS: u64 = 3600;
+
+// All stages in the order provisioning goes through them
+const STAGES: [ProvisioningStage; 5] = [
+    ProvisioningStage::Reserved,
+    ProvisioningStage::BitcoinAddress,
+    ProvisioningStage::EthereumAddress,
+    ProvisioningStage::Metadata,
+    ProvisioningStage::Minted,
+];
+
+impl ProvisioningState {
+    // Function to get the stage of a state without its data
+    pub fn stage(&self) -> ProvisioningStage {
+        match self {
+            ProvisioningState::Reserved => ProvisioningStage::Reserved,
+            ProvisioningState::BitcoinAddress { .. } => ProvisioningStage::BitcoinAddress,
+            ProvisioningState::EthereumAddress { .. } => ProvisioningStage::EthereumAddress,
+            ProvisioningState::Metadata { .. } => ProvisioningStage::Metadata,
+            ProvisioningState::Minted { .. } => ProvisioningStage::Minted,
+        }
+    }
+}
+
+// Function to get the delay in nanoseconds before the next retry of a mint after a number of attempts
+pub fn retry_delay(attempts: u32) -> u64 {
+    let seconds = MIN_RETRY_DELAY_SECONDS.saturating_mul(1u64 << attempts.saturating_sub(1).min(32));
+    seconds.min(MAX_RETRY_DELAY_SECONDS) * 1_000_000_000
+}
+
+// Function to record a failed attempt of a pending mint and when to retry it
+pub fn record_failure(pending: &mut PendingMint, error: String, now: u64) {
+    pending.last_error = Some(error);
+    pending.next_retry_at = Some(now + retry_delay(pending.attempts));
+}
+
+// Function to check whether the timer retries a pending mint; mints that never failed are retried right away
+pub fn due_for_retry(pending: &PendingMint, now: u64) -> bool {
+    pending.next_retry_at.is_none_or(|retry_at| retry_at <= now)
+}
+
+// Function to check whether the timer gives up on a pending mint. Only mints that failed before minting are given up,
+// from the Metadata stage on the DIP721 canister may have minted the NFT and the mint is retried until it is indexed
+pub fn should_abandon(pending: &PendingMint) -> bool {
+    pending.attempts >= MAX_MINT_ATTEMPTS
+        && matches!(pending.state.stage(), ProvisioningStage::Reserved | ProvisioningStage::BitcoinAddress | ProvisioningStage::EthereumAddress)
+}
+
+// Function to group pending mints by the stage they are stuck in, listing every stage
+pub fn group_by_stage(pending_mints: Vec<PendingMint>) -> Vec<StuckSquares> {
+    STAGES
+        .iter()
+        .map(|stage| StuckSquares {
+            stage: *stage,
+            squares: pending_mints.iter().filter(|pending| pending.state.stage() == *stage).cloned().collect(),
+        })
+        .collect()
+}
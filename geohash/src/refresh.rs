@@ -0,0 +1,56 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use crate::types::{RefreshFailure, RefreshJobStatus};
+
+// The refresh job runs on a timer and walks the minted squares in batches: it refreshes the balances of each square and
+// pulls the feeds for it, so read endpoints serve cached values instead of calling other canisters and APIs.
+
+pub const DEFAULT_REFRESH_INTERVAL_SECONDS: u64 = 3600;
+pub const MIN_REFRESH_INTERVAL_SECONDS: u64 = 60;
+pub const DEFAULT_REFRESH_BATCH_SIZE: u32 = 10;
+pub const MAX_REFRESH_BATCH_SIZE: u32 = 50;
+
+// Number of failures kept in the status of the job
+const MAX_RECENT_FAILURES: usize = 20;
+
+impl Default for RefreshJobStatus {
+    fn default() -> Self {
+        RefreshJobStatus {
+            interval_seconds: DEFAULT_REFRESH_INTERVAL_SECONDS,
+            batch_size: DEFAULT_REFRESH_BATCH_SIZE,
+            next_square: None,
+            last_run: None,
+            squares_processed_last_run: 0,
+            squares_processed_total: 0,
+            failures_last_run: 0,
+            failures_total: 0,
+            recent_failures: vec![],
+        }
+    }
+}
+
+// Function to check the settings of the job
+pub fn validate_refresh_settings(interval_seconds: u64, batch_size: u32) -> Result<(), String> {
+    if interval_seconds < MIN_REFRESH_INTERVAL_SECONDS {
+        return Err(format!("The refresh job runs at most every {} seconds", MIN_REFRESH_INTERVAL_SECONDS));
+    }
+    if batch_size == 0 || batch_size > MAX_REFRESH_BATCH_SIZE {
+        return Err(format!("A refresh batch has 1 to {} squares", MAX_REFRESH_BATCH_SIZE));
+    }
+    Ok(())
+}
+
+// Function to record a finished batch in the status of the job; next_square is the last square of the batch
+pub fn record_batch(status: &mut RefreshJobStatus, processed: u32, failures: Vec<RefreshFailure>, next_square: Option<String>, now: u64) {
+    status.next_square = next_square;
+    status.last_run = Some(now);
+    status.squares_processed_last_run = processed;
+    status.squares_processed_total += processed as u64;
+    status.failures_last_run = failures.len() as u32;
+    status.failures_total += failures.len() as u64;
+
+    status.recent_failures.extend(failures);
+    let excess = status.recent_failures.len().saturating_sub(MAX_RECENT_FAILURES);
+    status.recent_failures.drain(..excess);
+}
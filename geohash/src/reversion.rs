@@ -0,0 +1,155 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use candid::Principal;
+use crate::metrics::numeric_value;
+use crate::types::{GeohashError, MetricDefinition, MetricObservation, MetricValueType, ReversionEventKind, ReversionPolicy, RiskReason, SquareReversion};
+
+// Owners of squares are patrons: they are expected to keep the health of their squares up and gather donations. A timer
+// walks the minted squares in batches and evaluates every owned square once per evaluation interval against the
+// reversion policy. Squares failing all its checks are marked at risk, and a square still at risk after the grace period
+// reverts to the canister, where the next user can claim it.
+
+pub const DEFAULT_EVALUATION_INTERVAL_SECONDS: u64 = 86_400;
+pub const MIN_EVALUATION_INTERVAL_SECONDS: u64 = 60;
+
+// Interval of the timer evaluating the squares and the number of minted squares it walks per run
+pub const EVALUATION_TICK_SECONDS: u64 = 60;
+pub const EVALUATION_BATCH_SIZE: usize = 100;
+pub const DEFAULT_GRACE_PERIOD_SECONDS: u64 = 30 * 86_400;
+
+// Most health thresholds of a policy
+const MAX_HEALTH_THRESHOLDS: usize = 20;
+
+// Number of events returned if no limit is given, and the most returned at once
+pub const DEFAULT_EVENT_LIMIT: u32 = 100;
+pub const MAX_EVENT_LIMIT: u32 = 1000;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+// Without thresholds, a minimum of donations or a maximum inactivity no square is ever at risk
+impl Default for ReversionPolicy {
+    fn default() -> Self {
+        ReversionPolicy {
+            evaluation_interval_seconds: DEFAULT_EVALUATION_INTERVAL_SECONDS,
+            health_thresholds: vec![],
+            min_donations: None,
+            max_owner_inactivity_seconds: None,
+            grace_period_seconds: DEFAULT_GRACE_PERIOD_SECONDS,
+        }
+    }
+}
+
+// Function to check a reversion policy; health thresholds have to bound registered numeric metrics
+pub fn validate_reversion_policy(policy: &ReversionPolicy, definition_of: impl Fn(&str) -> Option<MetricDefinition>) -> Result<(), GeohashError> {
+    if policy.evaluation_interval_seconds < MIN_EVALUATION_INTERVAL_SECONDS {
+        return Err(GeohashError::InvalidInput(format!("Squares are evaluated at most every {} seconds", MIN_EVALUATION_INTERVAL_SECONDS)));
+    }
+    if policy.health_thresholds.len() > MAX_HEALTH_THRESHOLDS {
+        return Err(GeohashError::InvalidInput(format!("A policy has at most {} health thresholds", MAX_HEALTH_THRESHOLDS)));
+    }
+    for threshold in &policy.health_thresholds {
+        let definition = definition_of(&threshold.metric).ok_or_else(|| GeohashError::NotFound(format!("Metric {}", threshold.metric)))?;
+        if !matches!(definition.value_type, MetricValueType::Nat | MetricValueType::Int | MetricValueType::Float) {
+            return Err(GeohashError::InvalidInput(format!("{} is not a numeric metric", threshold.metric)));
+        }
+        let valid = match (threshold.min, threshold.max) {
+            (None, None) => false,
+            (Some(min), Some(max)) => min.is_finite() && max.is_finite() && min <= max,
+            (Some(bound), None) | (None, Some(bound)) => bound.is_finite(),
+        };
+        if !valid {
+            return Err(GeohashError::InvalidInput(format!("The threshold of {} needs a finite minimum or maximum", threshold.metric)));
+        }
+    }
+    Ok(())
+}
+
+/// Function to find why a square is at risk of reverting. A square is only at risk if every check of the policy fails:
+/// - health: the latest value of a metric lies outside its threshold (metrics without observations count as healthy)
+/// - donations: the square received fewer donations since the previous evaluation than the minimum (unknown on the
+///   first evaluation, which counts as enough)
+/// - owner activity: the owner was inactive for longer than the maximum
+///
+/// Returns no reasons if the square is not at risk, also if the policy has no checks.
+pub fn risk_reasons(policy: &ReversionPolicy, latest: &[MetricObservation], donations: Option<u64>, last_active_at: u64, now: u64) -> Vec<RiskReason> {
+    let mut reasons = vec![];
+
+    if !policy.health_thresholds.is_empty() {
+        let low_health: Vec<RiskReason> = policy
+            .health_thresholds
+            .iter()
+            .filter_map(|threshold| {
+                let observation = latest.iter().find(|observation| observation.metric == threshold.metric)?;
+                let value = numeric_value(&observation.value)?;
+                let outside = threshold.min.is_some_and(|min| value < min) || threshold.max.is_some_and(|max| value > max);
+                outside.then(|| RiskReason::LowHealth { metric: threshold.metric.clone(), value })
+            })
+            .collect();
+        if low_health.is_empty() {
+            return vec![];
+        }
+        reasons.extend(low_health);
+    }
+
+    if let Some(min_donations) = policy.min_donations {
+        match donations {
+            Some(received) if received < min_donations => reasons.push(RiskReason::LowDonations { received }),
+            _ => return vec![],
+        }
+    }
+
+    if let Some(max_inactivity) = policy.max_owner_inactivity_seconds {
+        if now.saturating_sub(last_active_at) <= max_inactivity.saturating_mul(NANOS_PER_SECOND) {
+            return vec![];
+        }
+        reasons.push(RiskReason::InactiveOwner { last_active_at });
+    }
+
+    reasons
+}
+
+/// Function to get the next evaluation of a square from the previous one, with the event if the square became or stopped
+/// being at risk. An evaluation of an earlier owner does not count for the current one.
+pub fn next_reversion(previous: Option<SquareReversion>, geohash: &str, owner: Principal, balance: Option<u64>, reasons: Vec<RiskReason>, now: u64) -> (SquareReversion, Option<ReversionEventKind>) {
+    let previous = previous.filter(|previous| previous.owner == owner);
+    let at_risk_since = previous.as_ref().and_then(|previous| previous.at_risk_since);
+
+    let (at_risk_since, event) = match (at_risk_since, reasons.is_empty()) {
+        (None, true) => (None, None),
+        (None, false) => (Some(now), Some(ReversionEventKind::AtRisk(reasons.clone()))),
+        (Some(_), true) => (None, Some(ReversionEventKind::Recovered)),
+        (Some(since), false) => (Some(since), None),
+    };
+
+    let reversion = SquareReversion {
+        geohash: geohash.to_string(),
+        owner,
+        last_evaluated_at: now,
+        last_balance: balance.or(previous.and_then(|previous| previous.last_balance)),
+        at_risk_since,
+        reasons,
+    };
+    (reversion, event)
+}
+
+// Function to check whether a square is due for evaluation: it was never evaluated for its owner, or not for a whole
+// evaluation interval
+pub fn due_for_evaluation(previous: Option<&SquareReversion>, owner: Principal, policy: &ReversionPolicy, now: u64) -> bool {
+    previous
+        .filter(|previous| previous.owner == owner)
+        .is_none_or(|previous| now.saturating_sub(previous.last_evaluated_at) >= policy.evaluation_interval_seconds.saturating_mul(NANOS_PER_SECOND))
+}
+
+// Function to get the donations received since the previous evaluation, if the balance is known both times
+pub fn donations_since(previous: Option<&SquareReversion>, owner: Principal, balance: Option<u64>) -> Option<u64> {
+    let previous_balance = previous.filter(|previous| previous.owner == owner)?.last_balance?;
+    Some(balance?.saturating_sub(previous_balance))
+}
+
+// Function to check whether a square was at risk for the whole grace period
+pub fn due_for_reversion(reversion: &SquareReversion, policy: &ReversionPolicy, now: u64) -> bool {
+    reversion
+        .at_risk_since
+        .is_some_and(|since| now.saturating_sub(since) >= policy.grace_period_seconds.saturating_mul(NANOS_PER_SECOND))
+}
@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use crate::grid_generator::{Resolution, SquareIndex, square_at};
+
+// Three-word codes for squares, e.g. "bafok.temul.zirag". Each word is a consonant-vowel-consonant-vowel-consonant
+// syllable carrying 16 bits, so a code holds 48 bits: the resolution level (3 bits), the row (17 bits) and the
+// column (18 bits) of the square. The bits are scrambled so neighbouring squares get unrelated codes.
+
+const CONSONANTS: [char; 16] = ['b', 'd', 'f', 'g', 'h', 'j', 'k', 'l', 'm', 'n', 'p', 'r', 's', 't', 'v', 'z'];
+const VOWELS: [char; 4] = ['a', 'e', 'i', 'o'];
+
+const ROW_BITS: u32 = 17;
+const COL_BITS: u32 = 18;
+const CODE_BITS: u32 = 48;
+const CODE_MASK: u64 = (1 << CODE_BITS) - 1;
+
+// Odd multiplier used to scramble the packed square position (any odd number is invertible modulo 2^48)
+const SCRAMBLE_MULTIPLIER: u64 = 0x9E37_79B9_7F4B;
+
+// Function to compute the inverse of an odd number modulo 2^48 (Newton's iteration doubles the correct bits each step)
+fn modular_inverse(value: u64) -> u64 {
+    let mut inverse = value;
+    for _ in 0..5 {
+        inverse = inverse.wrapping_mul(2u64.wrapping_sub(value.wrapping_mul(inverse)));
+    }
+    inverse & CODE_MASK
+}
+
+// Function to scramble 48 bits; the xor shift undoes itself because it shifts by half the width
+fn scramble(value: u64) -> u64 {
+    let value = value.wrapping_mul(SCRAMBLE_MULTIPLIER) & CODE_MASK;
+    value ^ (value >> (CODE_BITS / 2))
+}
+
+fn unscramble(value: u64) -> u64 {
+    let value = value ^ (value >> (CODE_BITS / 2));
+    value.wrapping_mul(modular_inverse(SCRAMBLE_MULTIPLIER)) & CODE_MASK
+}
+
+// Function to spell 16 bits as a pronounceable word
+fn encode_word(bits: u16) -> String {
+    let bits = bits as usize;
+    [
+        CONSONANTS[bits >> 12],
+        VOWELS[(bits >> 10) & 0x3],
+        CONSONANTS[(bits >> 6) & 0xF],
+        VOWELS[(bits >> 4) & 0x3],
+        CONSONANTS[bits & 0xF],
+    ]
+    .iter()
+    .collect()
+}
+
+// Function to read the 16 bits of a word
+fn decode_word(word: &str) -> Option<u16> {
+    let letters: Vec<char> = word.chars().collect();
+    if letters.len() != 5 {
+        return None;
+    }
+
+    let consonant = |letter: char| CONSONANTS.iter().position(|&c| c == letter).map(|position| position as u16);
+    let vowel = |letter: char| VOWELS.iter().position(|&v| v == letter).map(|position| position as u16);
+
+    Some(
+        (consonant(letters[0])? << 12)
+            | (vowel(letters[1])? << 10)
+            | (consonant(letters[2])? << 6)
+            | (vowel(letters[3])? << 4)
+            | consonant(letters[4])?,
+    )
+}
+
+/// Function to compute the three-word code of a square
+pub fn square_code(index: SquareIndex) -> String {
+    let packed = ((index.resolution.level() as u64) << (ROW_BITS + COL_BITS)) | ((index.row as u64) << COL_BITS) | index.col as u64;
+    let scrambled = scramble(packed);
+
+    [32, 16, 0].iter().map(|&shift| encode_word((scrambled >> shift) as u16)).collect::<Vec<_>>().join(".")
+}
+
+/// Function to find the square identified by a three-word code (case-insensitive, words separated by dots)
+pub fn square_from_code(code: &str) -> Option<SquareIndex> {
+    let words: Vec<&str> = code.trim().split('.').collect();
+    if words.len() != 3 {
+        return None;
+    }
+
+    let mut scrambled = 0u64;
+    for word in words {
+        scrambled = (scrambled << 16) | decode_word(&word.to_lowercase())? as u64;
+    }
+
+    let packed = unscramble(scrambled);
+    let level = (packed >> (ROW_BITS + COL_BITS)) as usize;
+    let row = ((packed >> COL_BITS) & ((1 << ROW_BITS) - 1)) as u32;
+    let col = (packed & ((1 << COL_BITS) - 1)) as u32;
+
+    square_at(*Resolution::ALL.get(level)?, row, col)
+}
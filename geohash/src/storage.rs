@@ -0,0 +1,426 @@
+// SPDX-License-Identifier: MIT
+// (C) 2024 Thomas Magerl
+
+use candid::{CandidType, Deserialize, Principal};
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use crate::metrics::{default_metric_definitions, observation_key, observation_prefix, observed_at_of_key, rating_key, rating_summary, MIN_RATING, MAX_RATING};
+use crate::oracle::OracleState;
+use crate::document::{encode_document, cid_of_block, same_content};
+use crate::types::{AuditEntry, Contribution, ClaimPolicy, ContributionPolicy, DocumentPointer, FeedStatus, GeohashError, MetricDefinition, MetricObservation, MetricValue, PendingMint, RatingSummary, RefreshJobStatus, Reputation, ReversionEvent, ReversionEventKind, ReversionPolicy, SquareReversion, SquareBalances, SquareDocument, SquareRecord};
+
+// Version of the layout of the stable memory; bump it and add a migration to StableState::migrate when the layout changes
+pub const SCHEMA_VERSION: u32 = 1;
+
+// Memory regions of the stable memory, one per data structure (never reuse or renumber an ID)
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(0);
+const TOKEN_IDS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const SQUARE_RECORDS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const METRIC_DEFINITIONS_MEMORY_ID: MemoryId = MemoryId::new(3);
+const LATEST_OBSERVATIONS_MEMORY_ID: MemoryId = MemoryId::new(4);
+const OBSERVATIONS_MEMORY_ID: MemoryId = MemoryId::new(5);
+const ORACLES_MEMORY_ID: MemoryId = MemoryId::new(6);
+const AUDIT_LOG_MEMORY_ID: MemoryId = MemoryId::new(7);
+const FEEDS_MEMORY_ID: MemoryId = MemoryId::new(8);
+const REFRESH_JOB_MEMORY_ID: MemoryId = MemoryId::new(9);
+const SQUARE_BALANCES_MEMORY_ID: MemoryId = MemoryId::new(10);
+const CONTRIBUTIONS_MEMORY_ID: MemoryId = MemoryId::new(11);
+const SQUARE_CONTRIBUTIONS_MEMORY_ID: MemoryId = MemoryId::new(12);
+const REPUTATIONS_MEMORY_ID: MemoryId = MemoryId::new(13);
+const CONTRIBUTION_POLICY_MEMORY_ID: MemoryId = MemoryId::new(14);
+const RATINGS_MEMORY_ID: MemoryId = MemoryId::new(15);
+const RATING_DISTRIBUTIONS_MEMORY_ID: MemoryId = MemoryId::new(16);
+const DOCUMENTS_MEMORY_ID: MemoryId = MemoryId::new(17);
+const DOCUMENT_NAMES_MEMORY_ID: MemoryId = MemoryId::new(18);
+const PENDING_MINTS_MEMORY_ID: MemoryId = MemoryId::new(19);
+const CLAIM_COUNTS_MEMORY_ID: MemoryId = MemoryId::new(20);
+const CLAIM_POLICY_MEMORY_ID: MemoryId = MemoryId::new(21);
+const REVERSION_POLICY_MEMORY_ID: MemoryId = MemoryId::new(22);
+const SQUARE_REVERSIONS_MEMORY_ID: MemoryId = MemoryId::new(23);
+const REVERSION_EVENTS_MEMORY_ID: MemoryId = MemoryId::new(24);
+const OWNER_ACTIVITY_MEMORY_ID: MemoryId = MemoryId::new(25);
+const REVERSION_CURSOR_MEMORY_ID: MemoryId = MemoryId::new(26);
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+// Wrapper storing any serializable value as CBOR in stable memory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cbor<T>(pub T);
+
+impl<T: Serialize + DeserializeOwned> Storable for Cbor<T> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&self.0, &mut bytes).expect("Failed to encode value for stable memory");
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Cbor(ciborium::de::from_reader(bytes.as_ref()).expect("Failed to decode value from stable memory"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+// Value of an observation in the log; the metric, square and time are part of its key
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ObservationEntry {
+    pub value: MetricValue,
+    pub submitter: Principal,
+}
+
+// Helper function to turn a failed write of a cell into the error returned by the endpoints
+fn write_failed(cell: &str, err: impl Debug) -> GeohashError {
+    GeohashError::StorageFailed(format!("Failed to write the {} cell: {:?}", cell, err))
+}
+
+// Configuration of the canister, stored together with the schema version of the stable memory
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct Config {
+    pub schema_version: u32,
+    pub dip721_canister_id: Option<Principal>,
+    pub bitcoin_canister_id: Option<Principal>,
+    pub ethereum_canister_id: Option<Principal>,
+}
+
+// All state of the canister, kept in stable memory so it survives upgrades without pre_upgrade/post_upgrade copies
+pub struct StableState {
+    pub config: StableCell<Cbor<Config>, Memory>,
+    pub token_ids: StableBTreeMap<String, u64, Memory>,
+    pub square_records: StableBTreeMap<String, Cbor<SquareRecord>, Memory>,
+    pub metric_definitions: StableBTreeMap<String, Cbor<MetricDefinition>, Memory>,
+    pub latest_observations: StableBTreeMap<String, Cbor<BTreeMap<String, MetricObservation>>, Memory>,
+    pub observations: StableBTreeMap<String, Cbor<ObservationEntry>, Memory>,
+    pub oracles: StableBTreeMap<Principal, Cbor<OracleState>, Memory>,
+    // Append-only, keyed by the sequence number of the entry
+    pub audit_log: StableBTreeMap<u64, Cbor<AuditEntry>, Memory>,
+    pub feeds: StableBTreeMap<String, Cbor<FeedStatus>, Memory>,
+    pub refresh_job: StableCell<Cbor<RefreshJobStatus>, Memory>,
+    pub square_balances: StableBTreeMap<String, Cbor<SquareBalances>, Memory>,
+    // Keyed by the ID of the contribution, which numbers them in order of submission
+    pub contributions: StableBTreeMap<u64, Cbor<Contribution>, Memory>,
+    // Index of the contributions of every square, keyed by "<square geohash>/<contribution ID>"
+    pub square_contributions: StableBTreeMap<String, u64, Memory>,
+    pub reputations: StableBTreeMap<Principal, Cbor<Reputation>, Memory>,
+    pub contribution_policy: StableCell<Cbor<ContributionPolicy>, Memory>,
+    // Rating of every user of a square, keyed by "<square geohash>/<principal>"
+    pub ratings: StableBTreeMap<String, u8, Memory>,
+    // Number of users who gave each rating per square, kept up to date with the ratings
+    pub rating_distributions: StableBTreeMap<String, Cbor<Vec<u32>>, Memory>,
+    // Immutable documents of the squares keyed by their CID, and the names pointing to the latest documents
+    pub documents: StableBTreeMap<String, Cbor<SquareDocument>, Memory>,
+    pub document_names: StableBTreeMap<String, Cbor<DocumentPointer>, Memory>,
+    // Mints that were started but not finished, keyed by the geohash of the square
+    pub pending_mints: StableBTreeMap<String, Cbor<PendingMint>, Memory>,
+    // Number of squares every principal claimed, minted or still pending
+    pub claim_counts: StableBTreeMap<Principal, u32, Memory>,
+    pub claim_policy: StableCell<Cbor<ClaimPolicy>, Memory>,
+    pub reversion_policy: StableCell<Cbor<ReversionPolicy>, Memory>,
+    // Latest evaluation of every owned square against the reversion policy
+    pub square_reversions: StableBTreeMap<String, Cbor<SquareReversion>, Memory>,
+    // Append-only, keyed by the sequence number of the event
+    pub reversion_events: StableBTreeMap<u64, Cbor<ReversionEvent>, Memory>,
+    // Time every principal last claimed, rated or contributed to a square
+    pub owner_activity: StableBTreeMap<Principal, u64, Memory>,
+    // Last square evaluated against the reversion policy, the next batch continues after it
+    pub reversion_cursor: StableCell<Cbor<Option<String>>, Memory>,
+}
+
+impl StableState {
+    /// Function to open the state stored in a memory (a fresh memory gives an empty state)
+    pub fn init(memory: DefaultMemoryImpl) -> Self {
+        let memory_manager = MemoryManager::init(memory);
+
+        StableState {
+            config: StableCell::init(memory_manager.get(CONFIG_MEMORY_ID), Cbor(Config::default())).expect("Failed to initialize the config cell"),
+            token_ids: StableBTreeMap::init(memory_manager.get(TOKEN_IDS_MEMORY_ID)),
+            square_records: StableBTreeMap::init(memory_manager.get(SQUARE_RECORDS_MEMORY_ID)),
+            metric_definitions: StableBTreeMap::init(memory_manager.get(METRIC_DEFINITIONS_MEMORY_ID)),
+            latest_observations: StableBTreeMap::init(memory_manager.get(LATEST_OBSERVATIONS_MEMORY_ID)),
+            observations: StableBTreeMap::init(memory_manager.get(OBSERVATIONS_MEMORY_ID)),
+            oracles: StableBTreeMap::init(memory_manager.get(ORACLES_MEMORY_ID)),
+            audit_log: StableBTreeMap::init(memory_manager.get(AUDIT_LOG_MEMORY_ID)),
+            feeds: StableBTreeMap::init(memory_manager.get(FEEDS_MEMORY_ID)),
+            refresh_job: StableCell::init(memory_manager.get(REFRESH_JOB_MEMORY_ID), Cbor(RefreshJobStatus::default())).expect("Failed to initialize the refresh job cell"),
+            square_balances: StableBTreeMap::init(memory_manager.get(SQUARE_BALANCES_MEMORY_ID)),
+            contributions: StableBTreeMap::init(memory_manager.get(CONTRIBUTIONS_MEMORY_ID)),
+            square_contributions: StableBTreeMap::init(memory_manager.get(SQUARE_CONTRIBUTIONS_MEMORY_ID)),
+            reputations: StableBTreeMap::init(memory_manager.get(REPUTATIONS_MEMORY_ID)),
+            contribution_policy: StableCell::init(memory_manager.get(CONTRIBUTION_POLICY_MEMORY_ID), Cbor(ContributionPolicy::default())).expect("Failed to initialize the contribution policy cell"),
+            ratings: StableBTreeMap::init(memory_manager.get(RATINGS_MEMORY_ID)),
+            rating_distributions: StableBTreeMap::init(memory_manager.get(RATING_DISTRIBUTIONS_MEMORY_ID)),
+            documents: StableBTreeMap::init(memory_manager.get(DOCUMENTS_MEMORY_ID)),
+            document_names: StableBTreeMap::init(memory_manager.get(DOCUMENT_NAMES_MEMORY_ID)),
+            pending_mints: StableBTreeMap::init(memory_manager.get(PENDING_MINTS_MEMORY_ID)),
+            claim_counts: StableBTreeMap::init(memory_manager.get(CLAIM_COUNTS_MEMORY_ID)),
+            claim_policy: StableCell::init(memory_manager.get(CLAIM_POLICY_MEMORY_ID), Cbor(ClaimPolicy::default())).expect("Failed to initialize the claim policy cell"),
+            reversion_policy: StableCell::init(memory_manager.get(REVERSION_POLICY_MEMORY_ID), Cbor(ReversionPolicy::default())).expect("Failed to initialize the reversion policy cell"),
+            square_reversions: StableBTreeMap::init(memory_manager.get(SQUARE_REVERSIONS_MEMORY_ID)),
+            reversion_events: StableBTreeMap::init(memory_manager.get(REVERSION_EVENTS_MEMORY_ID)),
+            owner_activity: StableBTreeMap::init(memory_manager.get(OWNER_ACTIVITY_MEMORY_ID)),
+            reversion_cursor: StableCell::init(memory_manager.get(REVERSION_CURSOR_MEMORY_ID), Cbor(None)).expect("Failed to initialize the reversion cursor cell"),
+        }
+    }
+
+    pub fn config(&self) -> Config {
+        self.config.get().0.clone()
+    }
+
+    pub fn update_config(&mut self, update: impl FnOnce(&mut Config)) -> Result<(), GeohashError> {
+        let mut config = self.config();
+        update(&mut config);
+        self.config.set(Cbor(config)).map(|_| ()).map_err(|err| write_failed("config", err))
+    }
+
+    pub fn refresh_job(&self) -> RefreshJobStatus {
+        self.refresh_job.get().0.clone()
+    }
+
+    pub fn update_refresh_job(&mut self, update: impl FnOnce(&mut RefreshJobStatus)) -> Result<(), GeohashError> {
+        let mut status = self.refresh_job();
+        update(&mut status);
+        self.refresh_job.set(Cbor(status)).map(|_| ()).map_err(|err| write_failed("refresh job", err))
+    }
+
+    pub fn contribution_policy(&self) -> ContributionPolicy {
+        self.contribution_policy.get().0.clone()
+    }
+
+    pub fn set_contribution_policy(&mut self, policy: ContributionPolicy) -> Result<(), GeohashError> {
+        self.contribution_policy.set(Cbor(policy)).map(|_| ()).map_err(|err| write_failed("contribution policy", err))
+    }
+
+    pub fn claim_policy(&self) -> ClaimPolicy {
+        self.claim_policy.get().0.clone()
+    }
+
+    pub fn set_claim_policy(&mut self, policy: ClaimPolicy) -> Result<(), GeohashError> {
+        self.claim_policy.set(Cbor(policy)).map(|_| ()).map_err(|err| write_failed("claim policy", err))
+    }
+
+    pub fn claims_of(&self, principal: &Principal) -> u32 {
+        self.claim_counts.get(principal).unwrap_or(0)
+    }
+
+    /// Function to stop counting a claim of a principal, e.g. of a square it no longer owns
+    pub fn release_claim(&mut self, principal: &Principal) {
+        let claims = self.claims_of(principal);
+        self.claim_counts.insert(*principal, claims.saturating_sub(1));
+    }
+
+    /// Function to drop a pending mint and stop counting it towards the claims of its owner; returns the dropped mint
+    pub fn cancel_mint(&mut self, geohash: &str) -> Option<PendingMint> {
+        let pending = self.pending_mints.remove(&geohash.to_string())?.0;
+        self.release_claim(&pending.owner);
+        Some(pending)
+    }
+
+    pub fn reversion_policy(&self) -> ReversionPolicy {
+        self.reversion_policy.get().0.clone()
+    }
+
+    pub fn set_reversion_policy(&mut self, policy: ReversionPolicy) -> Result<(), GeohashError> {
+        self.reversion_policy.set(Cbor(policy)).map(|_| ()).map_err(|err| write_failed("reversion policy", err))
+    }
+
+    pub fn reversion_cursor(&self) -> Option<String> {
+        self.reversion_cursor.get().0.clone()
+    }
+
+    pub fn set_reversion_cursor(&mut self, cursor: Option<String>) -> Result<(), GeohashError> {
+        self.reversion_cursor.set(Cbor(cursor)).map(|_| ()).map_err(|err| write_failed("reversion cursor", err))
+    }
+
+    pub fn reputation(&self, principal: &Principal) -> Reputation {
+        self.reputations.get(principal).map_or_else(|| Reputation::new(*principal), |reputation| reputation.0)
+    }
+
+    /// Function to bring the stable memory to the current schema version.
+    /// Version 0 is a fresh memory (state was never persisted before version 1), newer versions than this code are rejected.
+    pub fn migrate(&mut self) -> Result<(), String> {
+        let version = self.config().schema_version;
+        if version == SCHEMA_VERSION {
+            return Ok(());
+        }
+        if version > SCHEMA_VERSION {
+            return Err(format!("Stable memory has schema version {}, this canister only supports up to {}", version, SCHEMA_VERSION));
+        }
+
+        // A fresh memory only needs the default metrics; migrations of later layouts go here, one step per version
+        self.register_default_metrics();
+        self.update_config(|config| config.schema_version = SCHEMA_VERSION).map_err(|err| format!("{:?}", err))
+    }
+
+    // Function to register the default metrics that are not registered yet
+    fn register_default_metrics(&mut self) {
+        for definition in default_metric_definitions() {
+            if !self.metric_definitions.contains_key(&definition.name) {
+                self.metric_definitions.insert(definition.name.clone(), Cbor(definition));
+            }
+        }
+    }
+
+    /// Function to append an observation of a square to the log and make it the latest value of its metric if it is the newest one.
+    /// Nothing is ever removed from the log, so its length numbers the observations.
+    pub fn record_observation(&mut self, geohash: &str, observation: MetricObservation) {
+        let sequence = self.observations.len();
+        let entry = ObservationEntry { value: observation.value.clone(), submitter: observation.submitter };
+        self.observations.insert(observation_key(geohash, &observation.metric, observation.observed_at, sequence), Cbor(entry));
+
+        let mut latest = self.latest_observations.get(&geohash.to_string()).map(|latest| latest.0).unwrap_or_default();
+        let newer = latest.get(&observation.metric).is_none_or(|current| current.observed_at <= observation.observed_at);
+        if newer {
+            latest.insert(observation.metric.clone(), observation);
+            self.latest_observations.insert(geohash.to_string(), Cbor(latest));
+        }
+    }
+
+    /// Function to store the rating of a square by a user, replacing their previous rating, and get the ratings of the square.
+    /// The rating has to be in the range of MIN_RATING to MAX_RATING.
+    pub fn rate_square(&mut self, geohash: &str, principal: &Principal, rating: u8) -> RatingSummary {
+        let mut distribution = self
+            .rating_distributions
+            .get(&geohash.to_string())
+            .map_or_else(|| vec![0; (MAX_RATING - MIN_RATING + 1) as usize], |distribution| distribution.0);
+        if let Some(previous) = self.ratings.insert(rating_key(geohash, principal), rating) {
+            distribution[(previous as u32 - MIN_RATING) as usize] -= 1;
+        }
+        distribution[(rating as u32 - MIN_RATING) as usize] += 1;
+
+        self.rating_distributions.insert(geohash.to_string(), Cbor(distribution.clone()));
+        rating_summary(&distribution)
+    }
+
+    /// Function to get the ratings of a square, None if nobody rated it
+    pub fn rating_summary(&self, geohash: &str) -> Option<RatingSummary> {
+        self.rating_distributions.get(&geohash.to_string()).map(|distribution| rating_summary(&distribution.0))
+    }
+
+    /// Function to publish the current data of a minted square as a new version of its document and point its name to it.
+    /// Nothing is published if the data did not change since the latest version. Returns the name of the square, None if
+    /// the square is not minted.
+    pub fn publish_document(&mut self, geohash: &str, now: u64) -> Option<DocumentPointer> {
+        let record = self.square_records.get(&geohash.to_string())?.0;
+        let head = self.document_names.get(&record.document_name).map(|head| head.0);
+        let document = SquareDocument {
+            geohash: geohash.to_string(),
+            version: head.as_ref().map_or(1, |head| head.version + 1),
+            previous: head.as_ref().map(|head| head.cid.clone()),
+            updated_at: now,
+            token_id: record.token_id,
+            owner: record.owner,
+            bitcoin_address: record.bitcoin_address,
+            ethereum_address: record.ethereum_address,
+            minted_at: record.minted_at,
+            metrics: self.latest_observations(geohash),
+            rating: self.rating_summary(geohash),
+        };
+
+        if let Some(head) = head {
+            let current = self.documents.get(&head.cid).map(|current| current.0);
+            if current.is_some_and(|current| same_content(&current, &document)) {
+                return Some(head);
+            }
+        }
+        let pointer = DocumentPointer {
+            name: record.document_name,
+            cid: cid_of_block(&encode_document(&document)),
+            version: document.version,
+            updated_at: now,
+        };
+        self.documents.insert(pointer.cid.clone(), Cbor(document));
+        self.document_names.insert(pointer.name.clone(), Cbor(pointer.clone()));
+        Some(pointer)
+    }
+
+    /// Function to store a minted square: the mapping to its token ID, its record and its first document, all in one step
+    /// so a square is never left half minted. Ends the pending mint of the square.
+    pub fn finish_mint(&mut self, geohash: &str, record: SquareRecord) -> Option<DocumentPointer> {
+        let minted_at = record.minted_at;
+        self.token_ids.insert(geohash.to_string(), record.token_id);
+        self.square_records.insert(geohash.to_string(), Cbor(record));
+        self.pending_mints.remove(&geohash.to_string());
+        self.publish_document(geohash, minted_at)
+    }
+
+    /// Function to append an event to the log of reversions; its length numbers the events
+    pub fn log_reversion_event(&mut self, geohash: &str, owner: Principal, kind: ReversionEventKind, at: u64) {
+        let sequence = self.reversion_events.len();
+        self.reversion_events.insert(sequence, Cbor(ReversionEvent { sequence, geohash: geohash.to_string(), owner, kind, at }));
+    }
+
+    /// Function to store that the NFT of a square was transferred back to the canister: the canister owns the square until
+    /// the next user claims it, and the claim no longer counts for its previous owner
+    pub fn revert_square(&mut self, geohash: &str, canister_id: Principal, transaction_id: u128, now: u64) {
+        let Some(Cbor(mut record)) = self.square_records.get(&geohash.to_string()) else {
+            return;
+        };
+        let previous_owner = record.owner;
+        record.owner = canister_id;
+        self.square_records.insert(geohash.to_string(), Cbor(record));
+        self.release_claim(&previous_owner);
+        self.square_reversions.remove(&geohash.to_string());
+        self.log_reversion_event(geohash, previous_owner, ReversionEventKind::Reverted { transaction_id }, now);
+        self.publish_document(geohash, now);
+    }
+
+    /// Function to store that the NFT of a square that had reverted was transferred to the user who claimed it; the claim
+    /// was counted when it was reserved
+    pub fn reclaim_square(&mut self, geohash: &str, owner: Principal, transaction_id: u128, now: u64) {
+        let Some(Cbor(mut record)) = self.square_records.get(&geohash.to_string()) else {
+            return;
+        };
+        record.owner = owner;
+        record.claimed_at = now;
+        self.square_records.insert(geohash.to_string(), Cbor(record));
+        self.owner_activity.insert(owner, now);
+        self.log_reversion_event(geohash, owner, ReversionEventKind::Reclaimed { transaction_id }, now);
+        self.publish_document(geohash, now);
+    }
+
+    /// Function to store that the owner of a square transferred its NFT outside the canister: the record follows the owner
+    /// in the DIP721 canister, and the claim moves to the new owner (unless it is the canister)
+    pub fn resync_owner(&mut self, geohash: &str, owner: Principal, canister_id: Principal, now: u64) {
+        let Some(Cbor(mut record)) = self.square_records.get(&geohash.to_string()) else {
+            return;
+        };
+        let previous_owner = record.owner;
+        record.owner = owner;
+        record.claimed_at = now;
+        self.square_records.insert(geohash.to_string(), Cbor(record));
+        self.release_claim(&previous_owner);
+        if owner != canister_id {
+            let claims = self.claims_of(&owner);
+            self.claim_counts.insert(owner, claims + 1);
+        }
+        self.square_reversions.remove(&geohash.to_string());
+        self.log_reversion_event(geohash, previous_owner, ReversionEventKind::OwnerChanged { new_owner: owner }, now);
+        self.publish_document(geohash, now);
+    }
+
+    /// Function to get the latest observation of every metric of a square
+    pub fn latest_observations(&self, geohash: &str) -> Vec<MetricObservation> {
+        self.latest_observations
+            .get(&geohash.to_string())
+            .map(|latest| latest.0.into_values().collect())
+            .unwrap_or_default()
+    }
+
+    /// Function to get the observations of a metric of a square made from a time until before another, oldest first
+    pub fn observation_history(&self, geohash: &str, metric: &str, from: u64, to: u64, limit: usize) -> Vec<MetricObservation> {
+        let prefix = observation_prefix(geohash, metric);
+        self.observations
+            .range(observation_key(geohash, metric, from, 0)..)
+            .map_while(|(key, entry)| {
+                let observed_at = observed_at_of_key(&prefix, &key).filter(|observed_at| *observed_at < to)?;
+                Some(MetricObservation { metric: metric.to_string(), value: entry.0.value, observed_at, submitter: entry.0.submitter })
+            })
+            .take(limit)
+            .collect()
+    }
+}
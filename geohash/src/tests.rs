@@ -1,28 +1,37 @@
 // SPDX-License: MIT
 // (C) 2024 Thomas Magerl
 
-use crate::{query_compute_geohash, Geolocation, AreaResponse};
+use crate::area_generator::{Area, calculate_area};
+use crate::grid_generator::{Resolution, validate_coordinate, create_grid_with_geohash, grid_rows, square_index, square_geohash, square_bounds, square_center, square_from_geohash, parent_square, child_squares, neighbour_squares, square_ring, MAX_RING_STEPS};
+use crate::grid_match::{find_nearest_geohash_with_bounds, distance_to_center, distance_between_centers, DEFAULT_RESOLUTION};
+use crate::geodesy::{haversine_distance, vincenty_distance, ground_distance};
+use crate::grid_coverage::{Region, cover_region, MAX_PAGE_SIZE};
+use crate::types::{InitArgs, GridError, Geolocation, AreaResponse, Nft, MetadataPartLookup, MetadataPurpose, MetadataKeyVal, MetadataVal, SquareRecord, SquareStatus, SquareInput, MetricDefinition, MetricError, MetricObservation, MetricValue, MetricValueType, AggregationPeriod, MetricAggregate, Oracle, OracleObservation, FeedConfig, FieldMapping, RefreshFailure, RefreshJobStatus, RatingSummary, PendingMint, ProvisioningState, ProvisioningStage, SquareProperties, Wallet, ClaimPolicy, ReversionPolicy, HealthThreshold, RiskReason, ReversionEventKind, SquareBalances, GeohashError, ContributionError, ContributionPolicy, ContributionStatus, Vote};
+use crate::square_code::{square_code, square_from_code};
+use crate::{validate_dependency_canisters, resolve_square, lookup_squares, lookup_geohash, lookup_area, update_square_record, get_token_id_by_geohash, record_metric_value, submit_oracle_batch, next_minted_squares, submit_user_contribution, vote_on_contribution, rate_minted_square, get_document_block, document_history, reserve_mint, reserve_reclaim, evaluate_squares, stuck_pending_mints, cancel_pending_mint, lookup_square, get_bitcoin_canister_id, ring_of, STATE};
+use crate::feed::{validate_feed, feed_url, extract_fields, json_to_metric_value, transform_response};
+use crate::contribution::validate_policy;
+use crate::document::{document_name, cid_of_block, encode_document};
+use crate::nft_mint::mint_idempotency_key;
+use crate::claim::validate_claim_policy;
+use crate::reversion::{validate_reversion_policy, risk_reasons, next_reversion, due_for_evaluation, due_for_reversion, EVALUATION_BATCH_SIZE};
+use crate::provisioning::{retry_delay, record_failure, due_for_retry, should_abandon, group_by_stage, MAX_MINT_ATTEMPTS};
+use crate::refresh::{validate_refresh_settings, record_batch, DEFAULT_REFRESH_BATCH_SIZE, MAX_REFRESH_BATCH_SIZE};
+use ic_cdk::api::management_canister::http_request::{HttpHeader, HttpResponse};
+use candid::{Nat, encode_one, decode_one};
+use crate::oracle::{OracleState, RateWindow, RATE_LIMIT_WINDOW_NANOS};
+use crate::metrics::{validate_definition, validate_value, default_metric_definitions, period_start, aggregate_series, RATING_METRIC};
+use crate::storage::{StableState, Cbor, SCHEMA_VERSION};
+use ic_stable_structures::DefaultMemoryImpl;
+use candid::Principal;
 use rand::Rng;
-use std::fmt::{Debug, Formatter, Result};
-
-// Ensure AreaResponse implements Debug trait for debugging
-impl Debug for AreaResponse {
-    fn fmt(&self, f: &mut Formatter<'_>) -> Result {
-        f.debug_struct("AreaResponse")
-            .field("lat_start", &self.lat_start)
-            .field("lon_start", &self.lon_start)
-            .field("lat_end", &self.lat_end)
-            .field("lon_end", &self.lon_end)
-            .field("geohash", &self.geohash)
-            .finish()
-    }
-}
+use std::collections::HashMap;
 
 // Function to generate 100 geolocations spread across the globe
 fn generate_test_geolocations() -> Vec<((f64, f64), usize)> {
     let mut geolocations = Vec::new();
     let mut rng = rand::thread_rng();
-    
+
     for i in 0..10 {
         for j in 0..10 {
             let lat = -90.0 + i as f64 * 18.0 + rng.gen_range(0.0..18.0);  // Randomize within each 18-degree segment
@@ -34,7 +43,25 @@ fn generate_test_geolocations() -> Vec<((f64, f64), usize)> {
     geolocations
 }
 
-fn validate_geolocation_in_area(lat: f64, lon: f64, area: &AreaResponse, test_case_number: usize) {
+// Helper function to get the principal of a test user
+fn user(number: u8) -> Principal {
+    Principal::from_slice(&[1, number])
+}
+
+// Helper function to build the record of a minted square; tests override the fields they depend on
+fn square_record(owner: Principal, token_id: u64) -> SquareRecord {
+    SquareRecord {
+        token_id,
+        owner,
+        bitcoin_address: "bitcoin-address".to_string(),
+        ethereum_address: "ethereum-address".to_string(),
+        document_name: String::new(),
+        minted_at: 0,
+        claimed_at: 0,
+    }
+}
+
+fn validate_geolocation_in_area(lat: f64, lon: f64, area: &Area, test_case_number: usize) {
     println!("Test case {}: Latitude = {}, Area.lat_start = {}, Area.lat_end = {}", test_case_number, lat, area.lat_start, area.lat_end);
     println!("Test case {}: Longitude = {}, Area.lon_start = {}, Area.lon_end = {}", test_case_number, lon, area.lon_start, area.lon_end);
     assert!(lat >= area.lat_start && lat <= area.lat_end, "Test case {}: Latitude is not within the area bounds", test_case_number);
@@ -44,11 +71,1413 @@ fn validate_geolocation_in_area(lat: f64, lon: f64, area: &AreaResponse, test_ca
 #[test]
 fn test_geolocation_within_square() {
     let test_geolocations = generate_test_geolocations();
-    
+
+    for &((lat, lon), test_case_number) in &test_geolocations {
+        let (geohash, area) = find_nearest_geohash_with_bounds(lat, lon, DEFAULT_RESOLUTION);
+        println!("Test case {}: Geolocation = ({}, {}), Geohash = {}, Area = {:?}", test_case_number, lat, lon, geohash, area);
+        validate_geolocation_in_area(lat, lon, &area, test_case_number);
+    }
+}
+
+#[test]
+fn test_square_lookup_is_stable() {
+    let test_geolocations = generate_test_geolocations();
+
+    for &((lat, lon), test_case_number) in &test_geolocations {
+        let (first_geohash, _) = find_nearest_geohash_with_bounds(lat, lon, DEFAULT_RESOLUTION);
+        let (second_geohash, _) = find_nearest_geohash_with_bounds(lat, lon, DEFAULT_RESOLUTION);
+        assert_eq!(first_geohash, second_geohash, "Test case {}: Repeated lookups returned different squares", test_case_number);
+
+        // The center of a square must map back to the same square
+        let (_, area) = find_nearest_geohash_with_bounds(lat, lon, DEFAULT_RESOLUTION);
+        let center_lat = (area.lat_start + area.lat_end) / 2.0;
+        let center_lon = (area.lon_start + area.lon_end) / 2.0;
+        let (center_geohash, _) = find_nearest_geohash_with_bounds(center_lat, center_lon, DEFAULT_RESOLUTION);
+        assert_eq!(first_geohash, center_geohash, "Test case {}: Square center maps to a different square", test_case_number);
+    }
+}
+
+#[test]
+fn test_square_lookup_near_area_edges() {
+    // Points just inside the edges of a 0.03 degree area must still land in a square that contains them
+    let grid_spacing: f64 = 0.03;
+    let epsilon = 1e-9;
+
+    for (test_case_number, &(lat, lon)) in [(48.15, 11.58), (-33.87, 151.2), (0.0, 0.0), (60.0, -150.0)].iter().enumerate() {
+        let lower_lat = (lat / grid_spacing).floor() * grid_spacing;
+        let lower_lon = (lon / grid_spacing).floor() * grid_spacing;
+
+        for &(edge_lat, edge_lon) in &[
+            (lower_lat + epsilon, lower_lon + epsilon),
+            (lower_lat - epsilon, lower_lon - epsilon),
+            (lower_lat + grid_spacing - epsilon, lower_lon + grid_spacing - epsilon),
+        ] {
+            let (_, area) = find_nearest_geohash_with_bounds(edge_lat, edge_lon, DEFAULT_RESOLUTION);
+            validate_geolocation_in_area(edge_lat, edge_lon, &area, test_case_number + 1);
+        }
+    }
+}
+
+// Function to check if a point lies inside a square (squares are half-open, the poles and the antimeridian belong to the outermost squares)
+fn square_contains(area: &Area, lat: f64, lon: f64) -> bool {
+    let lat_inside = lat >= area.lat_start && (lat < area.lat_end || (area.lat_end == 90.0 && lat == 90.0));
+    let lon_inside = lon >= area.lon_start && (lon < area.lon_end || (area.lon_end == 180.0 && lon == 180.0));
+    lat_inside && lon_inside
+}
+
+// Function to calculate a 0.03 degree block around a location, which is not aligned with the square grid
+fn block_around(lat: f64, lon: f64) -> Area {
+    let grid_spacing = 0.03;
+    let lat_start = (lat / grid_spacing).floor() * grid_spacing;
+    let lon_start = (lon / grid_spacing).floor() * grid_spacing;
+
+    Area {
+        lat_start,
+        lon_start,
+        lat_end: lat_start + grid_spacing,
+        lon_end: lon_start + grid_spacing,
+    }
+}
+
+#[test]
+fn test_squares_are_unique_across_area_seams() {
+    let mut rng = rand::thread_rng();
+
+    for test_case_number in 1..=200 {
+        let lat: f64 = rng.gen_range(-85.0..85.0);
+        let lon: f64 = rng.gen_range(-179.0..179.0);
+
+        // Take a 0.03 degree block and its neighbours to the east and to the north
+        let area = block_around(lat, lon);
+        let east = block_around(lat, area.lon_end + 0.001);
+        let north = block_around(area.lat_end + 0.001, lon);
+
+        // Squares generated for neighbouring areas must agree on the bounds of every shared geohash
+        let mut squares: HashMap<String, Area> = HashMap::new();
+        for block in [&area, &east, &north] {
+            for (_, _, geohash, bounds) in create_grid_with_geohash(block.lat_start, block.lon_start, block.lat_end, block.lon_end, DEFAULT_RESOLUTION) {
+                match squares.get(&geohash) {
+                    Some(existing) => assert_eq!(existing, &bounds, "Test case {}: Geohash {} has two different bounds", test_case_number, geohash),
+                    None => {
+                        squares.insert(geohash, bounds);
+                    }
+                }
+            }
+        }
+
+        // Walk along both seams: every point must be covered by exactly one square, the one returned by the lookup
+        for step in 0..50 {
+            let t = step as f64 / 50.0;
+            let seam_points = [
+                (area.lat_start + t * (area.lat_end - area.lat_start), east.lon_start),
+                (area.lat_start + t * (area.lat_end - area.lat_start), east.lon_start - 1e-9),
+                (north.lat_start, area.lon_start + t * (area.lon_end - area.lon_start)),
+                (north.lat_start - 1e-9, area.lon_start + t * (area.lon_end - area.lon_start)),
+            ];
+
+            for &(point_lat, point_lon) in &seam_points {
+                let containing: Vec<&String> = squares
+                    .iter()
+                    .filter(|(_, bounds)| square_contains(bounds, point_lat, point_lon))
+                    .map(|(geohash, _)| geohash)
+                    .collect();
+                assert_eq!(containing.len(), 1, "Test case {}: Point ({}, {}) is covered by {} squares", test_case_number, point_lat, point_lon, containing.len());
+
+                let (geohash, _) = find_nearest_geohash_with_bounds(point_lat, point_lon, DEFAULT_RESOLUTION);
+                assert_eq!(containing[0], &geohash, "Test case {}: Lookup for ({}, {}) disagrees with the grid", test_case_number, point_lat, point_lon);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_squares_cover_the_globe() {
+    // Walk every meridian line from pole to pole, including the poles and the antimeridian
+    for lon_step in 0..=72 {
+        let lon = -180.0 + lon_step as f64 * 5.0;
+        for lat_step in 0..=360 {
+            let lat = -90.0 + lat_step as f64 * 0.5;
+            let (geohash, area) = find_nearest_geohash_with_bounds(lat, lon, DEFAULT_RESOLUTION);
+            assert!(square_contains(&area, lat, lon) || (lon == 180.0 && square_contains(&area, lat, -180.0)), "Point ({}, {}) is not covered by square {} {:?}", lat, lon, geohash, area);
+        }
+    }
+
+    // Distinct squares must have distinct geohashes
+    let mut indices_by_geohash = HashMap::new();
+    for (_, _, geohash, bounds) in create_grid_with_geohash(47.9, 11.3, 48.3, 11.9, DEFAULT_RESOLUTION) {
+        let center_lat = (bounds.lat_start + bounds.lat_end) / 2.0;
+        let center_lon = (bounds.lon_start + bounds.lon_end) / 2.0;
+        let index = square_index(center_lat, center_lon, DEFAULT_RESOLUTION);
+        assert!(indices_by_geohash.insert(geohash.clone(), index).is_none(), "Geohash {} was generated twice", geohash);
+    }
+}
+
+#[test]
+fn test_square_hierarchy() {
+    let test_geolocations = generate_test_geolocations();
+
     for &((lat, lon), test_case_number) in &test_geolocations {
-        let geolocation = Geolocation { latitude: lat, longitude: lon };
-        let area_response: AreaResponse = query_compute_geohash(geolocation);
-        println!("Test case {}: Geolocation = ({}, {}), Area = {:?}", test_case_number, lat, lon, area_response);
-        validate_geolocation_in_area(lat, lon, &area_response, test_case_number);
+        for &resolution in Resolution::ALL.iter() {
+            let index = square_index(lat, lon, resolution);
+            let area = calculate_area(lat, lon, resolution);
+            assert_eq!(square_bounds(index), area, "Test case {}: Area and square bounds differ", test_case_number);
+
+            // The geohash of a square identifies both the square and its resolution
+            assert_eq!(square_from_geohash(&square_geohash(index)), Some(index), "Test case {}: Geohash does not map back to its square", test_case_number);
+
+            // The parent square contains the point and the square
+            if let Some(parent) = parent_square(index) {
+                assert_eq!(parent, square_index(lat, lon, parent.resolution), "Test case {}: Parent does not contain the point", test_case_number);
+                let parent_area = square_bounds(parent);
+                assert!(parent_area.lat_start <= area.lat_start && area.lat_end <= parent_area.lat_end, "Test case {}: Square sticks out of its parent", test_case_number);
+                assert!(parent_area.lon_start <= area.lon_start && area.lon_end <= parent_area.lon_end, "Test case {}: Square sticks out of its parent", test_case_number);
+            } else {
+                assert_eq!(resolution, Resolution::Meters4000);
+            }
+
+            // The children tile the square exactly and one of them contains the point
+            let children = child_squares(index);
+            if let Some(child_resolution) = resolution.child() {
+                assert_eq!(children.len(), 4);
+                assert!(children.contains(&square_index(lat, lon, child_resolution)), "Test case {}: No child contains the point", test_case_number);
+                for child in &children {
+                    assert_eq!(parent_square(*child), Some(index), "Test case {}: Child has a different parent", test_case_number);
+                }
+                let child_areas: Vec<Area> = children.iter().map(|child| square_bounds(*child)).collect();
+                assert_eq!(child_areas[0].lat_start, area.lat_start);
+                assert_eq!(child_areas[0].lon_start, area.lon_start);
+                assert_eq!(child_areas[3].lat_end, area.lat_end);
+                assert_eq!(child_areas[3].lon_end, area.lon_end);
+                assert_eq!(child_areas[0].lat_end, child_areas[3].lat_start);
+                assert_eq!(child_areas[0].lon_end, child_areas[3].lon_start);
+            } else {
+                assert!(children.is_empty());
+            }
+        }
+    }
+}
+
+// Function to check if two squares touch at an edge or a corner (longitudes may wrap around the antimeridian)
+fn squares_touch(first: &Area, second: &Area) -> bool {
+    let epsilon = 1e-9;
+    let lat_touch = first.lat_start <= second.lat_end + epsilon && second.lat_start <= first.lat_end + epsilon;
+    let lon_touch = [-360.0, 0.0, 360.0].iter().any(|shift| {
+        first.lon_start <= second.lon_end + shift + epsilon && second.lon_start + shift <= first.lon_end + epsilon
+    });
+    lat_touch && lon_touch
+}
+
+#[test]
+fn test_square_neighbours() {
+    // Regular squares, squares at the antimeridian, at the equator and at a seam between two 4 km bands
+    let seam_lat = square_bounds(square_index(48.137, 11.575, Resolution::Meters4000)).lat_start;
+    let test_locations = [(48.137, 11.575), (-33.868, 151.209), (0.0001, 179.9999), (-16.5, -179.9999), (64.1466, -21.9426), (seam_lat + 1e-7, 11.575)];
+
+    for (test_case_number, &(lat, lon)) in test_locations.iter().enumerate() {
+        let index = square_index(lat, lon, DEFAULT_RESOLUTION);
+        let area = square_bounds(index);
+        let neighbours = neighbour_squares(index);
+
+        assert!((7..=10).contains(&neighbours.len()), "Test case {}: Unexpected number of neighbours: {}", test_case_number + 1, neighbours.len());
+        assert!(!neighbours.contains(&index), "Test case {}: A square is not its own neighbour", test_case_number + 1);
+        for neighbour in &neighbours {
+            assert!(squares_touch(&area, &square_bounds(*neighbour)), "Test case {}: Neighbour {:?} does not touch {:?}", test_case_number + 1, neighbour, index);
+            assert!(neighbour_squares(*neighbour).contains(&index), "Test case {}: Neighbourhood of {:?} is not symmetric", test_case_number + 1, neighbour);
+        }
+
+        // The first ring consists of the square and its neighbours
+        let ring = square_ring(index, 1);
+        assert_eq!(ring[0], index);
+        assert_eq!(ring.len(), neighbours.len() + 1);
+        assert!(neighbours.iter().all(|neighbour| ring.contains(neighbour)));
+
+        // Every ring contains the previous one
+        let ring = square_ring(index, 3);
+        assert!((40..=60).contains(&ring.len()), "Test case {}: Unexpected number of squares in the ring: {}", test_case_number + 1, ring.len());
+        assert!(square_ring(index, 2).iter().all(|square| ring.contains(square)));
+    }
+
+    // Squares inside a 4 km band have exactly 8 neighbours
+    let band = square_bounds(square_index(48.137, 11.575, Resolution::Meters4000));
+    let index = square_index((band.lat_start + band.lat_end) / 2.0, 11.575, DEFAULT_RESOLUTION);
+    assert_eq!(neighbour_squares(index).len(), 8);
+    assert_eq!(square_ring(index, 1).len(), 9);
+}
+
+#[test]
+fn test_square_ring_at_the_poles() {
+    for &lat in &[90.0, -90.0] {
+        let index = square_index(lat, 0.0, Resolution::Meters4000);
+        let ring = square_ring(index, 2);
+
+        // Squares never repeat, even if the ring wraps around the pole
+        for (position, square) in ring.iter().enumerate() {
+            assert!(!ring[position + 1..].contains(square), "Square {:?} appears twice in the ring", square);
+        }
+        for neighbour in neighbour_squares(index) {
+            assert!(squares_touch(&square_bounds(index), &square_bounds(neighbour)));
+        }
+    }
+
+    // Rings larger than the cap are rejected instead of being cut short
+    let geohash = square_geohash(square_index(48.137, 11.575, DEFAULT_RESOLUTION));
+    assert_eq!(ring_of(geohash.clone(), MAX_RING_STEPS).unwrap().len(), square_ring(square_from_geohash(&geohash).unwrap(), MAX_RING_STEPS).len());
+    assert!(matches!(ring_of(geohash, MAX_RING_STEPS + 1), Err(GeohashError::InvalidInput(_))));
+}
+
+#[test]
+fn test_squares_in_bounds_are_paged() {
+    let bounds = Area { lat_start: 48.10, lon_start: 11.50, lat_end: 48.20, lon_end: 11.65 };
+    let expected: Vec<String> = create_grid_with_geohash(bounds.lat_start, bounds.lon_start, bounds.lat_end, bounds.lon_end, DEFAULT_RESOLUTION)
+        .into_iter()
+        .map(|(_, _, geohash, _)| geohash)
+        .collect();
+
+    // Walking all pages yields every square of the grid exactly once, in grid order
+    let mut paged = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let page = cover_region(&bounds, DEFAULT_RESOLUTION, None, cursor, 37).unwrap();
+        assert_eq!(page.total_candidates, expected.len() as u64);
+        assert!(page.squares.len() <= 37);
+        paged.extend(page.squares.iter().map(|index| square_geohash(*index)));
+        match page.next_cursor {
+            Some(next_cursor) => cursor = next_cursor,
+            None => break,
+        }
+    }
+    assert_eq!(paged, expected);
+
+    // Page sizes are capped
+    let page = cover_region(&bounds, DEFAULT_RESOLUTION, None, 0, 100_000).unwrap();
+    assert_eq!(page.squares.len(), MAX_PAGE_SIZE.min(expected.len() as u32) as usize);
+
+    // Regions that are too large for the resolution and inverted bounds are rejected
+    let europe = Area { lat_start: 35.0, lon_start: -10.0, lat_end: 70.0, lon_end: 40.0 };
+    assert!(cover_region(&europe, Resolution::Meters250, None, 0, 100).is_err());
+    assert!(cover_region(&europe, Resolution::Meters4000, None, 0, 100).is_ok());
+    let inverted = Area { lat_start: 48.2, lon_start: 11.5, lat_end: 48.1, lon_end: 11.6 };
+    assert!(cover_region(&inverted, DEFAULT_RESOLUTION, None, 0, 100).is_err());
+}
+
+#[test]
+fn test_squares_in_polygon() {
+    // A triangle with a hole near its south-western corner
+    let geojson = r#"{
+        "type": "Feature",
+        "properties": {},
+        "geometry": {
+            "type": "Polygon",
+            "coordinates": [
+                [[11.50, 48.10], [11.65, 48.10], [11.50, 48.20], [11.50, 48.10]],
+                [[11.51, 48.11], [11.53, 48.11], [11.53, 48.13], [11.51, 48.13], [11.51, 48.11]]
+            ]
+        }
+    }"#;
+    let region = Region::from_geojson(geojson).unwrap();
+    let bounds = region.bounds();
+    assert_eq!(bounds, Area { lat_start: 48.10, lon_start: 11.50, lat_end: 48.20, lon_end: 11.65 });
+
+    let mut squares = Vec::new();
+    let mut cursor = 0;
+    loop {
+        let page = cover_region(&bounds, DEFAULT_RESOLUTION, Some(&region), cursor, 50).unwrap();
+        squares.extend(page.squares);
+        match page.next_cursor {
+            Some(next_cursor) => cursor = next_cursor,
+            None => break,
+        }
+    }
+
+    // Squares at the corners are covered, the square in the far corner of the bounding box and inside the hole are not
+    for &(lat, lon) in &[(48.1001, 11.5001), (48.1001, 11.6499), (48.1999, 11.5001), (48.14, 11.54)] {
+        assert!(squares.contains(&square_index(lat, lon, DEFAULT_RESOLUTION)), "Square at ({}, {}) is missing", lat, lon);
+    }
+    for &(lat, lon) in &[(48.1999, 11.6499), (48.12, 11.52)] {
+        assert!(!squares.contains(&square_index(lat, lon, DEFAULT_RESOLUTION)), "Square at ({}, {}) must not be covered", lat, lon);
+    }
+
+    // The polygon covers roughly half of its bounding box
+    let all = create_grid_with_geohash(bounds.lat_start, bounds.lon_start, bounds.lat_end, bounds.lon_end, DEFAULT_RESOLUTION).len();
+    assert!(squares.len() > all / 3 && squares.len() < all * 2 / 3, "{} of {} squares covered", squares.len(), all);
+
+    // Invalid GeoJSON is rejected
+    assert!(Region::from_geojson("not json").is_err());
+    assert!(Region::from_geojson(r#"{"type": "Point", "coordinates": [11.5, 48.1]}"#).is_err());
+    assert!(Region::from_geojson(r#"{"type": "Polygon", "coordinates": [[[11.5, 48.1], [11.6, 48.1]]]}"#).is_err());
+    assert!(Region::from_geojson(r#"{"type": "Polygon", "coordinates": [[[11.5, 98.1], [11.6, 48.1], [11.6, 48.2]]]}"#).is_err());
+
+    // Regions with many vertices scan fewer candidate squares per page
+    let circle: Vec<String> = (0..4_995)
+        .map(|step| {
+            let angle = step as f64 / 4_995.0 * std::f64::consts::TAU;
+            format!("[{}, {}]", 11.0 + 0.001 * angle.cos(), 48.0 + 0.001 * angle.sin())
+        })
+        .collect();
+    let geojson = format!(
+        r#"{{"type": "MultiPolygon", "coordinates": [[[{}]], [[[12.0, 49.0], [12.001, 49.0], [12.001, 49.001], [12.0, 49.0]]]]}}"#,
+        circle.join(", ")
+    );
+    let region = Region::from_geojson(&geojson).unwrap();
+    let page = cover_region(&region.bounds(), DEFAULT_RESOLUTION, Some(&region), 0, MAX_PAGE_SIZE).unwrap();
+    assert_eq!(page.next_cursor, Some(1_000));
+}
+
+#[test]
+fn test_squares_in_polygons_across_the_antimeridian() {
+    // Polygons crossing the antimeridian are rejected, they have to be split at 180 degrees
+    let crossing = r#"{"type": "Polygon", "coordinates": [[[179.9, -0.05], [-179.9, -0.05], [-179.9, 0.05], [179.9, 0.05], [179.9, -0.05]]]}"#;
+    assert!(matches!(Region::from_geojson(crossing), Err(GridError::InvalidRegion(_))));
+
+    // The halves of a split polygon get bounds crossing the antimeridian instead of bounds around the globe
+    let split = r#"{"type": "MultiPolygon", "coordinates": [
+        [[[179.9, -0.05], [180.0, -0.05], [180.0, 0.05], [179.9, 0.05], [179.9, -0.05]]],
+        [[[-180.0, -0.05], [-179.9, -0.05], [-179.9, 0.05], [-180.0, 0.05], [-180.0, -0.05]]]
+    ]}"#;
+    let region = Region::from_geojson(split).unwrap();
+    let bounds = region.bounds();
+    assert_eq!(bounds, Area { lat_start: -0.05, lon_start: 179.9, lat_end: 0.05, lon_end: -179.9 });
+    let mut squares = Vec::new();
+    let mut cursor = Some(0);
+    while let Some(next) = cursor {
+        let page = cover_region(&bounds, DEFAULT_RESOLUTION, Some(&region), next, MAX_PAGE_SIZE).unwrap();
+        assert_eq!(page.total_candidates, 1_104);
+        squares.extend(page.squares);
+        cursor = page.next_cursor;
+    }
+    assert_eq!(squares.len(), 1_104);
+    for &lon in &[179.95, -179.95] {
+        assert!(squares.contains(&square_index(0.0, lon, DEFAULT_RESOLUTION)));
+    }
+
+    // Bands around the whole globe run from one side of the map to the other
+    let band = r#"{"type": "Polygon", "coordinates": [[[-180.0, -0.05], [180.0, -0.05], [180.0, 0.05], [-180.0, 0.05], [-180.0, -0.05]]]}"#;
+    let bounds = Region::from_geojson(band).unwrap().bounds();
+    assert_eq!((bounds.lon_start, bounds.lon_end), (-180.0, 180.0));
+}
+
+#[test]
+fn test_coordinate_validation() {
+    // Coordinates on the edges of the globe are valid
+    for &(lat, lon) in &[(90.0, 0.0), (-90.0, 0.0), (0.0, 180.0), (0.0, -180.0), (0.0, 0.0), (90.0, 180.0)] {
+        assert_eq!(validate_coordinate(lat, lon), Ok(()));
+    }
+
+    // Coordinates off the globe and non-finite values are rejected
+    assert_eq!(validate_coordinate(90.0001, 0.0), Err(GridError::InvalidLatitude(90.0001)));
+    assert_eq!(validate_coordinate(-90.0001, 0.0), Err(GridError::InvalidLatitude(-90.0001)));
+    assert_eq!(validate_coordinate(0.0, -180.0001), Err(GridError::InvalidLongitude(-180.0001)));
+    assert_eq!(validate_coordinate(0.0, 180.0001), Err(GridError::InvalidLongitude(180.0001)));
+    assert!(matches!(validate_coordinate(f64::NAN, 0.0), Err(GridError::InvalidLatitude(_))));
+    assert!(matches!(validate_coordinate(0.0, f64::NAN), Err(GridError::InvalidLongitude(_))));
+    assert!(matches!(validate_coordinate(0.0, f64::INFINITY), Err(GridError::InvalidLongitude(_))));
+}
+
+#[test]
+fn test_squares_at_the_poles_and_the_antimeridian() {
+    for resolution in Resolution::ALL {
+        // Squares containing the poles and the antimeridian never leave the globe
+        for &(lat, lon) in &[(90.0, 0.0), (-90.0, 0.0), (89.9999, 45.0), (-89.9999, -45.0), (0.0, 0.0), (0.0, 180.0), (0.0, -180.0), (45.0, 179.9999), (-45.0, -179.9999)] {
+            let area = calculate_area(lat, lon, resolution);
+            assert!(area.lat_start >= -90.0 && area.lat_end <= 90.0, "Square {:?} leaves the globe", area);
+            assert!(area.lon_start >= -180.0 && area.lon_end <= 180.0, "Square {:?} leaves the globe", area);
+            assert!(square_contains(&area, lat, if lon == 180.0 { -180.0 } else { lon }));
+        }
+
+        // 180 and -180 are the same meridian
+        assert_eq!(square_index(10.0, 180.0, resolution), square_index(10.0, -180.0, resolution));
+    }
+
+    // Each pole is covered by a single square at the coarsest resolution
+    for &lat in &[90.0, -90.0] {
+        let pole = square_index(lat, 0.0, Resolution::Meters4000);
+        for &lon in &[-180.0, -90.0, 45.0, 179.999] {
+            assert_eq!(square_index(lat * 0.9999, lon, Resolution::Meters4000), pole);
+        }
+        let bounds = square_bounds(pole);
+        assert_eq!((bounds.lon_start, bounds.lon_end), (-180.0, 180.0));
     }
+
+    // Squares on both sides of the antimeridian are neighbours
+    let east = square_index(0.0, 179.9999, DEFAULT_RESOLUTION);
+    let west = square_index(0.0, -180.0, DEFAULT_RESOLUTION);
+    assert!(neighbour_squares(east).contains(&west));
+    assert!(neighbour_squares(west).contains(&east));
+}
+
+#[test]
+fn test_squares_in_bounds_across_the_antimeridian() {
+    let geohashes = |lon_start: f64, lon_end: f64| -> Vec<String> {
+        create_grid_with_geohash(-0.01, lon_start, 0.01, lon_end, DEFAULT_RESOLUTION)
+            .into_iter()
+            .map(|(_, _, geohash, _)| geohash)
+            .collect()
+    };
+
+    // Bounds crossing the antimeridian cover the squares on both sides of it
+    let bounds = Area { lat_start: -0.01, lon_start: 179.99, lat_end: 0.01, lon_end: -179.99 };
+    let page = cover_region(&bounds, DEFAULT_RESOLUTION, None, 0, MAX_PAGE_SIZE).unwrap();
+    assert_eq!(page.next_cursor, None);
+
+    let mut covered: Vec<String> = page.squares.iter().map(|index| square_geohash(*index)).collect();
+    let mut expected = [geohashes(179.99, 180.0), geohashes(-180.0, -179.99)].concat();
+    covered.sort();
+    expected.sort();
+    assert_eq!(covered, expected);
+
+    // Bounds wrapping almost all the way around cover the whole band, bounds ending at 180 stop there
+    let band = Area { lat_start: -0.05, lon_start: 10.0, lat_end: 0.05, lon_end: 9.99999 };
+    let whole = Area { lat_start: -0.05, lon_start: -180.0, lat_end: 0.05, lon_end: 180.0 };
+    let band_total = cover_region(&band, Resolution::Meters4000, None, 0, 1).unwrap().total_candidates;
+    assert_eq!(band_total, cover_region(&whole, Resolution::Meters4000, None, 0, 1).unwrap().total_candidates);
+
+    // Bounds starting and ending at 180 degrees select the last square of each row instead of the whole row
+    let rows = grid_rows(-0.01, 180.0, 0.01, 180.0, DEFAULT_RESOLUTION);
+    assert!(rows.iter().all(|row| row.count == 1));
+    assert_eq!(geohashes(180.0, 180.0), geohashes(179.9999, 180.0));
+}
+
+#[test]
+fn test_ground_distances() {
+    // Spherical and ellipsoidal distances agree with reference values
+    let paris_london = haversine_distance(48.8566, 2.3522, 51.5074, -0.1278);
+    assert!((paris_london - 343_500.0).abs() < 1_000.0, "Paris to London is {} m", paris_london);
+    let flinders_peak_buninyong = vincenty_distance(-37.951_033_417, 144.424_867_889, -37.652_821_139, 143.926_495_528).unwrap();
+    assert!((flinders_peak_buninyong - 54_972.271).abs() < 0.01, "Flinders Peak to Buninyong is {} m", flinders_peak_buninyong);
+    assert!((ground_distance(0.0, 0.0, 0.0, 1.0) - 111_319.491).abs() < 0.01);
+    assert_eq!(ground_distance(48.137, 11.575, 48.137, 11.575), 0.0);
+
+    // Nearly antipodal points fall back to the spherical distance
+    assert_eq!(vincenty_distance(0.0, 0.0, 0.5, 179.7), None);
+    assert_eq!(ground_distance(0.0, 0.0, 0.5, 179.7), haversine_distance(0.0, 0.0, 0.5, 179.7));
+
+    // Neighbouring squares are one square apart at every latitude, including high latitudes
+    for &lat in &[0.0, 45.0, -60.0, 80.0] {
+        let index = square_index(lat, 11.575, DEFAULT_RESOLUTION);
+        let bounds = square_bounds(index);
+        let east = square_index(lat, bounds.lon_end, DEFAULT_RESOLUTION);
+        let north = square_index(bounds.lat_end, 11.575, DEFAULT_RESOLUTION);
+        for neighbour in [east, north] {
+            let distance = distance_between_centers(index, neighbour);
+            assert!((distance - 500.0).abs() < 25.0, "Squares at latitude {} are {} m apart", lat, distance);
+        }
+
+        // A location is never further from the center of its square than half the diagonal
+        let (center_lat, center_lon) = square_center(index);
+        assert!(distance_to_center(bounds.lat_start, bounds.lon_start, index) < 370.0);
+        assert_eq!(distance_to_center(center_lat, center_lon, index), 0.0);
+    }
+}
+
+#[test]
+fn test_square_lookup_does_not_mint() {
+    let munich = Geolocation { latitude: 48.137, longitude: 11.575 };
+
+    // Unminted squares are reported as such and looking them up does not mint them
+    let lookup = lookup_geohash(munich).unwrap();
+    assert_eq!(lookup.status, SquareStatus::Unminted);
+    assert_eq!(lookup.real_time_metrics, None);
+    assert_eq!(lookup_area(lookup.geohash.clone()).unwrap(), lookup);
+    assert_eq!(get_token_id_by_geohash(&lookup.geohash), None);
+
+    // Minted squares come with their cached record and metrics
+    let record = SquareRecord { document_name: document_name(&lookup.geohash), minted_at: 1_700_000_000_000_000_000, claimed_at: 1_700_000_000_000_000_000, ..square_record(Principal::anonymous(), 7) };
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+    update_square_record(lookup.geohash.clone(), record.clone());
+    record_metric_value(&lookup.geohash, "Air quality index", MetricValue::Nat(42), record.minted_at, record.owner).unwrap();
+    rate_minted_square(user(1), &lookup.geohash, 5, record.minted_at).unwrap();
+
+    let minted = lookup_area(lookup.geohash.clone()).unwrap();
+    assert_eq!(minted.status, SquareStatus::Minted(record.clone()));
+    assert_eq!(minted.real_time_metrics, Some(HashMap::from([("Air quality index".to_string(), MetricValue::Nat(42))])));
+    assert_eq!(minted.rating.map(|rating| (rating.count, rating.mean)), Some((1, 5.0)));
+
+    // Ratings are given per user, not recorded as an observation
+    assert_eq!(
+        record_metric_value(&lookup.geohash, RATING_METRIC, MetricValue::Nat(5), record.minted_at, user(1)),
+        Err(MetricError::UnknownMetric(RATING_METRIC.to_string()))
+    );
+
+    // Invalid input is rejected
+    assert!(lookup_area("not a geohash!".to_string()).is_err());
+    assert!(lookup_geohash(Geolocation { latitude: 91.0, longitude: 0.0 }).is_err());
+}
+
+#[test]
+fn test_area_response_candid_round_trip() {
+    // The typed response survives Candid encoding, including the NFT, the typed metrics and the rating
+    let response = AreaResponse {
+        lat_start: 48.13,
+        lon_start: 11.57,
+        lat_end: 48.14,
+        lon_end: 11.58,
+        geohash: "u281z7j5e".to_string(),
+        nft_square: Some(Nft {
+            owner: user(1),
+            token_id: 7,
+            metadata: vec![MetadataPartLookup {
+                purpose: MetadataPurpose::Rendered,
+                key_val_data: vec![
+                    MetadataKeyVal { key: "bitcoin_address".to_string(), val: MetadataVal::TextContent("bitcoin-address".to_string()) },
+                    MetadataKeyVal { key: "version".to_string(), val: MetadataVal::Nat64Content(2) },
+                ],
+                data: vec![1, 2, 3],
+            }],
+            content: vec![4, 5],
+        }),
+        bitcoin_balance: 1_000,
+        ethereum_balance: 0,
+        real_time_metrics: Some(HashMap::from([
+            ("Air quality index".to_string(), MetricValue::Nat(42)),
+            ("Crime rate".to_string(), MetricValue::Float(1.5)),
+            ("Flooded".to_string(), MetricValue::Bool(false)),
+        ])),
+        rating: Some(RatingSummary { count: 2, mean: 7.5, distribution: vec![0, 0, 0, 0, 0, 0, 1, 1, 0, 0] }),
+        created: true,
+    };
+    let bytes = encode_one(&response).unwrap();
+    assert_eq!(decode_one::<AreaResponse>(&bytes).unwrap(), response);
+
+    // Squares without an NFT, metrics or ratings encode their optional fields as empty
+    let unminted = AreaResponse { nft_square: None, real_time_metrics: None, rating: None, created: false, ..response };
+    assert_eq!(decode_one::<AreaResponse>(&encode_one(&unminted).unwrap()).unwrap(), unminted);
+}
+
+#[test]
+fn test_square_codes() {
+    let mut rng = rand::thread_rng();
+    let mut codes = HashMap::new();
+
+    // Codes round-trip at every resolution, including the edges of the grid
+    for resolution in Resolution::ALL {
+        let mut locations = vec![(90.0, 0.0), (-90.0, 0.0), (0.0, -180.0), (0.0, 179.9999)];
+        locations.extend((0..200).map(|_| (rng.gen_range(-90.0..=90.0), rng.gen_range(-180.0..180.0))));
+
+        for (lat, lon) in locations {
+            let index = square_index(lat, lon, resolution);
+            let code = square_code(index);
+            assert_eq!(code.split('.').map(str::len).collect::<Vec<_>>(), vec![5, 5, 5], "Malformed code {}", code);
+            assert_eq!(square_from_code(&code), Some(index));
+            assert_eq!(square_from_code(&code.to_uppercase()), Some(index));
+            if let Some(other) = codes.insert(code.clone(), index) {
+                assert_eq!(other, index, "Code {} is shared by two squares", code);
+            }
+        }
+    }
+
+    // Neighbouring squares get unrelated codes
+    let index = square_index(48.137, 11.575, DEFAULT_RESOLUTION);
+    let code = square_code(index);
+    for neighbour in neighbour_squares(index) {
+        let neighbour_code = square_code(neighbour);
+        let shared_words = code.split('.').zip(neighbour_code.split('.')).filter(|(a, b)| a == b).count();
+        assert!(shared_words < 2, "Codes {} and {} are too similar", code, neighbour_code);
+    }
+
+    // Malformed codes are rejected
+    for code in ["", "bafok.temul", "bafok.temul.zirag.bafok", "bafok.temul.zira", "bafok.temul.zirac", "bafok-temul-zirag"] {
+        assert_eq!(square_from_code(code), None, "Code {:?} was accepted", code);
+    }
+}
+
+#[test]
+fn test_square_inputs_resolve_to_the_same_square() {
+    let index = square_index(48.137, 11.575, DEFAULT_RESOLUTION);
+    let expected = (square_geohash(index), square_bounds(index));
+
+    let inputs = vec![
+        SquareInput::Coordinate(Geolocation { latitude: 48.137, longitude: 11.575 }),
+        SquareInput::Geohash(geohash::encode(geohash::Coord { x: 11.575, y: 48.137 }, 9).unwrap()),
+        SquareInput::SquareId(square_geohash(index)),
+        SquareInput::Code(square_code(index)),
+    ];
+    for input in inputs {
+        assert_eq!(resolve_square(&input), Ok(expected.clone()), "Input {:?} resolved to another square", input);
+    }
+
+    // Square ids and codes of squares at other resolutions do not name an NFT square
+    let parent = parent_square(index).unwrap();
+    assert!(matches!(resolve_square(&SquareInput::SquareId(square_geohash(parent))), Err(GridError::NotASquare(_))));
+    assert!(matches!(resolve_square(&SquareInput::Code(square_code(parent))), Err(GridError::NotASquare(_))));
+    assert!(matches!(resolve_square(&SquareInput::Code("bafok.temul".to_string())), Err(GridError::InvalidCode(_))));
+
+    // Batches are resolved input by input and capped
+    let lookups = lookup_squares(vec![SquareInput::SquareId(square_geohash(index)), SquareInput::Geohash("not a geohash!".to_string())]).unwrap();
+    assert!(lookups[0].is_ok() && lookups[1].is_err());
+    let too_many = (0..21).map(|_| SquareInput::SquareId(square_geohash(index))).collect();
+    assert_eq!(lookup_squares(too_many), Err(GeohashError::TooManyInputs(20)));
+}
+
+#[test]
+fn test_state_survives_upgrades() {
+    let memory = DefaultMemoryImpl::default();
+    let canister_id = Principal::from_text("br5f7-7uaaa-aaaaa-qaaca-cai").unwrap();
+    let record = SquareRecord { document_name: document_name("u281z7j5e3gr"), minted_at: 1_700_000_000_000_000_000, claimed_at: 1_700_000_000_000_000_000, ..square_record(canister_id, 3) };
+    let air_quality = MetricObservation { metric: "Air quality index".to_string(), value: MetricValue::Nat(7), observed_at: record.minted_at, submitter: canister_id };
+
+    // A fresh memory gets the current schema version and is filled by the installed canister
+    {
+        let mut state = StableState::init(memory.clone());
+        assert_eq!(state.config().schema_version, 0);
+        state.migrate().unwrap();
+        state.update_config(|config| config.dip721_canister_id = Some(canister_id)).unwrap();
+        state.token_ids.insert("u281z7j5e3gr".to_string(), 3);
+        state.square_records.insert("u281z7j5e3gr".to_string(), Cbor(record.clone()));
+        state.record_observation("u281z7j5e3gr", air_quality.clone());
+    }
+
+    // The upgraded canister opens the same stable memory and finds everything again
+    let mut upgraded = StableState::init(memory.clone());
+    upgraded.migrate().unwrap();
+    assert_eq!(upgraded.config().schema_version, SCHEMA_VERSION);
+    assert_eq!(upgraded.config().dip721_canister_id, Some(canister_id));
+    assert_eq!(upgraded.token_ids.get(&"u281z7j5e3gr".to_string()), Some(3));
+    assert_eq!(upgraded.square_records.get(&"u281z7j5e3gr".to_string()), Some(Cbor(record.clone())));
+    assert_eq!(upgraded.latest_observations("u281z7j5e3gr"), vec![air_quality]);
+    assert_eq!(upgraded.metric_definitions.len(), default_metric_definitions().len() as u64);
+
+    // Memory written by a newer version of the canister is not silently reinterpreted
+    upgraded.update_config(|config| config.schema_version = SCHEMA_VERSION + 1).unwrap();
+    assert!(StableState::init(memory).migrate().is_err());
+}
+
+#[test]
+fn test_dependency_canister_validation() {
+    let args = InitArgs {
+        dip721_canister_id: Principal::from_text("br5f7-7uaaa-aaaaa-qaaca-cai").unwrap(),
+        bitcoin_canister_id: Principal::from_text("b77ix-eeaaa-aaaaa-qaada-cai").unwrap(),
+        ethereum_canister_id: Principal::from_text("bd3sg-teaaa-aaaaa-qaaba-cai").unwrap(),
+    };
+    assert_eq!(validate_dependency_canisters(&args), Ok(()));
+
+    // Anonymous and management canister IDs are placeholders, not dependencies
+    let anonymous = InitArgs { bitcoin_canister_id: Principal::anonymous(), ..args.clone() };
+    assert!(validate_dependency_canisters(&anonymous).is_err());
+    let management = InitArgs { ethereum_canister_id: Principal::management_canister(), ..args.clone() };
+    assert!(validate_dependency_canisters(&management).is_err());
+
+    // Every dependency is a different canister
+    let duplicate = InitArgs { ethereum_canister_id: args.dip721_canister_id, ..args.clone() };
+    assert!(validate_dependency_canisters(&duplicate).unwrap_err().contains("DIP721 and Ethereum"));
+}
+
+#[test]
+fn test_metric_registry_and_history() {
+    let air_quality = default_metric_definitions().into_iter().find(|definition| definition.name == "Air quality index").unwrap();
+    assert_eq!(validate_value(&air_quality, &MetricValue::Nat(42)), Ok(()));
+    assert_eq!(validate_value(&air_quality, &MetricValue::Nat(501)), Err(MetricError::OutOfRange(501.0)));
+    assert_eq!(validate_value(&air_quality, &MetricValue::Float(42.0)), Err(MetricError::WrongValueType(MetricValueType::Nat)));
+
+    // Ratings are no metric, they cannot be registered or recorded
+    assert!(default_metric_definitions().iter().all(|definition| definition.name != RATING_METRIC));
+    assert!(validate_definition(&MetricDefinition { name: RATING_METRIC.to_string(), ..air_quality.clone() }).is_err());
+    assert_eq!(validate_value(&MetricDefinition { name: RATING_METRIC.to_string(), ..air_quality }, &MetricValue::Nat(5)), Err(MetricError::UnknownMetric(RATING_METRIC.to_string())));
+
+    // Values other than numbers can be stored, e.g. whether basements flooded, but they have no range
+    let flooding = MetricDefinition {
+        name: "Basement flooding".to_string(),
+        unit: "".to_string(),
+        value_type: MetricValueType::Bool,
+        min: None,
+        max: None,
+        source: "users".to_string(),
+    };
+    assert_eq!(validate_definition(&flooding), Ok(()));
+    assert!(validate_definition(&MetricDefinition { min: Some(0.0), ..flooding.clone() }).is_err());
+    assert!(validate_definition(&MetricDefinition { name: "flooding/basement".to_string(), ..flooding.clone() }).is_err());
+    assert!(validate_definition(&MetricDefinition { value_type: MetricValueType::Float, min: Some(2.0), max: Some(1.0), ..flooding }).is_err());
+
+    // History is kept per square and metric in time order, while the latest observation is the newest one
+    let mut state = StableState::init(DefaultMemoryImpl::default());
+    state.migrate().unwrap();
+    let observation = |value, observed_at| MetricObservation {
+        metric: "Air quality index".to_string(),
+        value: MetricValue::Nat(value),
+        observed_at,
+        submitter: Principal::anonymous(),
+    };
+    state.record_observation("u281z7j5e3gr", observation(4, 30));
+    state.record_observation("u281z7j5e3gr", observation(9, 10));
+    state.record_observation("u281z7j5e3gr", observation(6, 20));
+    state.record_observation("u281z7j5e3gr", observation(7, 20));
+    state.record_observation("u281z7j5e3gs", observation(1, 40));
+
+    // Observations made at the same time are all kept
+    assert_eq!(state.latest_observations("u281z7j5e3gr"), vec![observation(4, 30)]);
+    assert_eq!(
+        state.observation_history("u281z7j5e3gr", "Air quality index", 0, u64::MAX, 10),
+        vec![observation(9, 10), observation(6, 20), observation(7, 20), observation(4, 30)]
+    );
+    assert_eq!(state.observation_history("u281z7j5e3gr", "Air quality index", 15, 30, 10), vec![observation(6, 20), observation(7, 20)]);
+    assert_eq!(state.observation_history("u281z7j5e3gr", "Air quality index", 15, 30, 1), vec![observation(6, 20)]);
+    assert!(state.observation_history("u281z7j5e3gr", "Crime rate", 0, u64::MAX, 10).is_empty());
+}
+
+#[test]
+fn test_metric_series_aggregation() {
+    const DAY: u64 = 86_400_000_000_000;
+    // 2024-02-29 and 2024-03-01, 12:00 UTC
+    let leap_day = 19_782 * DAY + DAY / 2;
+    let first_of_march = leap_day + DAY;
+
+    assert_eq!(period_start(leap_day, AggregationPeriod::Day), 19_782 * DAY);
+    assert_eq!(period_start(leap_day, AggregationPeriod::Month), 19_754 * DAY);
+    assert_eq!(period_start(first_of_march, AggregationPeriod::Month), 19_783 * DAY);
+    assert_eq!(period_start(0, AggregationPeriod::Month), 0);
+
+    let values = [(leap_day, 10.0), (leap_day + 1, 40.0), (leap_day + 2, 20.0), (leap_day + 3, 30.0), (first_of_march, 5.0)];
+    assert_eq!(
+        aggregate_series(&values, AggregationPeriod::Month, 50),
+        vec![
+            MetricAggregate { period_start: 19_754 * DAY, count: 4, min: 10.0, max: 40.0, mean: 25.0, percentile: 25.0 },
+            MetricAggregate { period_start: 19_783 * DAY, count: 1, min: 5.0, max: 5.0, mean: 5.0, percentile: 5.0 },
+        ]
+    );
+    assert_eq!(aggregate_series(&values[..4], AggregationPeriod::Day, 90)[0].percentile, 37.0);
+    assert_eq!(aggregate_series(&values[..4], AggregationPeriod::Day, 0)[0].percentile, 10.0);
+    assert!(aggregate_series(&[], AggregationPeriod::Day, 50).is_empty());
+}
+
+#[test]
+fn test_oracle_submissions() {
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+    let oracle = Principal::from_text("b77ix-eeaaa-aaaaa-qaada-cai").unwrap();
+    let now = 1_700_000_000_000_000_000;
+    let munich = SquareInput::Coordinate(Geolocation { latitude: 48.137, longitude: 11.575 });
+    let submitted = |metric: &str, value, observed_at| OracleObservation {
+        square: munich.clone(),
+        metric: metric.to_string(),
+        value,
+        observed_at,
+        source_reference: "https://example.org/stations/42".to_string(),
+    };
+
+    // Only registered oracles can submit
+    assert_eq!(submit_oracle_batch(oracle, vec![], now), Err(MetricError::Unauthorized));
+    STATE.with(|state| {
+        state.borrow_mut().oracles.insert(oracle, Cbor(OracleState {
+            oracle: Oracle { principal: oracle, name: "Test oracle".to_string(), max_observations_per_hour: 4, registered_at: now },
+            window: RateWindow::default(),
+        }))
+    });
+
+    // Each observation is checked on its own
+    let results = submit_oracle_batch(oracle, vec![
+        submitted("Air quality index", MetricValue::Nat(42), now - 1),
+        submitted("Air quality index", MetricValue::Nat(900), now - 1),
+        submitted("Air quality index", MetricValue::Nat(42), now + RATE_LIMIT_WINDOW_NANOS),
+    ], now).unwrap();
+    let (geohash, _) = resolve_square(&munich).unwrap();
+    assert_eq!(results[0].as_ref().unwrap().submitter, oracle);
+    assert_eq!(results[1], Err(MetricError::OutOfRange(900.0)));
+    assert_eq!(results[2], Err(MetricError::InvalidTimestamp(now + RATE_LIMIT_WINDOW_NANOS)));
+    assert_eq!(STATE.with(|state| state.borrow().latest_observations(&geohash)), vec![results[0].clone().unwrap()]);
+
+    // Every submission is audited, accepted or not
+    let audit: Vec<_> = STATE.with(|state| state.borrow().audit_log.iter().map(|(_, entry)| entry.0).collect());
+    assert_eq!(audit.len(), 3);
+    assert!(audit.iter().all(|entry| entry.oracle == oracle && entry.square == Some(geohash.clone())));
+    assert_eq!(audit[0].rejection, None);
+    assert_eq!(audit[1].rejection, Some(MetricError::OutOfRange(900.0)));
+
+    // The rate limit counts all submitted observations and resets after the window
+    assert_eq!(
+        submit_oracle_batch(oracle, vec![submitted("Crime rate", MetricValue::Float(1.0), now); 2], now + 1),
+        Err(MetricError::RateLimited(RATE_LIMIT_WINDOW_NANOS - 1))
+    );
+    assert!(submit_oracle_batch(oracle, vec![submitted("Crime rate", MetricValue::Float(1.0), now); 2], now + RATE_LIMIT_WINDOW_NANOS).is_ok());
+}
+
+#[test]
+fn test_feed_responses() {
+    let config = FeedConfig {
+        name: "Air quality".to_string(),
+        url_template: "http://localhost:8088/air-quality?latitude={lat}&longitude={lon}".to_string(),
+        fields: vec![FieldMapping { path: "current.us_aqi".to_string(), metric: "Air quality index".to_string() }],
+        max_response_bytes: 10_000,
+    };
+    assert_eq!(validate_feed(&config), Ok(()));
+    assert!(validate_feed(&FeedConfig { url_template: "ftp://example.org/{lat}/{lon}".to_string(), ..config.clone() }).is_err());
+
+    // Plain http is only accepted for a local stub server
+    assert_eq!(validate_feed(&FeedConfig { url_template: "http://localhost:8088/air-quality?latitude={lat}&longitude={lon}".to_string(), ..config.clone() }), Ok(()));
+    assert_eq!(validate_feed(&FeedConfig { url_template: "http://127.0.0.1/{lat}/{lon}".to_string(), ..config.clone() }), Ok(()));
+    assert!(validate_feed(&FeedConfig { url_template: "http://example.org/{lat}/{lon}".to_string(), ..config.clone() }).is_err());
+    assert!(validate_feed(&FeedConfig { url_template: "http://localhost.example.org/{lat}/{lon}".to_string(), ..config.clone() }).is_err());
+    assert!(validate_feed(&FeedConfig { url_template: "https://example.org/aqi".to_string(), ..config.clone() }).is_err());
+    assert!(validate_feed(&FeedConfig { fields: vec![], ..config.clone() }).is_err());
+    assert_eq!(feed_url(&config.url_template, 48.1374, -11.5755), "http://localhost:8088/air-quality?latitude=48.137400&longitude=-11.575500");
+
+    // Fields are found by dot-separated paths, array elements by their position
+    let body = br#"{"generated_at": 1718000000, "current": {"us_aqi": 41.6, "flooded": true}, "hourly": {"pm10": [12.5, 14.0]}}"#;
+    let paths = vec!["current.us_aqi".to_string(), "hourly.pm10.1".to_string(), "current.missing".to_string()];
+    let fields = extract_fields(body, &paths).unwrap();
+    assert_eq!(fields.len(), 2);
+    assert_eq!(json_to_metric_value(&fields["current.us_aqi"], MetricValueType::Nat), Some(MetricValue::Nat(42)));
+    assert_eq!(json_to_metric_value(&fields["hourly.pm10.1"], MetricValueType::Float), Some(MetricValue::Float(14.0)));
+    assert_eq!(json_to_metric_value(&fields["current.us_aqi"], MetricValueType::Bool), None);
+    assert!(extract_fields(b"<html>", &paths).is_err());
+
+    // Responses of different replicas agree once they are transformed, even if they differ in headers and other fields
+    let context = serde_json::to_vec(&paths).unwrap();
+    let response = |body: &[u8], date: &str| HttpResponse {
+        status: Nat::from(200u32),
+        headers: vec![HttpHeader { name: "Date".to_string(), value: date.to_string() }],
+        body: body.to_vec(),
+    };
+    let first = transform_response(response(body, "Mon"), &context);
+    let second = transform_response(response(br#"{"hourly": {"pm10": [12.5, 14.0]}, "current": {"us_aqi": 41.6}, "generated_at": 1718000001}"#, "Tue"), &context);
+    assert_eq!(first, second);
+    assert!(first.headers.is_empty());
+}
+
+#[test]
+fn test_refresh_batches_go_round_the_minted_squares() {
+    let record = SquareRecord { document_name: "geohash:u281z7j5e".to_string(), ..square_record(Principal::anonymous(), 0) };
+    let batch_size = DEFAULT_REFRESH_BATCH_SIZE as usize;
+    assert!(next_minted_squares(None, batch_size).is_empty());
+
+    let squares: Vec<String> = (0..batch_size + 3).map(|position| format!("u281z7j5e{:03}", position)).collect();
+    for geohash in &squares {
+        update_square_record(geohash.clone(), record.clone());
+    }
+    let first = next_minted_squares(None, batch_size);
+    assert_eq!(first, squares[..batch_size]);
+    let second = next_minted_squares(first.last(), batch_size);
+    assert_eq!(second, squares[batch_size..]);
+    assert_eq!(next_minted_squares(second.last(), batch_size), first);
+}
+
+#[test]
+fn test_refresh_job_status() {
+    assert!(validate_refresh_settings(3600, DEFAULT_REFRESH_BATCH_SIZE).is_ok());
+    assert!(validate_refresh_settings(1, DEFAULT_REFRESH_BATCH_SIZE).is_err());
+    assert!(validate_refresh_settings(3600, 0).is_err());
+    assert!(validate_refresh_settings(3600, MAX_REFRESH_BATCH_SIZE + 1).is_err());
+
+    // A fresh canister starts with the default settings and no runs
+    let state = StableState::init(DefaultMemoryImpl::default());
+    let mut status = state.refresh_job();
+    assert_eq!(status, RefreshJobStatus::default());
+    assert_eq!(status.last_run, None);
+
+    let failure = |position: u64| RefreshFailure { geohash: format!("u281z7j5e{:03}", position), error: "Failed".to_string(), failed_at: position };
+    record_batch(&mut status, 10, vec![failure(1), failure(2)], Some("u281z7j5e009".to_string()), 100);
+    record_batch(&mut status, 3, vec![], Some("u281z7j5e012".to_string()), 200);
+    assert_eq!(status.next_square.as_deref(), Some("u281z7j5e012"));
+    assert_eq!(status.last_run, Some(200));
+    assert_eq!((status.squares_processed_last_run, status.squares_processed_total), (3, 13));
+    assert_eq!((status.failures_last_run, status.failures_total), (0, 2));
+
+    // Only the most recent failures are kept
+    record_batch(&mut status, 30, (3..33).map(failure).collect(), None, 300);
+    assert_eq!(status.failures_total, 32);
+    assert_eq!(status.recent_failures.len(), 20);
+    assert_eq!(status.recent_failures.first(), Some(&failure(13)));
+    assert_eq!(status.recent_failures.last(), Some(&failure(32)));
+}
+
+#[test]
+fn test_contributions_are_decided_by_votes() {
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+    let now = 1_700_000_000_000_000_000;
+    let munich = SquareInput::Coordinate(Geolocation { latitude: 48.137, longitude: 11.575 });
+    let contribute = |contributor, description: &str| {
+        submit_user_contribution(contributor, &munich, "Crime rate", MetricValue::Float(1.5), description.to_string(), now)
+    };
+
+    // Contributions are signed, described and valid observations
+    assert_eq!(contribute(Principal::anonymous(), "Quiet street"), Err(ContributionError::AnonymousCaller));
+    assert!(matches!(contribute(user(1), " "), Err(ContributionError::InvalidDescription(_))));
+    assert_eq!(
+        submit_user_contribution(user(1), &munich, "Crime rate", MetricValue::Nat(1), "Quiet street".to_string(), now),
+        Err(ContributionError::InvalidObservation(MetricError::WrongValueType(MetricValueType::Float)))
+    );
+
+    // Nobody has reputation yet, so anyone may vote
+    STATE.with(|state| state.borrow_mut().contribution_policy.set(Cbor(ContributionPolicy { min_voter_reputation: 0, ..ContributionPolicy::default() }))).unwrap();
+    let accepted = contribute(user(1), "Quiet street").unwrap();
+    assert_eq!((accepted.id, accepted.status), (0, ContributionStatus::Pending));
+    assert_eq!(vote_on_contribution(user(1), 0, Vote::Corroborate, now), Err(ContributionError::OwnContribution));
+    assert_eq!(vote_on_contribution(Principal::anonymous(), 0, Vote::Corroborate, now), Err(ContributionError::AnonymousCaller));
+    assert_eq!(vote_on_contribution(user(2), 7, Vote::Corroborate, now), Err(ContributionError::UnknownContribution(7)));
+
+    // Three corroborations accept it, a dispute does not stop them
+    vote_on_contribution(user(2), 0, Vote::Corroborate, now).unwrap();
+    assert_eq!(vote_on_contribution(user(2), 0, Vote::Dispute, now), Err(ContributionError::AlreadyVoted));
+    vote_on_contribution(user(3), 0, Vote::Dispute, now).unwrap();
+    vote_on_contribution(user(4), 0, Vote::Corroborate, now).unwrap();
+    let decided = vote_on_contribution(user(5), 0, Vote::Corroborate, now + 1).unwrap();
+    assert_eq!((decided.status, decided.decided_at), (ContributionStatus::Accepted, Some(now + 1)));
+    assert_eq!(vote_on_contribution(user(6), 0, Vote::Dispute, now), Err(ContributionError::AlreadyDecided(ContributionStatus::Accepted)));
+
+    // The accepted contribution is an observation of the square by its contributor
+    let (geohash, _) = resolve_square(&munich).unwrap();
+    let latest = STATE.with(|state| state.borrow().latest_observations(&geohash));
+    assert_eq!(latest, vec![MetricObservation { metric: "Crime rate".to_string(), value: MetricValue::Float(1.5), observed_at: now, submitter: user(1) }]);
+
+    // A contribution disputed by enough users is rejected and not stored
+    STATE.with(|state| state.borrow_mut().contribution_policy.set(Cbor(ContributionPolicy { disputes_to_reject: 2, min_voter_reputation: 0, ..ContributionPolicy::default() }))).unwrap();
+    contribute(user(3), "Car thefts every night").unwrap();
+    vote_on_contribution(user(4), 1, Vote::Dispute, now).unwrap();
+    vote_on_contribution(user(2), 1, Vote::Corroborate, now).unwrap();
+    assert_eq!(vote_on_contribution(user(5), 1, Vote::Dispute, now).unwrap().status, ContributionStatus::Rejected);
+    assert_eq!(STATE.with(|state| state.borrow().latest_observations(&geohash)), latest);
+
+    // Reputations follow the outcomes: +10 per accepted and -5 per rejected contribution, +1 or -1 per vote
+    let reputation = |number| STATE.with(|state| state.borrow().reputation(&user(number)));
+    assert_eq!((reputation(1).score, reputation(1).contributions, reputation(1).accepted), (10, 1, 1));
+    assert_eq!((reputation(2).score, reputation(2).votes, reputation(2).agreeing_votes), (0, 2, 1));
+    assert_eq!((reputation(3).score, reputation(3).rejected), (-6, 1));
+    assert_eq!((reputation(4).score, reputation(5).score), (2, 2));
+
+    // Users below the reputation of the policy cannot vote
+    STATE.with(|state| state.borrow_mut().contribution_policy.set(Cbor(ContributionPolicy::default()))).unwrap();
+    contribute(user(1), "Bike lanes everywhere").unwrap();
+    assert_eq!(vote_on_contribution(user(3), 2, Vote::Corroborate, now), Err(ContributionError::InsufficientReputation(-6)));
+    assert!(validate_policy(&ContributionPolicy { corroborations_to_accept: 0, ..ContributionPolicy::default() }).is_err());
+}
+
+#[test]
+fn test_zero_reputation_votes_do_not_decide() {
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+    let now = 1_700_000_000_000_000_000;
+    let munich = SquareInput::Coordinate(Geolocation { latitude: 48.137, longitude: 11.575 });
+    submit_user_contribution(user(1), &munich, "Crime rate", MetricValue::Float(1.5), "Quiet street".to_string(), now).unwrap();
+
+    // Fresh principals have no reputation, so under the default policy they cannot accept a contribution
+    for number in 2..=4 {
+        assert_eq!(vote_on_contribution(user(number), 0, Vote::Corroborate, now), Err(ContributionError::InsufficientReputation(0)));
+    }
+    let contribution = STATE.with(|state| state.borrow().contributions.get(&0)).unwrap().0;
+    assert_eq!((contribution.status, contribution.corroborations.len()), (ContributionStatus::Pending, 0));
+    let (geohash, _) = resolve_square(&munich).unwrap();
+    assert!(STATE.with(|state| state.borrow().latest_observations(&geohash)).is_empty());
+}
+
+#[test]
+fn test_ratings_per_user() {
+    let (geohash, _) = resolve_square(&SquareInput::Coordinate(Geolocation { latitude: 48.137, longitude: 11.575 })).unwrap();
+    let (unminted, _) = resolve_square(&SquareInput::Coordinate(Geolocation { latitude: 52.52, longitude: 13.405 })).unwrap();
+    let record = SquareRecord { document_name: document_name(&geohash), minted_at: 1_700_000_000_000_000_000, claimed_at: 1_700_000_000_000_000_000, ..square_record(user(1), 5) };
+
+    // Only signed-in users rate minted squares, from 1 to 10
+    assert_eq!(rate_minted_square(user(1), &geohash, 8, 0), Err(GeohashError::NotMinted(geohash.clone())));
+    update_square_record(geohash.clone(), record);
+    assert_eq!(rate_minted_square(Principal::anonymous(), &geohash, 8, 0), Err(GeohashError::AnonymousCaller));
+    assert!(matches!(rate_minted_square(user(1), &geohash, 0, 0), Err(GeohashError::InvalidInput(_))));
+    assert!(matches!(rate_minted_square(user(1), &geohash, 11, 0), Err(GeohashError::InvalidInput(_))));
+    assert!(matches!(rate_minted_square(user(1), "not a geohash!", 8, 0), Err(GeohashError::InvalidGeohash(_))));
+
+    // Every user has one rating, rating again replaces it
+    rate_minted_square(user(1), &geohash, 8, 0).unwrap();
+    rate_minted_square(user(2), &geohash, 4, 0).unwrap();
+    let summary = rate_minted_square(user(1), &geohash, 10, 0).unwrap();
+    assert_eq!(summary, RatingSummary { count: 2, mean: 7.0, distribution: vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 1] });
+    assert_eq!(lookup_area(geohash.clone()).unwrap().rating, Some(summary));
+    assert_eq!(lookup_area(unminted).unwrap().rating, None);
+
+}
+
+#[test]
+fn test_square_documents() {
+    let (geohash, _) = resolve_square(&SquareInput::Coordinate(Geolocation { latitude: 48.137, longitude: 11.575 })).unwrap();
+    let name = document_name(&geohash);
+    let owner = user(1);
+    let record = SquareRecord { document_name: name.clone(), minted_at: 1_000, claimed_at: 1_000, ..square_record(owner, 9) };
+
+    // Unminted squares have no documents
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+    assert_eq!(STATE.with(|state| state.borrow_mut().publish_document(&geohash, 2_000)), None);
+
+    // Minting publishes the first version; publishing unchanged data keeps it
+    update_square_record(geohash.clone(), record.clone());
+    let first = STATE.with(|state| state.borrow_mut().publish_document(&geohash, 2_000)).unwrap();
+    assert_eq!((first.name.as_str(), first.version, first.updated_at), (name.as_str(), 1, 2_000));
+    assert!(first.cid.starts_with("bafyrei"));
+    // CIDs match those of IPFS, e.g. of the empty DAG-CBOR map
+    assert_eq!(cid_of_block(&[0xa0]), "bafyreigbtj4x7ip5legnfznufuopl4sg4knzc2cof6duas4b3q2fy6swua");
+    assert_eq!(STATE.with(|state| state.borrow_mut().publish_document(&geohash, 3_000)), Some(first.clone()));
+
+    // Every change publishes a new version linking to the previous one
+    rate_minted_square(owner, &geohash, 7, 4_000).unwrap();
+    let second = lookup_area(geohash.clone()).unwrap().document.unwrap();
+    assert_eq!((second.version, second.updated_at), (2, 4_000));
+    let document = STATE.with(|state| state.borrow().documents.get(&second.cid)).unwrap().0;
+    assert_eq!(document.previous, Some(first.cid.clone()));
+    assert_eq!(document.rating.as_ref().map(|rating| rating.mean), Some(7.0));
+
+    // The CID is the hash of the canonical block, which always encodes the same way
+    let block = get_document_block(second.cid.clone()).unwrap();
+    assert_eq!(cid_of_block(&block), second.cid);
+    assert_eq!(encode_document(&document), block);
+    let decoded: ciborium::value::Value = ciborium::de::from_reader(block.as_slice()).unwrap();
+    let keys: Vec<String> = decoded.as_map().unwrap().iter().map(|(key, _)| key.as_text().unwrap().to_string()).collect();
+    assert_eq!(keys, ["owner", "rating", "geohash", "metrics", "version", "previous", "token_id", "minted_at", "updated_at", "bitcoin_address", "ethereum_address"]);
+    // Floats take 64 bits even if fewer would do, and the previous version is a link (tag 42)
+    assert!(block.windows(9).any(|window| window[0] == 0xfb && window[1..] == 7.0f64.to_be_bytes()));
+    assert!(matches!(decoded.as_map().unwrap()[5].1, ciborium::value::Value::Tag(42, _)));
+
+    // The history follows the links back from the latest version
+    let history = document_history(name.clone(), None, None).unwrap();
+    assert_eq!(history, vec![second.clone(), first.clone()]);
+    assert_eq!(document_history(name.clone(), Some(first.cid.clone()), None).unwrap(), vec![first.clone()]);
+    assert_eq!(document_history(name.clone(), None, Some(1)).unwrap(), vec![second]);
+    assert!(matches!(document_history(name, Some("not a cid".to_string()), None), Err(GeohashError::InvalidInput(_))));
+    assert_eq!(document_history("geohash:unknown".to_string(), None, None), Err(GeohashError::NotFound("geohash:unknown".to_string())));
+}
+
+#[test]
+fn test_mints_are_reserved_per_square() {
+    let (geohash, _) = resolve_square(&SquareInput::Coordinate(Geolocation { latitude: 52.52, longitude: 13.405 })).unwrap();
+    let (claimer, other) = (user(1), user(2));
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+
+    // The first claim locks the square and records the pending mint with the idempotency key of the square
+    let (guard, pending) = reserve_mint(claimer, &geohash, None, 1_000).unwrap();
+    assert_eq!((pending.owner, pending.idempotency_key.as_str(), pending.attempts), (claimer, mint_idempotency_key(&geohash).as_str(), 1));
+    assert_eq!(STATE.with(|state| state.borrow().pending_mints.get(&geohash)).unwrap().0, pending);
+
+    // Concurrent claims of the same square fail while the mint awaits other canisters
+    assert_eq!(reserve_mint(other, &geohash, None, 1_001).unwrap_err(), GeohashError::MintInProgress(geohash.clone()));
+    assert!(reserve_mint(claimer, &geohash, None, 1_001).is_err());
+    drop(guard);
+
+    // An interrupted mint stays reserved for its owner, who resumes it with the same key
+    assert_eq!(reserve_mint(other, &geohash, None, 2_000).unwrap_err(), GeohashError::MintInProgress(geohash.clone()));
+    let (guard, resumed) = reserve_mint(claimer, &geohash, None, 2_000).unwrap();
+    assert_eq!(resumed, PendingMint { attempts: 2, ..pending });
+
+    // Finishing the mint maps the square to its token, ends the pending mint and publishes the first document
+    let record = SquareRecord { document_name: document_name(&geohash), minted_at: 2_500, claimed_at: 1_000, ..square_record(claimer, 3) };
+    let document = STATE.with(|state| state.borrow_mut().finish_mint(&geohash, record.clone())).unwrap();
+    drop(guard);
+    assert_eq!((document.version, document.updated_at), (1, 2_500));
+    assert_eq!(get_token_id_by_geohash(&geohash), Some(3));
+    assert_eq!(lookup_area(geohash.clone()).unwrap().status, SquareStatus::Minted(record));
+    assert!(STATE.with(|state| state.borrow().pending_mints.is_empty()));
+
+    // Minted squares cannot be claimed again
+    assert_eq!(reserve_mint(claimer, &geohash, None, 3_000).unwrap_err(), GeohashError::AlreadyClaimed { geohash, token_id: 3 });
+}
+
+#[test]
+fn test_provisioning_resumes_at_the_failed_step() {
+    let (geohash, _) = resolve_square(&SquareInput::Coordinate(Geolocation { latitude: -33.87, longitude: 151.21 })).unwrap();
+    let owner = user(3);
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+
+    // A new mint starts reserved and is retried right away if it was interrupted
+    let (guard, pending) = reserve_mint(owner, &geohash, None, 1_000).unwrap();
+    assert_eq!((pending.state.clone(), pending.next_retry_at), (ProvisioningState::Reserved, None));
+    assert!(due_for_retry(&pending, 1_000));
+
+    // Mints in flight are not stuck
+    assert!(stuck_pending_mints().iter().all(|stuck| stuck.geohash != geohash));
+
+    // The Ethereum address failed after the Bitcoin address was stored: the retry is scheduled and the address kept
+    let mut pending = PendingMint { state: ProvisioningState::BitcoinAddress { bitcoin_address: "bitcoin-address".to_string() }, ..pending };
+    record_failure(&mut pending, "Ethereum canister unavailable".to_string(), 2_000);
+    STATE.with(|state| state.borrow_mut().pending_mints.insert(geohash.clone(), Cbor(pending.clone())));
+    drop(guard);
+    assert_eq!(pending.next_retry_at, Some(2_000 + retry_delay(1)));
+    assert!(!due_for_retry(&pending, 2_000));
+    assert!(due_for_retry(&pending, 2_000 + retry_delay(1)));
+
+    // The stuck square shows up in the state it is stuck in
+    let stuck = group_by_stage(stuck_pending_mints());
+    assert_eq!(stuck.len(), 5);
+    let stuck_in = |stage| stuck.iter().find(|stuck| stuck.stage == stage).unwrap().squares.iter().any(|stuck| stuck.geohash == geohash);
+    assert!(stuck_in(ProvisioningStage::BitcoinAddress));
+    assert!(!stuck_in(ProvisioningStage::Reserved));
+
+    // Claiming it again resumes with the Bitcoin address instead of starting over
+    let (_guard, resumed) = reserve_mint(owner, &geohash, None, 3_000).unwrap();
+    assert_eq!(resumed.state, pending.state);
+    assert_eq!(resumed.attempts, 2);
+}
+
+#[test]
+fn test_stuck_mints_are_given_up() {
+    let (geohash, _) = resolve_square(&SquareInput::Coordinate(Geolocation { latitude: 35.68, longitude: 139.69 })).unwrap();
+    let owner = user(5);
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+
+    // Mints in flight cannot be cancelled
+    let (guard, pending) = reserve_mint(owner, &geohash, None, 1_000).unwrap();
+    assert_eq!(STATE.with(|state| state.borrow().claims_of(&owner)), 1);
+    assert_eq!(cancel_pending_mint(&geohash), Err(GeohashError::MintInProgress(geohash.clone())));
+    drop(guard);
+
+    // The timer gives up mints that kept failing before the NFT metadata was created, but keeps retrying later stages
+    let exhausted = PendingMint { attempts: MAX_MINT_ATTEMPTS, ..pending.clone() };
+    assert!(!should_abandon(&PendingMint { attempts: MAX_MINT_ATTEMPTS - 1, ..pending.clone() }));
+    assert!(should_abandon(&exhausted));
+    let properties = SquareProperties {
+        geohash: geohash.clone(),
+        metadata: document_name(&geohash),
+        wallet: Wallet { ether: "ethereum-address".to_string(), bitcoin: "bitcoin-address".to_string() },
+    };
+    assert!(!should_abandon(&PendingMint { state: ProvisioningState::Metadata { properties: properties.clone() }, ..exhausted.clone() }));
+
+    // Minted squares are finished instead of cancelled
+    let minted = PendingMint { state: ProvisioningState::Minted { properties, token_id: 5 }, ..pending.clone() };
+    STATE.with(|state| state.borrow_mut().pending_mints.insert(geohash.clone(), Cbor(minted.clone())));
+    assert!(matches!(cancel_pending_mint(&geohash), Err(GeohashError::InvalidInput(_))));
+
+    // Cancelling a stuck mint releases the square and the claim of its owner
+    STATE.with(|state| state.borrow_mut().pending_mints.insert(geohash.clone(), Cbor(pending.clone())));
+    assert_eq!(cancel_pending_mint(&geohash), Ok(pending));
+    assert_eq!(STATE.with(|state| state.borrow().claims_of(&owner)), 0);
+    assert_eq!(cancel_pending_mint(&geohash), Err(GeohashError::NotFound(geohash.clone())));
+    let other = user(6);
+    assert_eq!(reserve_mint(other, &geohash, None, 2_000).unwrap().1.owner, other);
+}
+
+#[test]
+fn test_provisioning_retries_back_off() {
+    // The delay doubles with every attempt, from a minute up to an hour
+    assert_eq!(retry_delay(1), 60_000_000_000);
+    assert_eq!(retry_delay(2), 120_000_000_000);
+    assert_eq!(retry_delay(6), 1_920_000_000_000);
+    assert_eq!(retry_delay(7), 3_600_000_000_000);
+    assert_eq!(retry_delay(u32::MAX), 3_600_000_000_000);
+
+    // Every stage is listed, also without stuck squares, in the order of provisioning
+    let properties = SquareProperties {
+        geohash: "u33db".to_string(),
+        metadata: document_name("u33db"),
+        wallet: Wallet { ether: "ethereum-address".to_string(), bitcoin: "bitcoin-address".to_string() },
+    };
+    let pending = PendingMint {
+        geohash: "u33db".to_string(),
+        owner: user(4),
+        idempotency_key: mint_idempotency_key("u33db"),
+        started_at: 0,
+        attempts: 1,
+        last_error: None,
+        state: ProvisioningState::Minted { properties, token_id: 4 },
+        next_retry_at: None,
+    };
+    let stages: Vec<(ProvisioningStage, usize)> = group_by_stage(vec![pending]).into_iter().map(|stuck| (stuck.stage, stuck.squares.len())).collect();
+    assert_eq!(stages, vec![
+        (ProvisioningStage::Reserved, 0),
+        (ProvisioningStage::BitcoinAddress, 0),
+        (ProvisioningStage::EthereumAddress, 0),
+        (ProvisioningStage::Metadata, 0),
+        (ProvisioningStage::Minted, 1),
+    ]);
+}
+
+#[test]
+fn test_claims_follow_the_policy() {
+    let berlin = Geolocation { latitude: 52.52, longitude: 13.405 };
+    let (geohash, _) = resolve_square(&SquareInput::Coordinate(berlin.clone())).unwrap();
+    let munich = Geolocation { latitude: 48.137, longitude: 11.575 };
+    let (other_square, _) = resolve_square(&SquareInput::Coordinate(munich.clone())).unwrap();
+    let claimer = user(1);
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+
+    // Anonymous callers never claim squares
+    assert_eq!(reserve_mint(Principal::anonymous(), &geohash, None, 1_000).unwrap_err(), GeohashError::AnonymousCaller);
+
+    // Controllers can require a location inside the square and limit the claims per principal
+    let policy = ClaimPolicy { require_location: true, max_claims_per_principal: Some(1) };
+    assert!(validate_claim_policy(&policy).is_ok());
+    assert!(validate_claim_policy(&ClaimPolicy { max_claims_per_principal: Some(0), ..policy.clone() }).is_err());
+    STATE.with(|state| state.borrow_mut().claim_policy.set(Cbor(policy))).unwrap();
+
+    assert_eq!(reserve_mint(claimer, &geohash, None, 1_000).unwrap_err(), GeohashError::LocationRequired);
+    assert_eq!(reserve_mint(claimer, &geohash, Some(munich.clone()), 1_000).unwrap_err(), GeohashError::OutsideSquare(geohash.clone()));
+    assert!(matches!(reserve_mint(claimer, &geohash, Some(Geolocation { latitude: 95.0, longitude: 0.0 }), 1_000), Err(GeohashError::InvalidCoordinate(_))));
+    assert_eq!(STATE.with(|state| state.borrow().claims_of(&claimer)), 0);
+
+    // A claim from inside the square counts towards the limit and keeps its time
+    let (guard, pending) = reserve_mint(claimer, &geohash, Some(berlin), 2_000).unwrap();
+    drop(guard);
+    assert_eq!(pending.started_at, 2_000);
+    assert_eq!(STATE.with(|state| state.borrow().claims_of(&claimer)), 1);
+    assert_eq!(reserve_mint(claimer, &other_square, Some(munich), 2_000).unwrap_err(), GeohashError::ClaimLimitReached(1));
+
+    // Resuming the claim is not a new claim
+    let (_guard, resumed) = reserve_mint(claimer, &geohash, None, 3_000).unwrap();
+    assert_eq!((resumed.started_at, resumed.attempts), (2_000, 2));
+    assert_eq!(STATE.with(|state| state.borrow().claims_of(&claimer)), 1);
+}
+
+#[test]
+fn test_squares_at_risk() {
+    const DAY: u64 = 86_400_000_000_000;
+    let observation = |metric: &str, value: f64| MetricObservation { metric: metric.to_string(), value: MetricValue::Float(value), observed_at: 0, submitter: Principal::anonymous() };
+    let policy = ReversionPolicy {
+        evaluation_interval_seconds: 86_400,
+        health_thresholds: vec![HealthThreshold { metric: "Air quality index".to_string(), min: None, max: Some(100.0) }],
+        min_donations: Some(1_000),
+        max_owner_inactivity_seconds: Some(30 * 86_400),
+        grace_period_seconds: 7 * 86_400,
+    };
+    let definitions = default_metric_definitions();
+    let definition_of = |name: &str| definitions.iter().find(|definition| definition.name == name).cloned();
+    assert!(validate_reversion_policy(&policy, definition_of).is_ok());
+    assert!(validate_reversion_policy(&ReversionPolicy { evaluation_interval_seconds: 10, ..policy.clone() }, definition_of).is_err());
+    let unbounded = vec![HealthThreshold { metric: "Air quality index".to_string(), min: None, max: None }];
+    assert!(validate_reversion_policy(&ReversionPolicy { health_thresholds: unbounded, ..policy.clone() }, definition_of).is_err());
+    let unknown = vec![HealthThreshold { metric: "Noise".to_string(), min: None, max: Some(1.0) }];
+    assert_eq!(validate_reversion_policy(&ReversionPolicy { health_thresholds: unknown, ..policy.clone() }, definition_of), Err(GeohashError::NotFound("Metric Noise".to_string())));
+
+    // A square is only at risk if its health is low, it got few donations and its owner was inactive
+    let polluted = vec![observation("Air quality index", 180.0)];
+    assert_eq!(risk_reasons(&policy, &polluted, Some(10), 0, 40 * DAY), vec![
+        RiskReason::LowHealth { metric: "Air quality index".to_string(), value: 180.0 },
+        RiskReason::LowDonations { received: 10 },
+        RiskReason::InactiveOwner { last_active_at: 0 },
+    ]);
+    assert!(risk_reasons(&policy, &[observation("Air quality index", 40.0)], Some(10), 0, 40 * DAY).is_empty());
+    assert!(risk_reasons(&policy, &[], Some(10), 0, 40 * DAY).is_empty());
+    assert!(risk_reasons(&policy, &polluted, Some(5_000), 0, 40 * DAY).is_empty());
+    assert!(risk_reasons(&policy, &polluted, None, 0, 40 * DAY).is_empty());
+    assert!(risk_reasons(&policy, &polluted, Some(10), 20 * DAY, 40 * DAY).is_empty());
+    assert!(risk_reasons(&ReversionPolicy::default(), &polluted, Some(0), 0, 40 * DAY).is_empty());
+
+    // A square at risk reverts after the grace period unless it recovers
+    let owner = user(1);
+    let reasons = risk_reasons(&policy, &polluted, Some(10), 0, 40 * DAY);
+    let (at_risk, event) = next_reversion(None, "u33db", owner, Some(10), reasons.clone(), 40 * DAY);
+    assert_eq!((at_risk.at_risk_since, event), (Some(40 * DAY), Some(ReversionEventKind::AtRisk(reasons.clone()))));
+    let (still_at_risk, event) = next_reversion(Some(at_risk.clone()), "u33db", owner, None, reasons.clone(), 41 * DAY);
+    assert_eq!((still_at_risk.at_risk_since, still_at_risk.last_balance, event), (Some(40 * DAY), Some(10), None));
+    assert!(!due_for_reversion(&still_at_risk, &policy, 46 * DAY));
+    assert!(due_for_reversion(&still_at_risk, &policy, 47 * DAY));
+    let (recovered, event) = next_reversion(Some(still_at_risk.clone()), "u33db", owner, Some(2_000), vec![], 42 * DAY);
+    assert_eq!((recovered.at_risk_since, event), (None, Some(ReversionEventKind::Recovered)));
+
+    // A new owner starts without the risk of the previous one
+    let (new_owner, event) = next_reversion(Some(still_at_risk), "u33db", user(2), None, vec![], 42 * DAY);
+    assert_eq!((new_owner.at_risk_since, new_owner.last_balance, event), (None, None, None));
+}
+
+#[test]
+fn test_inactive_squares_revert_and_can_be_claimed_again() {
+    const DAY: u64 = 86_400_000_000_000;
+    let (geohash, _) = resolve_square(&SquareInput::Coordinate(Geolocation { latitude: 40.4168, longitude: -3.7038 })).unwrap();
+    let (owner, claimer, canister_id) = (user(3), user(4), user(5));
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+    let policy = ReversionPolicy { min_donations: Some(1_000), max_owner_inactivity_seconds: Some(30 * 86_400), grace_period_seconds: 7 * 86_400, ..ReversionPolicy::default() };
+    STATE.with(|state| state.borrow_mut().reversion_policy.set(Cbor(policy))).unwrap();
+
+    let record = SquareRecord { document_name: document_name(&geohash), minted_at: DAY, claimed_at: DAY, ..square_record(owner, 5) };
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.finish_mint(&geohash, record.clone());
+        state.claim_counts.insert(owner, 1);
+    });
+    let balance = |bitcoin_balance| STATE.with(|state| state.borrow_mut().square_balances.insert(geohash.clone(), Cbor(SquareBalances { bitcoin_balance, ethereum_balance: 0, refreshed_at: 0 })));
+
+    // The first evaluation has no donations to compare with, the second finds too few from an inactive owner
+    balance(500);
+    assert!(evaluate_squares(canister_id, 40 * DAY, EVALUATION_BATCH_SIZE, true).unwrap().is_empty());
+    balance(600);
+    assert!(evaluate_squares(canister_id, 41 * DAY, EVALUATION_BATCH_SIZE, true).unwrap().is_empty());
+    let reversion = STATE.with(|state| state.borrow().square_reversions.get(&geohash)).unwrap().0;
+    assert_eq!(reversion.at_risk_since, Some(41 * DAY));
+    assert_eq!(reversion.reasons, vec![RiskReason::LowDonations { received: 100 }, RiskReason::InactiveOwner { last_active_at: DAY }]);
+
+    // Squares claimed by nobody else cannot be reclaimed before they revert
+    assert!(reserve_reclaim(claimer, &geohash, None, canister_id).unwrap().is_none());
+
+    // After the grace period the square is due to revert
+    assert_eq!(evaluate_squares(canister_id, 48 * DAY, EVALUATION_BATCH_SIZE, true).unwrap(), vec![(geohash.clone(), record)]);
+    STATE.with(|state| state.borrow_mut().revert_square(&geohash, canister_id, 7, 48 * DAY));
+    let reverted = lookup_area(geohash.clone()).unwrap();
+    assert!(matches!(reverted.status, SquareStatus::Minted(ref record) if record.owner == canister_id));
+    assert_eq!(STATE.with(|state| state.borrow().claims_of(&owner)), 0);
+
+    // Squares owned by the canister are not evaluated, and the next user claims the square with the same NFT
+    assert!(evaluate_squares(canister_id, 60 * DAY, EVALUATION_BATCH_SIZE, true).unwrap().is_empty());
+    assert_eq!(reserve_reclaim(Principal::anonymous(), &geohash, None, canister_id).unwrap_err(), GeohashError::AnonymousCaller);
+    let (guard, reverted_record) = reserve_reclaim(claimer, &geohash, None, canister_id).unwrap().unwrap();
+    assert_eq!(reverted_record.token_id, 5);
+
+    // The claim counts from the reservation, so concurrent reclaims cannot exceed the limit, and a failed transfer
+    // releases it
+    assert_eq!(STATE.with(|state| state.borrow().claims_of(&claimer)), 1);
+    STATE.with(|state| state.borrow_mut().release_claim(&claimer));
+    drop(guard);
+    STATE.with(|state| state.borrow_mut().claim_policy.set(Cbor(ClaimPolicy { max_claims_per_principal: Some(1), ..ClaimPolicy::default() }))).unwrap();
+    let (_guard, _) = reserve_reclaim(claimer, &geohash, None, canister_id).unwrap().unwrap();
+    STATE.with(|state| state.borrow_mut().reclaim_square(&geohash, claimer, 8, 61 * DAY));
+    let reclaimed = STATE.with(|state| state.borrow().square_records.get(&geohash)).unwrap().0;
+    assert_eq!((reclaimed.owner, reclaimed.claimed_at, reclaimed.token_id), (claimer, 61 * DAY, 5));
+    assert_eq!(STATE.with(|state| state.borrow().claims_of(&claimer)), 1);
+
+    // Every change of ownership is in the event log
+    let events: Vec<(Principal, ReversionEventKind)> = STATE.with(|state| state.borrow().reversion_events.iter().map(|(_, event)| (event.0.owner, event.0.kind)).collect());
+    assert_eq!(events, vec![
+        (owner, ReversionEventKind::AtRisk(reversion.reasons)),
+        (owner, ReversionEventKind::Reverted { transaction_id: 7 }),
+        (claimer, ReversionEventKind::Reclaimed { transaction_id: 8 }),
+    ]);
+}
+
+#[test]
+fn test_squares_are_evaluated_in_batches() {
+    const DAY: u64 = 86_400_000_000_000;
+    let (owner, buyer, canister_id) = (user(6), user(7), user(8));
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+    let policy = ReversionPolicy { max_owner_inactivity_seconds: Some(86_400), ..ReversionPolicy::default() };
+    STATE.with(|state| state.borrow_mut().reversion_policy.set(Cbor(policy.clone()))).unwrap();
+
+    let squares: Vec<String> = (0..5).map(|position| format!("u33db{:03}", position)).collect();
+    for geohash in &squares {
+        update_square_record(geohash.clone(), SquareRecord { document_name: document_name(geohash), ..square_record(owner, 0) });
+    }
+    let evaluated = || STATE.with(|state| state.borrow().square_reversions.len());
+
+    // Every batch continues after the square the previous one stopped at, and squares are evaluated once per interval
+    evaluate_squares(canister_id, 10 * DAY, 3, true).unwrap();
+    assert_eq!((evaluated(), STATE.with(|state| state.borrow().reversion_cursor())), (3, Some(squares[2].clone())));
+    evaluate_squares(canister_id, 10 * DAY, 3, true).unwrap();
+    assert_eq!(evaluated(), 5);
+    let reversion = STATE.with(|state| state.borrow().square_reversions.get(&squares[0])).unwrap().0;
+    assert!(!due_for_evaluation(Some(&reversion), owner, &policy, 10 * DAY + 1));
+    assert!(due_for_evaluation(Some(&reversion), owner, &policy, 11 * DAY));
+    assert!(due_for_evaluation(Some(&reversion), buyer, &policy, 10 * DAY + 1));
+    assert!(evaluate_squares(canister_id, 10 * DAY + 1, 3, true).unwrap().is_empty());
+    assert_eq!(STATE.with(|state| state.borrow().square_reversions.get(&squares[0])).unwrap().0, reversion);
+
+    // A square whose NFT was transferred outside the canister follows its new owner
+    STATE.with(|state| state.borrow_mut().claim_counts.insert(owner, 5));
+    STATE.with(|state| state.borrow_mut().resync_owner(&squares[0], buyer, canister_id, 12 * DAY));
+    let record = STATE.with(|state| state.borrow().square_records.get(&squares[0])).unwrap().0;
+    assert_eq!((record.owner, record.claimed_at), (buyer, 12 * DAY));
+    assert_eq!(STATE.with(|state| (state.borrow().claims_of(&owner), state.borrow().claims_of(&buyer))), (4, 1));
+    assert!(STATE.with(|state| state.borrow().square_reversions.get(&squares[0])).is_none());
+    let event = STATE.with(|state| state.borrow().reversion_events.iter().map(|(_, event)| event.0).last()).unwrap();
+    assert_eq!((event.owner, event.kind), (owner, ReversionEventKind::OwnerChanged { new_owner: buyer }));
+}
+
+#[test]
+fn test_errors_of_the_endpoints() {
+    // Invalid input is told apart by its kind
+    assert!(matches!(lookup_geohash(Geolocation { latitude: 91.0, longitude: 0.0 }), Err(GeohashError::InvalidCoordinate(_))));
+    assert_eq!(lookup_area("not a geohash!".to_string()), Err(GeohashError::InvalidGeohash("not a geohash!".to_string())));
+    assert!(matches!(lookup_square(SquareInput::Code("bafok.temul".to_string())), Err(GeohashError::InvalidInput(_))));
+
+    // Errors of the metrics and contribution functions keep their details unless a common error fits
+    assert_eq!(GeohashError::from(MetricError::OutOfRange(11.0)), GeohashError::Metric(MetricError::OutOfRange(11.0)));
+    assert_eq!(GeohashError::from(MetricError::Unauthorized), GeohashError::Unauthorized);
+    assert_eq!(GeohashError::from(MetricError::InvalidSquare(GridError::NotASquare("u2".to_string()))), GeohashError::NotASquare("u2".to_string()));
+    assert_eq!(GeohashError::from(ContributionError::AnonymousCaller), GeohashError::AnonymousCaller);
+    assert_eq!(GeohashError::from(ContributionError::UnknownContribution(7)), GeohashError::NotFound("Contribution 7".to_string()));
+    assert_eq!(GeohashError::from(ContributionError::AlreadyVoted), GeohashError::Contribution(ContributionError::AlreadyVoted));
+    assert_eq!(
+        GeohashError::from(ContributionError::InvalidObservation(MetricError::WrongValueType(MetricValueType::Nat))),
+        GeohashError::Metric(MetricError::WrongValueType(MetricValueType::Nat))
+    );
+
+    // Missing records are not found instead of empty, dependencies that are not configured are reported as such
+    STATE.with(|state| state.borrow_mut().migrate()).unwrap();
+    let cid = "bafyreigbtj4x7ip5legnfznufuopl4sg4knzc2cof6duas4b3q2fy6swua".to_string();
+    assert_eq!(get_document_block(cid.clone()), Err(GeohashError::NotFound(cid)));
+    assert_eq!(get_bitcoin_canister_id(), Err(GeohashError::NotConfigured("Bitcoin".to_string())));
 }
@@ -1,16 +1,48 @@
 use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
 use std::collections::HashMap;
+use crate::area_generator::Area;
+use crate::grid_generator::Resolution;
 
 
 // Define a struct for geolocation to be used with Candid
-#[derive(CandidType, Deserialize, Debug)]
+#[derive(CandidType, Deserialize, Debug, Clone)]
 pub struct Geolocation {
     pub latitude: f64,
     pub longitude: f64,
 }
 
-// Define a struct for area response to be used with Candid (not used as of now)
-#[derive(CandidType, Deserialize, Debug)]
+// Define a struct for the canister IDs the geohash canister depends on, passed on install, upgrade or by a controller
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct InitArgs {
+    pub dip721_canister_id: Principal,
+    pub bitcoin_canister_id: Principal,
+    pub ethereum_canister_id: Principal,
+}
+
+// Enum representing invalid input to the grid functions
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GridError {
+    InvalidLatitude(f64),
+    InvalidLongitude(f64),
+    InvalidGeohash(String),
+    NotASquare(String),
+    InvalidRegion(String),
+    InvalidCode(String),
+    TooManyInputs(u32),
+}
+
+// Enum representing the ways to identify a square: any coordinate or geohash inside it, its canonical square geohash or its three-word code
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub enum SquareInput {
+    Coordinate(Geolocation),
+    Geohash(String),
+    SquareId(String),
+    Code(String),
+}
+
+// Define a struct for area response to be used with Candid
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
 pub struct AreaResponse {
     pub lat_start: f64,
     pub lon_start: f64,
@@ -18,9 +50,486 @@ pub struct AreaResponse {
     pub lon_end: f64,
     pub geohash: String,
     pub nft_square: Option<Nft>,
+    pub bitcoin_balance: u64,
+    pub ethereum_balance: u64,
+    pub real_time_metrics: Option<HashMap<String, MetricValue>>,
+    pub rating: Option<RatingSummary>,
     pub created: bool,
 }
 
+// Define a struct for a square of the grid at any resolution to be used with Candid
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct SquareInfo {
+    pub geohash: String,
+    pub resolution: Resolution,
+    pub bounds: Area,
+}
+
+// Define a struct for a square covering a region, flagged if its NFT was minted
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct CoveredSquare {
+    pub geohash: String,
+    pub bounds: Area,
+    pub minted: bool,
+}
+
+// Define a struct for one page of squares covering a region; pass next_cursor to get the next page
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct CoveragePage {
+    pub squares: Vec<CoveredSquare>,
+    pub next_cursor: Option<u64>,
+    pub total_candidates: u64,
+}
+
+// Define a struct for a minted square, cached locally so it can be served from query calls
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SquareRecord {
+    pub token_id: u64,
+    pub owner: Principal,
+    pub bitcoin_address: String,
+    pub ethereum_address: String,
+    // Name pointing to the latest document of the square
+    pub document_name: String,
+    pub minted_at: u64,
+    // When the owner claimed the square
+    pub claimed_at: u64,
+}
+
+// Define a struct for a mint of a square that was started but is not finished yet. It is kept until the NFT and the
+// record of the square are stored, so a mint interrupted after the DIP721 canister minted the NFT is finished with the
+// same idempotency key instead of minting a second NFT.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingMint {
+    pub geohash: String,
+    pub owner: Principal,
+    pub idempotency_key: String,
+    pub started_at: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub state: ProvisioningState,
+    pub next_retry_at: Option<u64>,
+}
+
+// Enum representing how far the provisioning of a square got, with what the finished steps returned: reserved, Bitcoin
+// address, Ethereum address, NFT metadata, minted. Indexing the token ends the pending mint.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ProvisioningState {
+    Reserved,
+    BitcoinAddress { bitcoin_address: String },
+    EthereumAddress { bitcoin_address: String, ethereum_address: String },
+    Metadata { properties: SquareProperties },
+    Minted { properties: SquareProperties, token_id: u64 },
+}
+
+// Enum representing the states of provisioning without their data
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ProvisioningStage {
+    Reserved,
+    BitcoinAddress,
+    EthereumAddress,
+    Metadata,
+    Minted,
+}
+
+// Define a struct for the pending mints stuck in a state of provisioning
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct StuckSquares {
+    pub stage: ProvisioningStage,
+    pub squares: Vec<PendingMint>,
+}
+
+// Enum representing whether the NFT of a square was minted
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub enum SquareStatus {
+    Unminted,
+    Minted(SquareRecord),
+}
+
+// Define a struct for a square looked up without minting, with its metrics, ratings, cached balances and latest document
+// if the square was minted
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct SquareLookup {
+    pub geohash: String,
+    pub bounds: Area,
+    pub status: SquareStatus,
+    pub real_time_metrics: Option<HashMap<String, MetricValue>>,
+    pub rating: Option<RatingSummary>,
+    pub balances: Option<SquareBalances>,
+    pub document: Option<DocumentPointer>,
+}
+
+// Enum representing the type of the values of a metric
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum MetricValueType {
+    Nat,
+    Int,
+    Float,
+    Bool,
+    Text,
+}
+
+// Enum representing a value of a metric
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MetricValue {
+    Nat(u64),
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Text(String),
+}
+
+// Define a struct for a metric in the registry; min and max bound numeric values
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetricDefinition {
+    pub name: String,
+    pub unit: String,
+    pub value_type: MetricValueType,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub source: String,
+}
+
+// Define a struct for a value of a metric observed for a square at a time (nanoseconds since the epoch) and who submitted it
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetricObservation {
+    pub metric: String,
+    pub value: MetricValue,
+    pub observed_at: u64,
+    pub submitter: Principal,
+}
+
+// Enum representing the periods observations are aggregated over (UTC days and calendar months)
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AggregationPeriod {
+    Day,
+    Month,
+}
+
+// Define a struct for the aggregate of the observations of a metric in one period; Bool values count as 0 and 1,
+// so their mean is the share of true observations
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetricAggregate {
+    pub period_start: u64,
+    pub count: u32,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub percentile: f64,
+}
+
+// Enum representing errors of the metrics functions
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum MetricError {
+    Unauthorized,
+    UnknownMetric(String),
+    InvalidDefinition(String),
+    WrongValueType(MetricValueType),
+    OutOfRange(f64),
+    InvalidSquare(GridError),
+    NotNumeric(String),
+    InvalidPercentile(u8),
+    TooManyObservations(u32),
+    TooManyInputs(u32),
+    RateLimited(u64),
+    InvalidTimestamp(u64),
+    InvalidSourceReference(String),
+    UnknownFeed(String),
+    FetchFailed(String),
+}
+
+// Define a struct for a data provider allowed to submit observations, with its rate limit
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Oracle {
+    pub principal: Principal,
+    pub name: String,
+    pub max_observations_per_hour: u32,
+    pub registered_at: u64,
+}
+
+// Define a struct for an observation submitted by an oracle; the source reference points to the original measurement
+#[derive(CandidType, Deserialize, Debug, Clone)]
+pub struct OracleObservation {
+    pub square: SquareInput,
+    pub metric: String,
+    pub value: MetricValue,
+    pub observed_at: u64,
+    pub source_reference: String,
+}
+
+// Define a struct for the audit record of an observation submitted by an oracle, with the reason if it was rejected
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub sequence: u64,
+    pub oracle: Principal,
+    pub received_at: u64,
+    pub square: Option<String>,
+    pub metric: String,
+    pub value: MetricValue,
+    pub observed_at: u64,
+    pub source_reference: String,
+    pub rejection: Option<MetricError>,
+}
+
+// Define a struct for a field of the JSON response of a feed stored as a metric; the path is dot-separated with
+// numbers for array elements, e.g. "current.us_aqi" or "hourly.pm10.0"
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldMapping {
+    pub path: String,
+    pub metric: String,
+}
+
+// Define a struct for a JSON API pulled for the centre of every minted square by the refresh job; the URL template
+// contains {lat} and {lon}
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeedConfig {
+    pub name: String,
+    pub url_template: String,
+    pub fields: Vec<FieldMapping>,
+    pub max_response_bytes: u64,
+}
+
+// Define a struct for a feed with the state of its pulls
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FeedStatus {
+    pub config: FeedConfig,
+    pub last_run: Option<u64>,
+    pub last_error: Option<String>,
+    pub stored_observations: u64,
+}
+
+// Define a struct for the ratings of a square: the number of users who rated it, the mean rating and how many users
+// gave each rating, starting at 1
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RatingSummary {
+    pub count: u32,
+    pub mean: f64,
+    pub distribution: Vec<u32>,
+}
+
+// Define a struct for the balances of a minted square cached by the refresh job
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SquareBalances {
+    pub bitcoin_balance: u64,
+    pub ethereum_balance: u64,
+    pub refreshed_at: u64,
+}
+
+// Define a struct for a square the refresh job failed to refresh
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RefreshFailure {
+    pub geohash: String,
+    pub error: String,
+    pub failed_at: u64,
+}
+
+// Define a struct for the state of the job refreshing the minted squares in batches; next_square is the last square
+// refreshed, the next batch continues after it
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RefreshJobStatus {
+    pub interval_seconds: u64,
+    pub batch_size: u32,
+    pub next_square: Option<String>,
+    pub last_run: Option<u64>,
+    pub squares_processed_last_run: u32,
+    pub squares_processed_total: u64,
+    pub failures_last_run: u32,
+    pub failures_total: u64,
+    pub recent_failures: Vec<RefreshFailure>,
+}
+
+// Enum representing the state of a contribution: pending until enough users corroborated or disputed it
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ContributionStatus {
+    Pending,
+    Accepted,
+    Rejected,
+}
+
+// Enum representing the vote of a user on the contribution of another user
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Vote {
+    Corroborate,
+    Dispute,
+}
+
+// Define a struct for a fact about a square logged by a user as a typed observation of a metric, with a description
+// (e.g. "basements flood every rainy season") and the users who corroborated or disputed it
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Contribution {
+    pub id: u64,
+    pub geohash: String,
+    pub metric: String,
+    pub value: MetricValue,
+    pub description: String,
+    pub contributor: Principal,
+    pub submitted_at: u64,
+    pub corroborations: Vec<Principal>,
+    pub disputes: Vec<Principal>,
+    pub status: ContributionStatus,
+    pub decided_at: Option<u64>,
+}
+
+// Define a struct for the rules of claiming squares: whether callers have to submit a location inside the square and how
+// many squares a principal can claim (None for no limit); anonymous callers can never claim. By default neither is limited.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ClaimPolicy {
+    pub require_location: bool,
+    pub max_claims_per_principal: Option<u32>,
+}
+
+// Define a struct for a bound on the latest value of a numeric metric of a square; the health of a square is low while
+// a value lies outside its bounds
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HealthThreshold {
+    pub metric: String,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+}
+
+// Define a struct for the rules reverting the NFTs of inactive squares to the canister. A square is at risk while all
+// configured checks fail: low health, fewer donations (in satoshi) since the previous evaluation than the minimum, and an
+// owner inactive for longer than the maximum (in seconds). Squares at risk for the grace period (in seconds) revert.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReversionPolicy {
+    pub evaluation_interval_seconds: u64,
+    pub health_thresholds: Vec<HealthThreshold>,
+    pub min_donations: Option<u64>,
+    pub max_owner_inactivity_seconds: Option<u64>,
+    pub grace_period_seconds: u64,
+}
+
+// Enum representing why a square is at risk of reverting
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum RiskReason {
+    LowHealth { metric: String, value: f64 },
+    LowDonations { received: u64 },
+    InactiveOwner { last_active_at: u64 },
+}
+
+// Define a struct for the evaluation of a minted square: its Bitcoin balance at the last evaluation, to measure the
+// donations received since, and since when and why it is at risk of reverting
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SquareReversion {
+    pub geohash: String,
+    pub owner: Principal,
+    pub last_evaluated_at: u64,
+    pub last_balance: Option<u64>,
+    pub at_risk_since: Option<u64>,
+    pub reasons: Vec<RiskReason>,
+}
+
+// Enum representing what happened to the ownership of a square
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ReversionEventKind {
+    AtRisk(Vec<RiskReason>),
+    Recovered,
+    Reverted { transaction_id: u128 },
+    RevertFailed(String),
+    Reclaimed { transaction_id: u128 },
+    OwnerChanged { new_owner: Principal },
+}
+
+// Define a struct for an entry of the append-only log of reversions; owner is the owner before the event, except for
+// reclaims, where it is the new owner. OwnerChanged records an NFT transferred outside the canister.
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReversionEvent {
+    pub sequence: u64,
+    pub geohash: String,
+    pub owner: Principal,
+    pub kind: ReversionEventKind,
+    pub at: u64,
+}
+
+// Define a struct for the number of votes deciding a contribution and the reputation a user needs to vote
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ContributionPolicy {
+    pub corroborations_to_accept: u32,
+    pub disputes_to_reject: u32,
+    pub min_voter_reputation: i64,
+}
+
+// Define a struct for the reputation of a user, earned by accepted contributions and votes agreeing with the outcome
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reputation {
+    pub principal: Principal,
+    pub score: i64,
+    pub contributions: u32,
+    pub accepted: u32,
+    pub rejected: u32,
+    pub votes: u32,
+    pub agreeing_votes: u32,
+}
+
+// Enum representing errors of the contribution functions
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ContributionError {
+    AnonymousCaller,
+    Unauthorized,
+    UnknownContribution(u64),
+    InvalidObservation(MetricError),
+    InvalidDescription(String),
+    InvalidPolicy(String),
+    OwnContribution,
+    AlreadyVoted,
+    AlreadyDecided(ContributionStatus),
+    InsufficientReputation(i64),
+}
+
+// Enum representing the errors of the endpoints of the canister, so callers can tell invalid input, squares that are not
+// minted and dependency canisters that cannot be reached apart; errors of the metrics and contribution functions keep
+// their details
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GeohashError {
+    InvalidCoordinate(String),
+    InvalidGeohash(String),
+    NotASquare(String),
+    InvalidInput(String),
+    TooManyInputs(u32),
+    NotFound(String),
+    NotMinted(String),
+    AlreadyClaimed { geohash: String, token_id: u64 },
+    MintInProgress(String),
+    MintFailed { geohash: String, reason: String },
+    TransferFailed { token_id: u64, reason: String },
+    DependencyUnavailable { canister: String, reason: String },
+    NotConfigured(String),
+    LocationRequired,
+    OutsideSquare(String),
+    ClaimLimitReached(u32),
+    AnonymousCaller,
+    Unauthorized,
+    RefreshRunning,
+    StorageFailed(String),
+    Metric(MetricError),
+    Contribution(ContributionError),
+}
+
+// Define a struct for a version of the published data of a minted square. Documents are immutable and addressed by the
+// CID of their DAG-CBOR encoding; previous is the CID of the version before
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SquareDocument {
+    pub geohash: String,
+    pub version: u64,
+    pub previous: Option<String>,
+    pub updated_at: u64,
+    pub token_id: u64,
+    pub owner: Principal,
+    pub bitcoin_address: String,
+    pub ethereum_address: String,
+    pub minted_at: u64,
+    pub metrics: Vec<MetricObservation>,
+    pub rating: Option<RatingSummary>,
+}
+
+// Define a struct for a name pointing to the latest document of a square
+#[derive(CandidType, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DocumentPointer {
+    pub name: String,
+    pub cid: String,
+    pub version: u64,
+    pub updated_at: u64,
+}
+
 // Metadata description type, representing a list of metadata parts
 pub type MetadataDesc = Vec<MetadataPart>;
 
@@ -28,7 +537,7 @@ pub type MetadataDesc = Vec<MetadataPart>;
 pub type MetadataLookupDesc = Vec<MetadataPartLookup>;
 
 // Struct representing an NFT
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
 pub struct Nft {
     pub owner: Principal,
     pub token_id: u64,
@@ -37,7 +546,7 @@ pub struct Nft {
 }
 
 // Struct representing the properties of a square
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct SquareProperties {
     pub geohash: String,
     pub metadata: String,
@@ -53,7 +562,7 @@ pub struct MetadataPart {
 }
 
 // Variation of MetadataPart for lookup, here key_val_data is a vector of MetadataKeyVal instead of a HashMap
-#[derive(CandidType, Deserialize, Clone, Debug, serde::Serialize)]
+#[derive(CandidType, Deserialize, Clone, Debug, serde::Serialize, PartialEq)]
 pub struct MetadataPartLookup {
     pub purpose: MetadataPurpose,
     pub key_val_data: Vec<MetadataKeyVal>,
@@ -68,7 +577,7 @@ pub enum MetadataPurpose {
 }
 
 // Enum representing different types of metadata values
-#[derive(CandidType, Deserialize, Clone, Debug, serde::Serialize)]
+#[derive(CandidType, Deserialize, Clone, Debug, serde::Serialize, PartialEq)]
 pub enum MetadataVal {
     TextContent(String),
     BlobContent(Vec<u8>),
@@ -80,7 +589,7 @@ pub enum MetadataVal {
 }
 
 // Struct representing a key-value pair for metadata
-#[derive(CandidType, Deserialize, Clone, Debug, serde::Serialize)]
+#[derive(CandidType, Deserialize, Clone, Debug, serde::Serialize, PartialEq)]
 pub struct MetadataKeyVal {
     pub key: String,
     pub val: MetadataVal,
@@ -110,13 +619,27 @@ pub struct GetEthereumAddressInput {
 }
 
 // Struct representing a wallet with different cryptocurrency balances
-#[derive(CandidType, Deserialize, Clone, Debug)]
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct Wallet {
     pub ether: String,
     pub bitcoin: String,
 }
 
 
+// Enum representing the result of an owner lookup
+#[derive(CandidType, Deserialize, Debug)]
+pub enum OwnerResult {
+    Ok(Principal),
+    Err(ApiError),
+}
+
+// Enum representing the result of a transfer
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TxReceipt {
+    Ok(u128),
+    Err(ApiError),
+}
+
 // Enum representing the result of a mint operation
 #[derive(CandidType, Deserialize, Debug)]
 pub enum MintReceipt {